@@ -3,6 +3,8 @@ pub use self::ack::{
 };
 
 mod ack {
+  use helpers::is_newer_seq;
+
   #[derive(Debug)]
   pub struct PeerAcks {
     pub ack_num: u16,
@@ -51,6 +53,33 @@ mod ack {
     }
   }
 
+  impl PeerAcks {
+    // True for seq nums already recorded, and for ones too old to fit in the ack field at all
+    pub fn has_seen(&self, seq_num: u16) -> bool {
+      if is_newer_seq(seq_num, self.ack_num) {
+        return false;
+      }
+      let ack_delta = self.ack_num.wrapping_sub(seq_num);
+      ack_delta == 0 || ack_delta > 32 || self.ack_field & (1 << (ack_delta - 1)) != 0
+    }
+  }
+
+  #[test]
+  fn has_seen_recorded_and_stale_seq_nums() {
+    let peer_acks = PeerAcks { ack_num: 40, ack_field: 0b101};
+    assert!(peer_acks.has_seen(40));
+    assert!(peer_acks.has_seen(39));
+    assert!(!peer_acks.has_seen(38));
+    assert!(peer_acks.has_seen(37));
+    assert!(!peer_acks.has_seen(8));
+    assert!(peer_acks.has_seen(7));
+    assert!(!peer_acks.has_seen(41));
+
+    let peer_acks = PeerAcks { ack_num: 2, ack_field: 0b100};
+    assert!(peer_acks.has_seen(65535));
+    assert!(!peer_acks.has_seen(0));
+  }
+
   #[test]
   fn update_ack_map_for_normal_newer() {
     // In range, sets correct flag
@@ -4,16 +4,29 @@ pub use self::incoming::{
 
 mod incoming {
   use std::sync::mpsc::{channel, Sender, Receiver};
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicBool, Ordering};
   use std::thread;
   use std::thread::JoinHandle;
+  use std::time::Duration;
+  use std::io::ErrorKind;
   use std::net:: UdpSocket;
+  use time::SteadyTime;
 
   use packet_types::{
     RawPacket,
-    SequencedAckedPacket
+    SequencedAckedPacket,
+    PacketKind
   };
-  use constants::UDP_MARKER;
-  use errors::socket_recv_err;
+  use constants::{
+    RECV_POLL_TIME,
+    HEADER_LEN,
+  };
+  use byteorder::{ByteOrder, BigEndian};
+  use fragment::FragmentBuffer;
+  use mtu::DatagramSizes;
+  use errors::{NetworkError, socket_recv_err};
+  use types::NetworkEvent;
 
   pub struct NetReceiver{
     pub socket_recv_rx: Receiver<SequencedAckedPacket>,
@@ -21,11 +34,14 @@ mod incoming {
   }
 
   impl NetReceiver {
-    pub fn new(socket: UdpSocket) -> NetReceiver {
+    pub fn new(socket: UdpSocket, marker: Vec<u8>, max_datagram_size: usize, datagram_sizes: DatagramSizes, running: Arc<AtomicBool>, event_tx: Sender<NetworkEvent>) -> NetReceiver {
       let (socket_recv_tx, socket_recv_rx) = channel();
+      // Wake up periodically so a shutdown is noticed even when the socket is quiet
+      let _ = socket.set_read_timeout(Some(Duration::from_millis(RECV_POLL_TIME)));
 
       let thread_handle = thread::spawn (move || {
-        loop { receive_packet(&socket, &socket_recv_tx) }
+        let mut fragments = FragmentBuffer::new();
+        while running.load(Ordering::SeqCst) { receive_packet(&socket, &marker, max_datagram_size, &mut fragments, &datagram_sizes, &socket_recv_tx, &event_tx) }
       });
 
       NetReceiver {
@@ -36,15 +52,56 @@ mod incoming {
 
   }
 
-  pub fn receive_packet(socket: &UdpSocket, socket_recv_tx: &Sender<SequencedAckedPacket>) {
-    let mut buf = [0; 256];
+  // Fragments are held in the buffer until the whole packet they belong to has arrived, but only for
+  // peers the director has connected, which are the ones it publishes datagram sizes for.
+  // Datagrams not starting with the marker are dropped, and ones only differing in its version are reported.
+  pub fn receive_packet(socket: &UdpSocket, marker: &[u8], max_datagram_size: usize, fragments: &mut FragmentBuffer, datagram_sizes: &DatagramSizes, socket_recv_tx: &Sender<SequencedAckedPacket>, event_tx: &Sender<NetworkEvent>) {
+    // One spare byte, so a datagram the OS had to truncate can be told apart from one that fit exactly
+    let mut buf = vec![0; max_datagram_size + 1];
     let _ = socket.recv_from(&mut buf)
-      .map_err(socket_recv_err)
-      .map(|(_, socket_addr)| RawPacket {addr: socket_addr, bytes: buf.to_vec()})
+      .map_err(|err| match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => (),
+        _ => {let _ = event_tx.send(NetworkEvent::Error(socket_recv_err(err)));}
+      })
       .ok()
-      .and_then(|packet| packet.strip_marker(UDP_MARKER))
+      .and_then(|(len, socket_addr)| {
+        if len > max_datagram_size {
+          let _ = event_tx.send(NetworkEvent::Error(NetworkError::PacketTooLarge(socket_addr, len)));
+          None
+        } else {
+          Some(RawPacket {addr: socket_addr, bytes: buf[0..len].to_vec()})
+        }
+      })
+      .and_then(|packet| {
+        let protocol_id = &marker[0..marker.len() - 2];
+        if packet.starts_with_marker(protocol_id) && !packet.starts_with_marker(marker) && packet.bytes.len() >= marker.len() {
+          let version = BigEndian::read_u16(&packet.bytes[protocol_id.len()..marker.len()]);
+          let _ = event_tx.send(NetworkEvent::Error(NetworkError::ProtocolVersionMismatch(packet.addr, version)));
+        }
+        packet.strip_marker(marker)
+      })
+      .and_then(|packet| {
+        if packet.bytes.len() < HEADER_LEN {
+          let _ = event_tx.send(NetworkEvent::Error(NetworkError::MalformedPacket(packet.addr)));
+          None
+        } else {
+          Some(packet)
+        }
+      })
       .map(|packet| packet.strip_sequence())
       .map(|packet| packet.strip_acks())
+      .and_then(|packet| {
+        if packet.kind() == Some(PacketKind::Fragment) {
+          if datagram_sizes.get(packet.addr).is_none() {
+            return None;
+          }
+          fragments.insert(SteadyTime::now(), packet)
+            .map_err(|err| {let _ = event_tx.send(NetworkEvent::Error(err));})
+            .unwrap_or(None)
+        } else {
+          Some(packet)
+        }
+      })
       .map(|packet| socket_recv_tx.send(packet));
   }
 
@@ -55,18 +112,24 @@ mod incoming {
     use std::thread;
     use std::net::SocketAddr;
     use std::str::FromStr;
-    use constants::UDP_MARKER;
+    use constants::MAX_DATAGRAM_SIZE;
+    use config::NetworkConfig;
     use super::receive_packet;
     use packet_types::SequencedAckedPacket;
+    use types::NetworkEvent;
+    use errors::NetworkError;
+    use fragment::{FragmentBuffer, split_packet};
+    use mtu::DatagramSizes;
 
     #[test]
     fn receive_not_marked() {
       let send_socket = UdpSocket::bind("127.0.0.1:54732").unwrap();
       let recv_socket = send_socket.try_clone().unwrap();
       let (socket_recv_tx, socket_recv_rx) = channel();
+      let (event_tx, _) = channel();
 
       let handle = thread::spawn(move || {
-        receive_packet(&recv_socket, &socket_recv_tx)
+        receive_packet(&recv_socket, &NetworkConfig::default().marker(), MAX_DATAGRAM_SIZE, &mut FragmentBuffer::new(), &DatagramSizes::new(), &socket_recv_tx, &event_tx)
       });
 
       let _ = send_socket.send_to(b"not_marker", "127.0.0.1:54732");
@@ -82,9 +145,10 @@ mod incoming {
       let send_socket = UdpSocket::bind("127.0.0.1:54734").unwrap();
       let recv_socket = send_socket.try_clone().unwrap();
       let (socket_recv_tx, socket_recv_rx) = channel();
+      let (event_tx, _) = channel();
 
       let handle = thread::spawn(move || {
-        receive_packet(&recv_socket, &socket_recv_tx)
+        receive_packet(&recv_socket, &NetworkConfig::default().marker(), MAX_DATAGRAM_SIZE, &mut FragmentBuffer::new(), &DatagramSizes::new(), &socket_recv_tx, &event_tx)
       });
       let message = b"hello world!".into_iter().cloned().collect();
 
@@ -95,7 +159,7 @@ mod incoming {
         ack_field: 3,
         bytes: message
       };
-      let raw_packet = expected_packet.clone().serialize(UDP_MARKER);
+      let raw_packet = expected_packet.clone().serialize(&NetworkConfig::default().marker());
 
       let _ = send_socket.send_to(&raw_packet.bytes[0..raw_packet.bytes.len()], raw_packet.addr);
       let _ = handle.join();
@@ -107,12 +171,117 @@ mod incoming {
       assert_eq!(full_result.ack_num, expected_packet.ack_num);
       assert_eq!(full_result.ack_field, expected_packet.ack_field);
 
-      // Trim packet
-      let bytes_result =
-        String::from_utf8(full_result.bytes).unwrap()
-          .trim_matches('\0').to_string();
+      assert_eq!(full_result.bytes, b"hello world!".to_vec());
+    }
+
+    // Sends a fragmented packet to itself, returning what came out of the receiver
+    fn receive_fragments(port: u16, connected: bool) -> Option<SequencedAckedPacket> {
+      let addr = SocketAddr::from_str(&format!("127.0.0.1:{}", port)).unwrap();
+      let send_socket = UdpSocket::bind(addr).unwrap();
+      let recv_socket = send_socket.try_clone().unwrap();
+      let (socket_recv_tx, socket_recv_rx) = channel();
+      let (event_tx, _) = channel();
+      let datagram_sizes = DatagramSizes::new();
+      if connected {
+        datagram_sizes.publish(vec![(addr, MAX_DATAGRAM_SIZE)].into_iter().collect());
+      }
+
+      let packet = SequencedAckedPacket {
+        addr: addr,
+        seq_num: 1,
+        ack_num: 2,
+        ack_field: 3,
+        bytes: (0..600).map(|byte| byte as u8).collect()
+      };
+      let fragments = split_packet(packet, MAX_DATAGRAM_SIZE);
+      let fragment_count = fragments.len();
+
+      let handle = thread::spawn(move || {
+        let mut fragment_buffer = FragmentBuffer::new();
+        for _ in 0..fragment_count {
+          receive_packet(&recv_socket, &NetworkConfig::default().marker(), MAX_DATAGRAM_SIZE, &mut fragment_buffer, &datagram_sizes, &socket_recv_tx, &event_tx)
+        }
+      });
+
+      for fragment in fragments.into_iter() {
+        let raw_packet = fragment.serialize(&NetworkConfig::default().marker());
+        let _ = send_socket.send_to(&raw_packet.bytes[0..raw_packet.bytes.len()], raw_packet.addr);
+      }
+      let _ = handle.join();
+
+      let result = socket_recv_rx.try_recv().ok();
+      assert_eq!(socket_recv_rx.try_recv().is_err(), true);
+      result
+    }
+
+    #[test]
+    fn receive_fragmented() {
+      let full_result = receive_fragments(54736, true).unwrap();
+      assert_eq!(full_result.seq_num, 1);
+      assert_eq!(full_result.bytes, (0..600).map(|byte| byte as u8).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn fragments_from_strangers_are_dropped() {
+      assert_eq!(receive_fragments(54737, false), None);
+    }
+
+    #[test]
+    fn receive_too_large() {
+      let send_socket = UdpSocket::bind("127.0.0.1:54738").unwrap();
+      let recv_socket = send_socket.try_clone().unwrap();
+      let (socket_recv_tx, socket_recv_rx) = channel();
+      let (event_tx, event_rx) = channel();
+
+      let handle = thread::spawn(move || {
+        receive_packet(&recv_socket, &NetworkConfig::default().marker(), MAX_DATAGRAM_SIZE, &mut FragmentBuffer::new(), &DatagramSizes::new(), &socket_recv_tx, &event_tx)
+      });
+
+      let raw_packet = SequencedAckedPacket {
+        addr: SocketAddr::from_str("127.0.0.1:54738").unwrap(),
+        seq_num: 1,
+        ack_num: 2,
+        ack_field: 3,
+        bytes: vec![1; MAX_DATAGRAM_SIZE]
+      }.serialize(&NetworkConfig::default().marker());
+      let _ = send_socket.send_to(&raw_packet.bytes[0..raw_packet.bytes.len()], raw_packet.addr);
+      let _ = handle.join();
+
+      assert_eq!(socket_recv_rx.recv().is_err(), true);
+      assert_eq!(event_rx.recv().unwrap(), NetworkEvent::Error(NetworkError::PacketTooLarge(raw_packet.addr, MAX_DATAGRAM_SIZE + 1)));
+    }
+
+    #[test]
+    fn receive_other_protocol() {
+      let send_socket = UdpSocket::bind("127.0.0.1:54744").unwrap();
+      let recv_socket = send_socket.try_clone().unwrap();
+      let (socket_recv_tx, socket_recv_rx) = channel();
+      let (event_tx, event_rx) = channel();
+      let config = NetworkConfig::default();
+      let mut other_game = NetworkConfig::default();
+      other_game.protocol_id = config.protocol_id + 1;
+      let mut other_version = NetworkConfig::default();
+      other_version.protocol_version = config.protocol_version + 1;
+
+      let handle = thread::spawn(move || {
+        for _ in 0..2 {
+          receive_packet(&recv_socket, &config.marker(), MAX_DATAGRAM_SIZE, &mut FragmentBuffer::new(), &DatagramSizes::new(), &socket_recv_tx, &event_tx)
+        }
+      });
+
+      let addr = SocketAddr::from_str("127.0.0.1:54744").unwrap();
+      let packet = SequencedAckedPacket { addr: addr, seq_num: 1, ack_num: 2, ack_field: 3, bytes: vec![4] };
+      for marker in vec![other_game.marker(), other_version.marker()].into_iter() {
+        let raw_packet = packet.clone().serialize(&marker);
+        let _ = send_socket.send_to(&raw_packet.bytes[0..raw_packet.bytes.len()], raw_packet.addr);
+      }
+      let _ = handle.join();
 
-      assert_eq!(bytes_result, "hello world!");
+      assert_eq!(socket_recv_rx.recv().is_err(), true);
+      // Only the other version is worth telling about
+      let version = other_version.protocol_version;
+      assert_eq!(event_rx.recv().unwrap(), NetworkEvent::Error(NetworkError::ProtocolVersionMismatch(addr, version)));
+      assert_eq!(event_rx.try_recv().is_err(), true);
     }
   }
 }
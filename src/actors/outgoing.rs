@@ -9,10 +9,13 @@ mod outgoing {
   use std::net:: UdpSocket;
   use packet_types::{
     RawPacket,
-    SequencedAckedPacket
+    SequencedAckedPacket,
+    PacketKind
   };
-  use constants::UDP_MARKER;
-  use errors::socket_send_err;
+  use errors::{NetworkError, socket_send_err};
+  use fragment::{split_packet, max_packet_size};
+  use mtu::DatagramSizes;
+  use types::NetworkEvent;
 
   pub struct NetSender {
     pub socket_send_tx: Sender<SequencedAckedPacket>,
@@ -20,11 +23,11 @@ mod outgoing {
   }
 
   impl NetSender {
-    pub fn new(socket: UdpSocket) -> NetSender {
+    pub fn new(socket: UdpSocket, marker: Vec<u8>, max_datagram_size: usize, datagram_sizes: DatagramSizes, event_tx: Sender<NetworkEvent>) -> NetSender {
       let (socket_send_tx, socket_send_rx) = channel();
 
       let thread_handle = thread::spawn (move || {
-        loop { send_packet(&socket, &socket_send_rx) }
+        while send_packet(&socket, &marker, max_datagram_size, &datagram_sizes, &socket_send_rx, &event_tx) {}
       });
 
       NetSender {
@@ -34,12 +37,33 @@ mod outgoing {
     }
   }
 
-  pub fn send_packet(socket: &UdpSocket, socket_send_rx: &Receiver<SequencedAckedPacket>) {
-    let _ =
-      socket_send_rx.recv()
-        .map(|packet: SequencedAckedPacket| packet.serialize(UDP_MARKER))
-        .map(|raw_payload: RawPacket| socket.send_to(&raw_payload.bytes[0..raw_payload.bytes.len()], raw_payload.addr))
-        .map(|send_res| send_res.map_err(socket_send_err));
+  // Packets too big for one datagram go out as several fragments, and ones too big for that are refused.
+  // Peers with a probed datagram size are fragmented to that instead of max_datagram_size.
+  // Returns false once the director has hung up, so the thread can exit
+  pub fn send_packet(socket: &UdpSocket, marker: &[u8], max_datagram_size: usize, datagram_sizes: &DatagramSizes, socket_send_rx: &Receiver<SequencedAckedPacket>, event_tx: &Sender<NetworkEvent>) -> bool {
+    socket_send_rx.recv()
+      .map(|packet: SequencedAckedPacket| {
+        // A probe only proves anything if it arrives whole
+        if packet.kind() == Some(PacketKind::Probe) {
+          send_datagram(socket, marker, packet, event_tx);
+          return;
+        }
+        if packet.bytes.len() > max_packet_size(max_datagram_size) {
+          let _ = event_tx.send(NetworkEvent::Error(NetworkError::PacketTooLarge(packet.addr, packet.bytes.len())));
+          return;
+        }
+        let datagram_size = datagram_sizes.get(packet.addr).unwrap_or(max_datagram_size);
+        for fragment in split_packet(packet, datagram_size).into_iter() {
+          send_datagram(socket, marker, fragment, event_tx);
+        }
+      })
+      .is_ok()
+  }
+
+  fn send_datagram(socket: &UdpSocket, marker: &[u8], packet: SequencedAckedPacket, event_tx: &Sender<NetworkEvent>) {
+    let raw_payload: RawPacket = packet.serialize(marker);
+    let _ = socket.send_to(&raw_payload.bytes[0..raw_payload.bytes.len()], raw_payload.addr)
+      .map_err(|err| event_tx.send(NetworkEvent::Error(socket_send_err(raw_payload.addr, err))));
   }
 
   #[cfg(test)]
@@ -49,9 +73,15 @@ mod outgoing {
     use std::thread;
     use std::net::SocketAddr;
     use std::str::FromStr;
-    use constants::UDP_MARKER;
+    use constants::MAX_DATAGRAM_SIZE;
+    use config::NetworkConfig;
     use super::send_packet;
     use packet_types::SequencedAckedPacket;
+    use types::NetworkEvent;
+    use errors::NetworkError;
+    use fragment::max_packet_size;
+    use mtu::{DatagramSizes, probe_payload};
+    use std::collections::HashMap;
 
     #[test]
     fn send() {
@@ -72,16 +102,68 @@ mod outgoing {
 
 
       let handle = thread::spawn(move || {
-        let mut buf = [0; 23];
+        let mut buf = [0; 26];
         let result = recv_socket.recv_from(&mut buf);
         assert_eq!(result.is_ok(), true);
-        assert_eq!(buf.to_vec(), result_packet.serialize(UDP_MARKER).bytes);
+        assert_eq!(buf.to_vec(), result_packet.serialize(&NetworkConfig::default().marker()).bytes);
       });
 
+      let (event_tx, _) = channel();
       let _ = socket_recv_tx.send(expected_packet);
-      send_packet(&send_socket, &socket_recv_rx);
+      send_packet(&send_socket, &NetworkConfig::default().marker(), MAX_DATAGRAM_SIZE, &DatagramSizes::new(), &socket_recv_rx, &event_tx);
 
       let _ = handle.join().map_err(|err| panic!(err));
     }
+
+    #[test]
+    fn send_stops_when_hung_up() {
+      let send_socket = UdpSocket::bind("127.0.0.1:54740").unwrap();
+      let (socket_recv_tx, socket_recv_rx) = channel::<SequencedAckedPacket>();
+      let (event_tx, _) = channel();
+      drop(socket_recv_tx);
+
+      assert_eq!(send_packet(&send_socket, &NetworkConfig::default().marker(), MAX_DATAGRAM_SIZE, &DatagramSizes::new(), &socket_recv_rx, &event_tx), false);
+    }
+
+    #[test]
+    fn send_refuses_too_large() {
+      let send_socket = UdpSocket::bind("127.0.0.1:54741").unwrap();
+      let (socket_recv_tx, socket_recv_rx) = channel();
+      let (event_tx, event_rx) = channel();
+      let addr = SocketAddr::from_str("127.0.0.1:54742").unwrap();
+      let size = max_packet_size(MAX_DATAGRAM_SIZE) + 1;
+
+      let _ = socket_recv_tx.send(SequencedAckedPacket { addr: addr, seq_num: 1, ack_num: 2, ack_field: 3, bytes: vec![1; size] });
+      assert_eq!(send_packet(&send_socket, &NetworkConfig::default().marker(), MAX_DATAGRAM_SIZE, &DatagramSizes::new(), &socket_recv_rx, &event_tx), true);
+      assert_eq!(event_rx.try_recv().unwrap(), NetworkEvent::Error(NetworkError::PacketTooLarge(addr, size)));
+    }
+
+    #[test]
+    fn send_uses_peer_datagram_size() {
+      let send_socket = UdpSocket::bind("127.0.0.1:54743").unwrap();
+      let recv_socket = send_socket.try_clone().unwrap();
+      let (socket_recv_tx, socket_recv_rx) = channel();
+      let (event_tx, _) = channel();
+      let addr = SocketAddr::from_str("127.0.0.1:54743").unwrap();
+      let datagram_sizes = DatagramSizes::new();
+      let mut sizes = HashMap::new();
+      sizes.insert(addr, 1024);
+      datagram_sizes.publish(sizes);
+
+      // Bigger than MAX_DATAGRAM_SIZE, but one datagram for a peer probed at 1024
+      let packet = SequencedAckedPacket { addr: addr, seq_num: 1, ack_num: 2, ack_field: 3, bytes: vec![4; 600] };
+      let _ = socket_recv_tx.send(packet.clone());
+      send_packet(&send_socket, &NetworkConfig::default().marker(), MAX_DATAGRAM_SIZE, &datagram_sizes, &socket_recv_rx, &event_tx);
+      let mut buf = [0; 2048];
+      let (len, _) = recv_socket.recv_from(&mut buf).unwrap();
+      assert_eq!(buf[0..len].to_vec(), packet.serialize(&NetworkConfig::default().marker()).bytes);
+
+      // Probes always go out whole
+      let probe = SequencedAckedPacket { addr: addr, seq_num: 0, ack_num: 0, ack_field: 0, bytes: probe_payload(1280) };
+      let _ = socket_recv_tx.send(probe);
+      send_packet(&send_socket, &NetworkConfig::default().marker(), MAX_DATAGRAM_SIZE, &datagram_sizes, &socket_recv_rx, &event_tx);
+      let (len, _) = recv_socket.recv_from(&mut buf).unwrap();
+      assert_eq!(len, 1280);
+    }
   }
 }
@@ -7,94 +7,1119 @@ mod state {
   use std::sync::mpsc::{channel, Sender, Receiver};
   use std::collections::HashMap;
   use std::thread;
+  use std::mem;
   use std::thread::JoinHandle;
   use time::{Duration, SteadyTime};
+  use byteorder::{ByteOrder, BigEndian};
   use packet_types::{
     Packet,
+    PacketKind,
+    OutgoingPacket,
     SequencedPacket,
     SequencedAckedPacket,
-    PacketWithTries
+    PacketWithTries,
+    QueuedPacket,
+    DeliveryMode,
+    MessageId,
+    BlobId
+  };
+  use types::{
+    Command,
+    NetworkEvent,
+    PeerStats
   };
   use constants::{
     MAX_RESEND_ATTEMPTS,
-    PACKET_DROP_TIME,
+    MAX_CONNECT_ATTEMPTS,
+    FAST_RESEND_THRESHOLD,
+    DISCONNECT_REPEAT_COUNT,
+    DATA_HEADER_LEN,
+    MAX_BLOB_SIZE,
+    MARKER_LEN,
+    HEADER_LEN,
+    FEC_HEADER_LEN,
   };
+  use stats::StatsBoard;
+  use mtu::{DatagramSizes, probe_payload, probe_ack_payload, read_probe_ack};
   use ack::PeerAcks;
+  use blob::cancel_payload;
+  use fragment::max_packet_size;
+  use connection::Connection;
+  use config::{NetworkConfig, OverflowPolicy};
+  use backpressure::{QueueSlots, CloseOnDrop};
+  use errors::NetworkError;
+  use rtt::RttEstimator;
 
-  use helpers::try_recv_all;
+  use helpers::{try_recv_all, is_newer_seq};
   use itertools::Itertools;
 
+  // Reliable packets by destination and seq number, with when they were sent, how often, and for which messages
+  pub type AwaitingAckMap = HashMap<(SocketAddr, u16), (SequencedAckedPacket, SteadyTime, i32, Vec<MessageId>)>;
+
   pub struct Director{
     pub api_out_rx: Receiver<Packet>,
-    pub api_in_tx: Sender<Packet>,
-    pub thread_handle: JoinHandle<()>
+    pub api_in_tx: Sender<OutgoingPacket>,
+    pub command_tx: Sender<Command>,
+    pub thread_handle: JoinHandle<Vec<Packet>>
   }
 
   impl Director {
-    pub fn new(socket_recv_rx: Receiver<SequencedAckedPacket>, socket_send_tx: Sender<SequencedAckedPacket>) -> Director {
+    pub fn new(socket_recv_rx: Receiver<SequencedAckedPacket>, socket_send_tx: Sender<SequencedAckedPacket>, event_tx: Sender<NetworkEvent>, queue_slots: QueueSlots, stats: StatsBoard, datagram_sizes: DatagramSizes, config: NetworkConfig) -> Director {
       let (api_out_tx, api_out_rx) = channel();
       let (api_in_tx, api_in_rx) = channel();
+      let (command_tx, command_rx) = channel();
       let mut seq_num_map = HashMap::new();
       let mut ack_map = HashMap::new();
       let mut packets_awaiting_ack = HashMap::new();
+      let mut connections = HashMap::new();
+      let mut queued_resends = Vec::new();
+      let mut queued_packets = Vec::new();
+      let mut queued_counts = HashMap::new();
+      let mut running = true;
+      let max_packet_size = max_packet_size(config.max_datagram_size);
 
       let thread_handle = thread::spawn (move || {
-        loop {
+        let _close_on_drop = CloseOnDrop(queue_slots.clone());
+        while running {
+          let now = SteadyTime::now();
+          let commands = try_recv_all(&command_rx);
           let recv_packets = try_recv_all(&socket_recv_rx);
-          let send_packets = try_recv_all(&api_in_rx);
-          let dropped_packets = extract_dropped_packets(&mut packets_awaiting_ack);
+          let send_packets: Vec<OutgoingPacket> = try_recv_all(&api_in_rx);
+          let received_counts = count_by_peer(send_packets.iter().map(|outgoing| outgoing.packet.addr));
+          let mut dropped_packets = extract_dropped_packets(&mut packets_awaiting_ack, &connections);
+          for dropped_packet in dropped_packets.iter() {
+            if let Some(connection) = connections.get_mut(&dropped_packet.packet.addr) {
+              connection.congestion.record_outcomes(0, 1);
+            }
+          }
+
+          for command in commands.into_iter() {
+            match command {
+              Command::Connect(addr) => {
+                if !connections.contains_key(&addr) {
+                  let connection = Connection::connecting(now);
+                  let connect = handshake_packet(addr, PacketKind::Connect, connection.session);
+                  connections.insert(addr, connection);
+                  send_to_peer(connect, &socket_send_tx, &mut connections);
+                }
+              },
+              Command::Disconnect(addr) => {
+                if connections.remove(&addr).is_some() {
+                  (0..DISCONNECT_REPEAT_COUNT)
+                    .for_each(|_| {let _ = socket_send_tx.send(control_packet(addr, PacketKind::Disconnect));});
+                  forget_peer(addr, &mut seq_num_map, &mut ack_map, &mut packets_awaiting_ack);
+                  let _ = event_tx.send(NetworkEvent::Disconnected(addr));
+                }
+              },
+              Command::SendBlob(addr, id, bytes) => start_blob(addr, id, bytes, config.max_datagram_size, &mut connections, &event_tx),
+              Command::CancelBlob(addr, id) => {
+                if cancel_blob(addr, id, &mut connections, &event_tx) {
+                  (0..DISCONNECT_REPEAT_COUNT)
+                    .for_each(|_| send_to_peer(unsequenced_packet(addr, cancel_payload(id)), &socket_send_tx, &mut connections));
+                }
+              },
+              Command::Shutdown => running = false
+            }
+          }
+
+          retry_connections(now, &mut connections, &event_tx).into_iter()
+            .for_each(|(addr, session)| send_to_peer(handshake_packet(addr, PacketKind::Connect, session), &socket_send_tx, &mut connections));
 
-          recv_packets.into_iter()
-            .map(|packet| {
-              delete_acked_packets(&packet, &mut packets_awaiting_ack);
+          let mut data_packets = Vec::new();
+          let mut ack_packets = Vec::new();
+          let mut parity_packets = Vec::new();
+          for packet in recv_packets.into_iter() {
+            if let Some(connection) = connections.get_mut(&packet.addr) {
+              connection.last_received = now;
+              connection.stats.packets_received += 1;
+              connection.stats.bytes_received += wire_size(&packet) as u64;
+            }
+            match packet.kind() {
+              Some(PacketKind::Data) | Some(PacketKind::Aggregate) => {
+                if accept_data_packet(packet.addr, now, &mut connections, &event_tx) {
+                  if let Some(connection) = connections.get_mut(&packet.addr) {
+                    connection.fec_decoder.record(&packet);
+                  }
+                  data_packets.push(packet);
+                }
+              },
+              Some(PacketKind::Parity) => {
+                if is_connected(&packet.addr, &connections) {
+                  parity_packets.push(packet);
+                }
+              },
+              Some(PacketKind::Ack) => {
+                if is_connected(&packet.addr, &connections) {
+                  ack_packets.push(packet);
+                }
+              },
+              Some(PacketKind::Probe) | Some(PacketKind::ProbeAck) => {
+                if let Some(reply) = handle_probe_packet(&packet, &config, &mut connections) {
+                  send_to_peer(reply, &socket_send_tx, &mut connections);
+                }
+              },
+              Some(PacketKind::BlobChunk) | Some(PacketKind::BlobAck) | Some(PacketKind::BlobCancel) => {
+                handle_blob_packet(&packet, now, config.max_datagram_size, &mut connections, &event_tx);
+              },
+              Some(kind) => {
+                let session = read_session(&packet.bytes);
+                // Nothing from a restarted peer's old session applies to its new one
+                if kind == PacketKind::Connect && end_old_session(packet.addr, session, &mut connections, &event_tx) {
+                  forget_peer(packet.addr, &mut seq_num_map, &mut ack_map, &mut packets_awaiting_ack);
+                }
+                if let Some(reply) = handle_control_packet(kind, packet.addr, session, now, config.max_connections, &mut connections, &event_tx) {
+                  send_to_peer(reply, &socket_send_tx, &mut connections);
+                }
+                if kind == PacketKind::Disconnect {
+                  forget_peer(packet.addr, &mut seq_num_map, &mut ack_map, &mut packets_awaiting_ack);
+                }
+              },
+              None => {let _ = event_tx.send(NetworkEvent::Error(NetworkError::MalformedPacket(packet.addr)));}
+            }
+          }
+
+          // Parities wait for the whole tick's data packets, so only packets that really are missing get rebuilt
+          data_packets.extend(
+            parity_packets.into_iter()
+              .filter_map(|parity| recover_packet(&parity, &mut connections)));
+
+          // Ack-only packets are not sequenced, so they are never deduped or acked themselves
+          ack_packets.into_iter()
+            .for_each(|packet| dropped_packets.extend(apply_acks(now, &packet, &mut packets_awaiting_ack, &mut connections, &event_tx).into_iter()));
+
+          data_packets.into_iter()
+            .flat_map(|packet| {
+              // Network duplicates and packets too old to ack are dropped before they touch any state
+              if has_seen_packet(&packet, &ack_map) {
+                return Vec::new().into_iter();
+              }
+              dropped_packets.extend(apply_acks(now, &packet, &mut packets_awaiting_ack, &mut connections, &event_tx).into_iter());
+              // Packets the receiving channels have no room for are left unacked, so the peer resends them later
+              if !fits_windows(&packet, &connections) {
+                return Vec::new().into_iter();
+              }
               add_packet_to_ack_map(packet.addr.clone(), packet.seq_num.clone(), &mut ack_map);
-              packet
+              if let Some(connection) = connections.get_mut(&packet.addr) {
+                connection.owe_ack(now);
+              }
+              deliver_packet(packet, &mut connections, &event_tx).into_iter()
+            })
+            .for_each(|packet| {let _ = api_out_tx.send(packet);});
+
+          expire_idle_connections(now, config.peer_timeout, &mut connections, &event_tx).into_iter()
+            .for_each(|addr| forget_peer(addr, &mut seq_num_map, &mut ack_map, &mut packets_awaiting_ack));
+
+          queued_packets.extend(
+            send_packets.into_iter()
+              .filter_map(|outgoing| frame_outgoing_packet(outgoing, max_packet_size, &mut connections, &event_tx)));
+
+          let mut lost_packets = Vec::new();
+          let dropped_packets: Vec<PacketWithTries> =
+            dropped_packets.into_iter()
+              .flat_map(|dropped_packet| {
+                let (resend, lost) = split_lost_messages(dropped_packet);
+                lost_packets.extend(lost);
+                resend
+              })
+              .collect();
+
+          for lost_packet in lost_packets.iter() {
+            if let Some(connection) = connections.get_mut(&lost_packet.packet.addr) {
+              connection.stats.lost += lost_packet.ids.len() as u64;
+            }
+          }
+          lost_packets.into_iter()
+            .flat_map(|lost_packet| lost_events(lost_packet).into_iter())
+            .for_each(|event| {let _ = event_tx.send(event);});
+
+          queued_resends.extend(dropped_packets.into_iter());
+          // Whatever was queued for peers that have since gone away will never be sent
+          queued_resends.retain(|resend: &PacketWithTries| is_connected(&resend.packet.addr, &connections));
+          queued_packets.retain(|queued: &QueuedPacket| is_connected(&queued.packet.addr, &connections));
+
+          update_congestion(now, &config, &mut connections, &event_tx);
+          // Published before anything is batched to the new sizes, so the sender never fragments smaller than the batches need
+          let peer_sizes = peer_datagram_sizes(&connections, &config);
+          datagram_sizes.publish(peer_sizes.clone());
+          let max_payload_size = |addr| peer_sizes.get(&addr).cloned().unwrap_or(config.max_datagram_size) - MARKER_LEN - HEADER_LEN - fec_header_len(&config);
+          // Resends go first, and whatever a peer's send rate cannot fit this tick waits for the next
+          let (resends, held_resends) =
+            schedule_packets(now, mem::replace(&mut queued_resends, Vec::new()), |resend| resend.packet.addr, &config, &mut connections);
+          let (selected_packets, over_budget_packets) =
+            select_by_priority(mem::replace(&mut queued_packets, Vec::new()), config.peer_tick_budget);
+          let (allowed_batches, in_flight_batches) =
+            limit_in_flight(batch_packets(selected_packets, max_payload_size), config.max_in_flight_per_peer, &packets_awaiting_ack);
+          let (send_batches, held_batches) =
+            schedule_packets(now, allowed_batches, |batch| batch[0].packet.addr, &config, &mut connections);
+          queued_resends = held_resends;
+          queued_packets =
+            over_budget_packets.into_iter()
+              .chain(in_flight_batches.into_iter().chain(held_batches.into_iter()).flat_map(|batch| batch.into_iter()))
+              .collect();
+          if config.overflow_policy == OverflowPolicy::DropOldestUnreliable {
+            drop_oldest_unreliable(&mut queued_packets, config.max_queued_per_peer, &event_tx);
+          }
+          report_deferred(&mut queued_packets, &event_tx);
+          free_queue_slots(received_counts, &queued_packets, &mut queued_counts, &queue_slots);
+
+          for resend in resends.iter() {
+            if let Some(connection) = connections.get_mut(&resend.packet.addr) {
+              connection.stats.resends += 1;
+            }
+          }
+          resends.into_iter()
+            .map(|dropped_packet| {
+              let packet = Packet{addr: dropped_packet.packet.addr, bytes: dropped_packet.packet.bytes};
+              (packet.clone(), dropped_packet.tries, Some(packet), dropped_packet.ids)
             })
-            .foreach(|packet| {let _ = api_out_tx.send(Packet {addr: packet.addr, bytes: packet.bytes});});
-
-          dropped_packets.into_iter()
-            .filter(|dropped_packet| dropped_packet.tries < MAX_RESEND_ATTEMPTS)
-            .map(|dropped_packet| (dropped_packet.packet, dropped_packet.tries))
-            .map(|(packet, tries)| (Packet{addr:packet.addr, bytes: packet.bytes}, tries))
-            .chain(send_packets.into_iter().map(|packet| (packet, 0)))
-            .map(|(packet, tries): (Packet, i32)| {
+            .chain(send_batches.into_iter().map(pack_batch).map(|(packet, resend_packet, ids)| (packet, 0, resend_packet, ids)))
+            .map(|(packet, tries, resend_packet, ids): (Packet, i32, Option<Packet>, Vec<MessageId>)| {
               let new_seq_num = increment_seq_number(&mut seq_num_map, packet.addr.clone());
-              (packet.add_sequence_number(new_seq_num), tries)
+              (packet.add_sequence_number(new_seq_num), tries, resend_packet, ids)
             })
-            .map(|(packet, tries): (SequencedPacket, i32)| {
+            .map(|(packet, tries, resend_packet, ids): (SequencedPacket, i32, Option<Packet>, Vec<MessageId>)| {
               let default = PeerAcks {ack_num: 0, ack_field: 0}; // TODO: remove this when we dont need it
               let ack_data = ack_map.get(&packet.addr).unwrap_or(&default);
-              (packet.add_acks(ack_data.ack_num, ack_data.ack_field), tries)
+              (packet.add_acks(ack_data.ack_num, ack_data.ack_field), tries, resend_packet, ids)
             })
-            .map(|(final_payload, tries)| {
-              add_packet_to_waiting(&final_payload, tries, &mut packets_awaiting_ack);
+            .map(|(final_payload, tries, resend_packet, ids)| {
+              // Only reliable messages are worth resending, so that is all that waits on the ack
+              if let Some(resend_packet) = resend_packet {
+                let waiting_packet = SequencedAckedPacket {
+                  addr: final_payload.addr,
+                  seq_num: final_payload.seq_num,
+                  ack_num: final_payload.ack_num,
+                  ack_field: final_payload.ack_field,
+                  bytes: resend_packet.bytes
+                };
+                add_packet_to_waiting(&waiting_packet, tries, ids, &mut packets_awaiting_ack);
+              }
               final_payload
             })
-            .foreach(|final_payload| {let _ = socket_send_tx.send(final_payload);});
+            .for_each(|final_payload| {
+              if let Some(connection) = connections.get_mut(&final_payload.addr) {
+                connection.last_sent = now;
+                connection.acks_owed_since = None;
+              }
+              let parities = protect_packet(&final_payload, now, &config, &peer_sizes, &mut connections);
+              send_to_peer(final_payload, &socket_send_tx, &mut connections);
+              parities.into_iter().for_each(|parity| send_to_peer(parity, &socket_send_tx, &mut connections));
+            });
+
+          collect_input_packets(now, &config, &peer_sizes, &mut connections, &mut seq_num_map, &ack_map).into_iter()
+            .for_each(|input_packet| {
+              if let Some(connection) = connections.get_mut(&input_packet.addr) {
+                connection.last_sent = now;
+                connection.acks_owed_since = None;
+              }
+              let parities = protect_packet(&input_packet, now, &config, &peer_sizes, &mut connections);
+              send_to_peer(input_packet, &socket_send_tx, &mut connections);
+              parities.into_iter().for_each(|parity| send_to_peer(parity, &socket_send_tx, &mut connections));
+            });
+
+          collect_ack_packets(now, config.ack_delay, &mut connections, &ack_map).into_iter()
+            .for_each(|ack_packet| send_to_peer(ack_packet, &socket_send_tx, &mut connections));
+
+          collect_blob_packets(now, &config, &mut connections, &event_tx).into_iter()
+            .for_each(|blob_packet| {
+              if let Some(connection) = connections.get_mut(&blob_packet.addr) {
+                connection.last_sent = now;
+              }
+              send_to_peer(blob_packet, &socket_send_tx, &mut connections);
+            });
+
+          collect_mtu_probes(now, &config, &mut connections).into_iter()
+            .for_each(|probe| send_to_peer(probe, &socket_send_tx, &mut connections));
+
+          collect_keepalives(now, config.keepalive_interval, &mut connections).into_iter()
+            .for_each(|addr| send_to_peer(control_packet(addr, PacketKind::Heartbeat), &socket_send_tx, &mut connections));
+
+          stats.publish(peer_stats(&connections, &packets_awaiting_ack, &config));
           // TODO: tune
           thread::sleep_ms(5)
         }
+
+        connections.drain().for_each(|(addr, _)| {
+          (0..DISCONNECT_REPEAT_COUNT)
+            .for_each(|_| {let _ = socket_send_tx.send(control_packet(addr, PacketKind::Disconnect));});
+          let _ = event_tx.send(NetworkEvent::Disconnected(addr));
+        });
+        queue_slots.free_all();
+        stats.publish(HashMap::new());
+        let mut unacked_packets = drain_unacked_packets(&mut packets_awaiting_ack);
+        unacked_packets.extend(drain_queued_packets(queued_resends, queued_packets).into_iter());
+        unacked_packets
       });
 
       Director {
         api_out_rx: api_out_rx,
         api_in_tx: api_in_tx,
+        command_tx: command_tx,
         thread_handle: thread_handle
       }
     }
   }
-  pub fn extract_dropped_packets(packets_awaiting_ack: &mut HashMap<(SocketAddr, u16), (SequencedAckedPacket, SteadyTime, i32)>) -> Vec<PacketWithTries>{
+
+  // Counts the packet against the peer's stats on its way to the socket
+  pub fn send_to_peer(packet: SequencedAckedPacket, socket_send_tx: &Sender<SequencedAckedPacket>, connections: &mut HashMap<SocketAddr, Connection>) {
+    if let Some(connection) = connections.get_mut(&packet.addr) {
+      connection.stats.packets_sent += 1;
+      connection.stats.bytes_sent += wire_size(&packet) as u64;
+    }
+    let _ = socket_send_tx.send(packet);
+  }
+
+  // Marker and headers included, but not fragment headers
+  pub fn wire_size(packet: &SequencedAckedPacket) -> usize {
+    MARKER_LEN + HEADER_LEN + packet.bytes.len()
+  }
+
+  // Every connected peer's counters, with the current rtt, loss, in flight and send rate filled in
+  pub fn peer_stats(connections: &HashMap<SocketAddr, Connection>, packets_awaiting_ack: &AwaitingAckMap, config: &NetworkConfig) -> HashMap<SocketAddr, PeerStats> {
+    let in_flight = count_by_peer(packets_awaiting_ack.keys().map(|&(addr, _)| addr));
+    connections.iter()
+      .filter(|&(_, connection)| connection.is_connected())
+      .map(|(addr, connection)| {
+        let stats = PeerStats {
+          smoothed_rtt: connection.rtt.smoothed_rtt(),
+          loss_percent: connection.congestion.loss_percent(),
+          packets_in_flight: in_flight.get(addr).cloned().unwrap_or(0),
+          send_rate: connection.congestion.send_rate(config),
+          datagram_size: connection.mtu.datagram_size(config),
+          .. connection.stats.clone()
+        };
+        (addr.clone(), stats)
+      })
+      .collect()
+  }
+
+  // Every connected peer's datagram size, for the sender thread to fragment with
+  pub fn peer_datagram_sizes(connections: &HashMap<SocketAddr, Connection>, config: &NetworkConfig) -> HashMap<SocketAddr, usize> {
+    connections.iter()
+      .filter(|&(_, connection)| connection.is_connected())
+      .map(|(addr, connection)| (addr.clone(), connection.mtu.datagram_size(config)))
+      .collect()
+  }
+
+  // Probes go out one size at a time, each given the peer's resend timeout to be acked
+  pub fn collect_mtu_probes(now: SteadyTime, config: &NetworkConfig, connections: &mut HashMap<SocketAddr, Connection>) -> Vec<SequencedAckedPacket> {
+    connections.iter_mut()
+      .filter(|&(_, ref connection)| connection.is_connected())
+      .filter_map(|(addr, connection)| {
+        let resend_timeout = connection.rtt.resend_timeout(1);
+        connection.mtu.next_probe(now, resend_timeout, config)
+          .map(|size| unsequenced_packet(*addr, probe_payload(size)))
+      })
+      .collect()
+  }
+
+  // Answers a probe with its size, or raises the peer's datagram size when the probe being tried is acked
+  pub fn handle_probe_packet(packet: &SequencedAckedPacket, config: &NetworkConfig, connections: &mut HashMap<SocketAddr, Connection>) -> Option<SequencedAckedPacket> {
+    if !is_connected(&packet.addr, connections) {
+      return None;
+    }
+    match packet.kind() {
+      Some(PacketKind::Probe) => Some(unsequenced_packet(packet.addr, probe_ack_payload(wire_size(packet)))),
+      _ => {
+        if let (Some(size), Some(connection)) = (read_probe_ack(&packet.bytes), connections.get_mut(&packet.addr)) {
+          connection.mtu.probe_acked(size, config);
+        }
+        None
+      }
+    }
+  }
+
+  // One sequenced packet for each channel with inputs that have not been sent yet, carrying all of its
+  // unacked inputs. Channels the peer's send rate cannot fit this tick send theirs with the next ones.
+  // Each input packet holds as many unacked inputs as fit in one datagram to its peer
+  pub fn collect_input_packets(now: SteadyTime, config: &NetworkConfig, peer_sizes: &HashMap<SocketAddr, usize>, connections: &mut HashMap<SocketAddr, Connection>, seq_num_map: &mut HashMap<SocketAddr, u16>, ack_map: &HashMap<SocketAddr, PeerAcks>) -> Vec<SequencedAckedPacket> {
+    let mut input_packets = Vec::new();
+    for (addr, connection) in connections.iter_mut().filter(|&(_, ref connection)| connection.is_connected()) {
+      let datagram_size = peer_sizes.get(addr).cloned().unwrap_or(config.max_datagram_size);
+      let max_len = datagram_size - MARKER_LEN - HEADER_LEN - DATA_HEADER_LEN - fec_header_len(config);
+      for channel_num in 0..connection.channels.len() {
+        if !connection.channels[channel_num].inputs.has_new() || !connection.congestion.try_send(now, config) {
+          continue;
+        }
+        let inputs = &mut connection.channels[channel_num].inputs;
+        if let Some((first_num, bytes)) = inputs.payload(config.input_redundancy, max_len) {
+          let seq_num = increment_seq_number(seq_num_map, *addr);
+          let (ack_num, ack_field) = ack_map.get(addr).map(|peer_acks| (peer_acks.ack_num, peer_acks.ack_field)).unwrap_or((0, 0));
+          inputs.record_sent(seq_num);
+          let packet =
+            Packet { addr: *addr, bytes: bytes }
+              .add_delivery(DeliveryMode::Input, channel_num as u8, first_num)
+              .add_kind(PacketKind::Data)
+              .add_sequence_number(seq_num)
+              .add_acks(ack_num, ack_field);
+          input_packets.push(packet);
+        }
+      }
+    }
+    input_packets
+  }
+
+  // With FEC on, packets leave room for the parity header so their parities still fit in one datagram
+  pub fn fec_header_len(config: &NetworkConfig) -> usize {
+    if config.fec_group_size > 0 && !config.fec_delivery_modes.is_empty() { FEC_HEADER_LEN } else { 0 }
+  }
+
+  // Adds a sequenced packet to its peer's FEC group, returning the group's parity packet once it fills.
+  // Only packets carrying a message in one of fec_delivery_modes are protected, and packets whose parity
+  // would not fit in one datagram are left out. Parities are charged to the peer's send rate.
+  pub fn protect_packet(packet: &SequencedAckedPacket, now: SteadyTime, config: &NetworkConfig, peer_sizes: &HashMap<SocketAddr, usize>, connections: &mut HashMap<SocketAddr, Connection>) -> Vec<SequencedAckedPacket> {
+    let datagram_size = peer_sizes.get(&packet.addr).cloned().unwrap_or(config.max_datagram_size);
+    if config.fec_group_size == 0 || wire_size(packet) + FEC_HEADER_LEN > datagram_size || !has_fec_mode(packet, config) {
+      return Vec::new();
+    }
+    let parities: Vec<SequencedAckedPacket> =
+      connections.get_mut(&packet.addr)
+        .map(|connection| connection.fec_encoder.add(packet, config.fec_group_size))
+        .unwrap_or(Vec::new())
+        .into_iter()
+        .map(|parity| unsequenced_packet(packet.addr, parity))
+        .collect();
+    if let Some(connection) = connections.get_mut(&packet.addr) {
+      for parity in parities.iter() {
+        connection.congestion.charge(now, config);
+        connection.stats.parities_sent += 1;
+        connection.stats.parity_bytes_sent += wire_size(parity) as u64;
+      }
+    }
+    parities
+  }
+
+  // Whether a Data packet, or any Data packet in an Aggregate, was sent with one of fec_delivery_modes
+  fn has_fec_mode(packet: &SequencedAckedPacket, config: &NetworkConfig) -> bool {
+    packet.clone().split_aggregate()
+      .unwrap_or(Vec::new())
+      .into_iter()
+      .filter_map(|message| message.strip_kind().strip_delivery())
+      .any(|message| config.fec_delivery_modes.contains(&message.delivery))
+  }
+
+  pub fn recover_packet(parity: &SequencedAckedPacket, connections: &mut HashMap<SocketAddr, Connection>) -> Option<SequencedAckedPacket> {
+    connections.get_mut(&parity.addr)
+      .and_then(|connection| {
+        let recovered = connection.fec_decoder.recover(parity.addr, &parity.bytes);
+        if recovered.is_some() {
+          connection.stats.recovered += 1;
+        }
+        recovered
+      })
+  }
+
+  // Control packets are not sequenced or acked, so they never touch the ack machinery
+  pub fn control_packet(addr: SocketAddr, kind: PacketKind) -> SequencedAckedPacket {
+    unsequenced_packet(addr, vec![kind.to_byte()])
+  }
+
+  // Connect and Accept carry the sender's session, so a restarted peer can be told apart from a repeated Connect
+  pub fn handshake_packet(addr: SocketAddr, kind: PacketKind, session: u32) -> SequencedAckedPacket {
+    let mut bytes = vec![kind.to_byte(), 0, 0, 0, 0];
+    BigEndian::write_u32(&mut bytes[1..5], session);
+    unsequenced_packet(addr, bytes)
+  }
+
+  pub fn read_session(bytes: &[u8]) -> Option<u32> {
+    if bytes.len() < 5 {
+      return None;
+    }
+    Some(BigEndian::read_u32(&bytes[1..5]))
+  }
+
+  // Control and blob packets skip the seq and ack machinery entirely
+  pub fn unsequenced_packet(addr: SocketAddr, bytes: Vec<u8>) -> SequencedAckedPacket {
+    SequencedAckedPacket {
+      addr: addr,
+      seq_num: 0,
+      ack_num: 0,
+      ack_field: 0,
+      bytes: bytes
+    }
+  }
+
+  pub fn is_connected(addr: &SocketAddr, connections: &HashMap<SocketAddr, Connection>) -> bool {
+    connections.get(addr).map(|connection| connection.is_connected()).unwrap_or(false)
+  }
+
+  pub fn mark_connected(addr: SocketAddr, now: SteadyTime, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) {
+    if !is_connected(&addr, connections) {
+      // Whatever the handshake sent still counts
+      let mut connection = Connection::connected(now);
+      if let Some(connecting) = connections.get(&addr) {
+        connection.session = connecting.session;
+        connection.stats = connecting.stats.clone();
+      }
+      connections.insert(addr, connection);
+      let _ = event_tx.send(NetworkEvent::Connected(addr));
+    }
+  }
+
+  // Drops a connected peer that connects again with a different session, since it must have restarted.
+  // Returns whether it did, so the rest of the peer's state can be forgotten too.
+  pub fn end_old_session(addr: SocketAddr, session: Option<u32>, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) -> bool {
+    let is_new_session =
+      connections.get(&addr)
+        .map(|connection| connection.is_connected() && session.is_some() && connection.peer_session.map(|peer_session| Some(peer_session) != session).unwrap_or(false))
+        .unwrap_or(false);
+    if is_new_session {
+      connections.remove(&addr);
+      let _ = event_tx.send(NetworkEvent::Disconnected(addr));
+    }
+    is_new_session
+  }
+
+  // Returns the reply that should be sent to the peer, if any
+  pub fn handle_control_packet(kind: PacketKind, addr: SocketAddr, session: Option<u32>, now: SteadyTime, max_connections: usize, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) -> Option<SequencedAckedPacket> {
+    match kind {
+      // New peers past the limit get no Accept, so their connect attempts run out
+      PacketKind::Connect if !connections.contains_key(&addr) && connections.len() >= max_connections => None,
+      PacketKind::Connect => {
+        mark_connected(addr, now, connections, event_tx);
+        connections.get_mut(&addr).map(|connection| {
+          connection.peer_session = connection.peer_session.or(session);
+          handshake_packet(addr, PacketKind::Accept, connection.session)
+        })
+      },
+      PacketKind::Accept => {
+        if connections.contains_key(&addr) {
+          mark_connected(addr, now, connections, event_tx);
+          if let Some(connection) = connections.get_mut(&addr) {
+            connection.peer_session = connection.peer_session.or(session);
+          }
+        }
+        None
+      },
+      PacketKind::Disconnect => {
+        if connections.remove(&addr).is_some() {
+          let _ = event_tx.send(NetworkEvent::Disconnected(addr));
+        }
+        None
+      },
+      // Fragments are reassembled before they reach the director, and blob, probe and parity packets are handled separately
+      PacketKind::Heartbeat | PacketKind::Data | PacketKind::Fragment |
+      PacketKind::BlobChunk | PacketKind::BlobAck | PacketKind::BlobCancel | PacketKind::Aggregate |
+      PacketKind::Ack | PacketKind::Probe | PacketKind::ProbeAck | PacketKind::Parity => None
+    }
+  }
+
+  // Data from a peer we are still connecting to means our Accept was lost on the way
+  pub fn accept_data_packet(addr: SocketAddr, now: SteadyTime, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) -> bool {
+    if connections.contains_key(&addr) {
+      mark_connected(addr, now, connections, event_tx);
+      true
+    } else {
+      false
+    }
+  }
+
+  // Wraps an application packet in its delivery header, stamping the next order number for its channel's stream,
+  // and queues it up with whether it needs to be resent until acked
+  pub fn frame_outgoing_packet(outgoing: OutgoingPacket, max_packet_size: usize, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) -> Option<QueuedPacket> {
+    let addr = outgoing.packet.addr;
+    match connections.get_mut(&addr) {
+      Some(ref mut connection) if connection.is_connected() => {
+        match connection.channel_mut(outgoing.channel) {
+          Some(_) if outgoing.packet.bytes.len() + DATA_HEADER_LEN > max_packet_size => {
+            let size = outgoing.packet.bytes.len();
+            let _ = event_tx.send(NetworkEvent::Error(NetworkError::PacketTooLarge(addr, size)));
+            None
+          },
+          // Inputs wait in their stream for the next input packet instead
+          Some(channel) if outgoing.delivery == DeliveryMode::Input => {
+            channel.inputs.push(outgoing.packet.bytes);
+            None
+          },
+          Some(channel) => {
+            let order_num = channel.next_order_num(outgoing.delivery);
+            let framed_packet =
+              outgoing.packet
+                .add_delivery(outgoing.delivery, outgoing.channel, order_num)
+                .add_kind(PacketKind::Data);
+            Some(QueuedPacket {
+              packet: framed_packet,
+              is_reliable: outgoing.delivery.is_reliable(),
+              id: outgoing.id,
+              priority: outgoing.priority,
+              accumulated: 0,
+              deferred: false
+            })
+          },
+          None => {
+            let _ = event_tx.send(NetworkEvent::Error(NetworkError::InvalidChannel(addr, outgoing.channel)));
+            None
+          }
+        }
+      },
+      _ => {
+        let _ = event_tx.send(NetworkEvent::Error(NetworkError::NotConnected(addr)));
+        None
+      }
+    }
+  }
+
+  pub fn start_blob(addr: SocketAddr, id: BlobId, bytes: Vec<u8>, max_datagram_size: usize, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) {
+    if bytes.len() > MAX_BLOB_SIZE as usize {
+      let _ = event_tx.send(NetworkEvent::Error(NetworkError::PacketTooLarge(addr, bytes.len())));
+      return;
+    }
+    match connections.get_mut(&addr) {
+      Some(ref mut connection) if connection.is_connected() => connection.blobs.start_send(id, bytes, max_datagram_size),
+      _ => {let _ = event_tx.send(NetworkEvent::Error(NetworkError::NotConnected(addr)));}
+    }
+  }
+
+  // Returns whether the blob was still being sent, so the peer needs telling
+  pub fn cancel_blob(addr: SocketAddr, id: BlobId, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) -> bool {
+    let was_sending =
+      connections.get_mut(&addr)
+        .map(|connection| connection.blobs.cancel_send(id))
+        .unwrap_or(false);
+    if was_sending {
+      let _ = event_tx.send(NetworkEvent::BlobCancelled { addr: addr, id: id });
+    }
+    was_sending
+  }
+
+  pub fn handle_blob_packet(packet: &SequencedAckedPacket, now: SteadyTime, max_datagram_size: usize, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) {
+    match connections.get_mut(&packet.addr) {
+      Some(ref mut connection) if connection.is_connected() => {
+        match connection.blobs.receive(packet.addr, now, &packet.bytes, max_datagram_size) {
+          Ok(events) => events.into_iter().for_each(|event| {let _ = event_tx.send(event);}),
+          Err(err) => {let _ = event_tx.send(NetworkEvent::Error(err));}
+        }
+      },
+      _ => ()
+    }
+  }
+
+  // Blob chunks and acks that are due for every connected peer, with only as many chunks as its send rate allows
+  pub fn collect_blob_packets(now: SteadyTime, config: &NetworkConfig, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) -> Vec<SequencedAckedPacket> {
+    connections.iter_mut()
+      .filter(|&(_, ref connection)| connection.is_connected())
+      .flat_map(|(addr, connection)| {
+        let resend_timeout = connection.rtt.resend_timeout(1);
+        // Chunks count against the peer's send rate like sequenced packets, and only get what those left this tick
+        let max_chunks = connection.congestion.available(now, config);
+        let (payloads, chunks_sent, events) = connection.blobs.poll(*addr, now, resend_timeout, config.max_datagram_size, max_chunks);
+        connection.congestion.spend(chunks_sent);
+        events.into_iter().for_each(|event| {let _ = event_tx.send(event);});
+        let addr = *addr;
+        payloads.into_iter().map(move |bytes| unsequenced_packet(addr, bytes))
+      })
+      .collect()
+  }
+
+  // Groups each peer's framed packets into as few batches as fit in its max_payload_size once packed, keeping send order
+  pub fn batch_packets<F>(packets: Vec<QueuedPacket>, max_payload_size: F) -> Vec<Vec<QueuedPacket>>
+    where F: Fn(SocketAddr) -> usize {
+    let mut peer_order = Vec::new();
+    let mut packets_by_peer: HashMap<SocketAddr, Vec<QueuedPacket>> = HashMap::new();
+    for packet in packets.into_iter() {
+      if !packets_by_peer.contains_key(&packet.packet.addr) {
+        peer_order.push(packet.packet.addr);
+      }
+      packets_by_peer.entry(packet.packet.addr).or_insert(Vec::new()).push(packet);
+    }
+
+    peer_order.into_iter()
+      .flat_map(|addr| {
+        // Every packet in an aggregate costs its length prefix, on top of the aggregate's kind byte
+        let mut batches = Vec::new();
+        let mut batch = Vec::new();
+        let mut batch_size = 1;
+        let max_payload_size = max_payload_size(addr);
+        for packet in packets_by_peer.remove(&addr).unwrap_or(Vec::new()).into_iter() {
+          let packet_size = 2 + packet.packet.bytes.len();
+          if !batch.is_empty() && batch_size + packet_size > max_payload_size {
+            batches.push(batch);
+            batch = Vec::new();
+            batch_size = 1;
+          }
+          batch_size += packet_size;
+          batch.push(packet);
+        }
+        if !batch.is_empty() {
+          batches.push(batch);
+        }
+        batches.into_iter()
+      })
+      .collect()
+  }
+
+  // Packs a batch into the packet to send, alongside what to resend if it goes unacked (just its
+  // reliable messages, if it has any) and the ids of those messages
+  pub fn pack_batch(batch: Vec<QueuedPacket>) -> (Packet, Option<Packet>, Vec<MessageId>) {
+    let addr = batch[0].packet.addr;
+    let (reliable_packets, ids): (Vec<Packet>, Vec<MessageId>) =
+      batch.iter()
+        .filter(|queued| queued.is_reliable)
+        .map(|queued| (queued.packet.clone(), queued.id))
+        .unzip();
+    let resend_packet = if reliable_packets.is_empty() { None } else { Some(pack_packets(addr, reliable_packets)) };
+    let packet = pack_packets(addr, batch.into_iter().map(|queued| queued.packet).collect());
+    (packet, resend_packet, ids)
+  }
+
+  // A lone packet goes out as it is, without the aggregate framing
+  fn pack_packets(addr: SocketAddr, mut packets: Vec<Packet>) -> Packet {
+    if packets.len() == 1 {
+      packets.remove(0)
+    } else {
+      Packet::aggregate(addr, packets)
+    }
+  }
+
+  // Returns the packets the peer's delivery channels have released for the application,
+  // from a Data packet or every Data packet in an Aggregate
+  pub fn deliver_packet(packet: SequencedAckedPacket, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) -> Vec<Packet> {
+    let addr = packet.addr;
+    match packet.split_aggregate() {
+      Some(packets) => {
+        let mut delivered = Vec::new();
+        for packet in packets.into_iter() {
+          delivered.extend(deliver_message(packet, connections, event_tx).into_iter());
+        }
+        delivered
+      },
+      None => {
+        let _ = event_tx.send(NetworkEvent::Error(NetworkError::MalformedPacket(addr)));
+        Vec::new()
+      }
+    }
+  }
+
+  // Whether every reliable message in a Data packet or Aggregate fits its channel's window
+  pub fn fits_windows(packet: &SequencedAckedPacket, connections: &HashMap<SocketAddr, Connection>) -> bool {
+    let connection = match connections.get(&packet.addr) {
+      Some(connection) => connection,
+      None => return true
+    };
+    packet.clone().split_aggregate()
+      .unwrap_or(Vec::new())
+      .into_iter()
+      .filter_map(|message| message.strip_kind().strip_delivery())
+      .all(|message| {
+        connection.channels.get(message.channel as usize)
+          .map(|channel| channel.fits_window(message.delivery, message.order_num))
+          .unwrap_or(true)
+      })
+  }
+
+  fn deliver_message(packet: SequencedAckedPacket, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) -> Vec<Packet> {
+    let addr = packet.addr;
+    match (packet.strip_kind().strip_delivery(), connections.get_mut(&addr)) {
+      (Some(delivery_packet), Some(connection)) => {
+        connection.channel_mut(delivery_packet.channel)
+          .map(|channel| channel.receive(delivery_packet))
+          .unwrap_or(Vec::new())
+      },
+      (None, _) => {
+        let _ = event_tx.send(NetworkEvent::Error(NetworkError::MalformedPacket(addr)));
+        Vec::new()
+      },
+      (_, None) => Vec::new()
+    }
+  }
+
+  // Returns the peers that need another Connect, dropping those that ran out of attempts
+  pub fn retry_connections(now: SteadyTime, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) -> Vec<(SocketAddr, u32)> {
+    let failed_addrs: Vec<SocketAddr> =
+      connections.iter()
+        .filter(|&(_, connection)| connection.needs_connect_attempt(now) && connection.attempts >= MAX_CONNECT_ATTEMPTS)
+        .map(|(addr, _)| addr.clone())
+        .collect();
+
+    failed_addrs.into_iter().for_each(|addr| {
+      connections.remove(&addr);
+      let _ = event_tx.send(NetworkEvent::ConnectionFailed(addr));
+    });
+
+    connections.iter_mut()
+      .filter(|&(_, ref connection)| connection.needs_connect_attempt(now))
+      .map(|(addr, connection)| {
+        connection.attempts += 1;
+        connection.last_attempt = now;
+        (addr.clone(), connection.session)
+      })
+      .collect()
+  }
+
+  // Returns the peers that went silent for too long, having already dropped their connections
+  pub fn expire_idle_connections(now: SteadyTime, peer_timeout: Duration, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) -> Vec<SocketAddr> {
+    let timed_out_addrs: Vec<SocketAddr> =
+      connections.iter()
+        .filter(|&(_, connection)| connection.is_timed_out(now, peer_timeout))
+        .map(|(addr, _)| addr.clone())
+        .collect();
+
+    timed_out_addrs.iter().for_each(|addr| {
+      connections.remove(addr);
+      let _ = event_tx.send(NetworkEvent::TimedOut(addr.clone()));
+    });
+    timed_out_addrs
+  }
+
+  // Ack-only packets for the peers whose acks have waited ack_delay without outgoing data to carry them
+  pub fn collect_ack_packets(now: SteadyTime, ack_delay: Duration, connections: &mut HashMap<SocketAddr, Connection>, ack_map: &HashMap<SocketAddr, PeerAcks>) -> Vec<SequencedAckedPacket> {
+    connections.iter_mut()
+      .filter(|&(_, ref connection)| connection.needs_ack(now, ack_delay))
+      .filter_map(|(addr, connection)| {
+        connection.acks_owed_since = None;
+        connection.last_sent = now;
+        ack_map.get(addr).map(|peer_acks| SequencedAckedPacket {
+          addr: addr.clone(),
+          seq_num: 0,
+          ack_num: peer_acks.ack_num,
+          ack_field: peer_acks.ack_field,
+          bytes: vec![PacketKind::Ack.to_byte()]
+        })
+      })
+      .collect()
+  }
+
+  // Returns the peers that have not been sent anything recently, marking them as sent to
+  pub fn collect_keepalives(now: SteadyTime, keepalive_interval: Duration, connections: &mut HashMap<SocketAddr, Connection>) -> Vec<SocketAddr> {
+    connections.iter_mut()
+      .filter(|&(_, ref connection)| connection.needs_keepalive(now, keepalive_interval))
+      .map(|(addr, connection)| {
+        connection.last_sent = now;
+        addr.clone()
+      })
+      .collect()
+  }
+
+  // Splits a dropped packet into what is still worth resending and what ran out of resend attempts.
+  // Ordered messages are never given up on, since every later one on their channel waits for them,
+  // so they are resent until they are acked or their peer times out.
+  pub fn split_lost_messages(dropped_packet: PacketWithTries) -> (Option<PacketWithTries>, Option<PacketWithTries>) {
+    if dropped_packet.tries < MAX_RESEND_ATTEMPTS {
+      return (Some(dropped_packet), None);
+    }
+    let addr = dropped_packet.packet.addr;
+    let (ordered, unordered): (Vec<(SequencedAckedPacket, MessageId)>, Vec<(SequencedAckedPacket, MessageId)>) =
+      dropped_packet.packet.clone().split_aggregate().unwrap_or(Vec::new()).into_iter()
+        .zip(dropped_packet.ids.iter().cloned())
+        .partition(|&(ref message, _)| is_ordered(message));
+    let repack = |messages: Vec<(SequencedAckedPacket, MessageId)>| {
+      if messages.is_empty() {
+        return None;
+      }
+      let (packets, ids): (Vec<Packet>, Vec<MessageId>) =
+        messages.into_iter()
+          .map(|(message, id)| (Packet { addr: addr, bytes: message.bytes }, id))
+          .unzip();
+      Some(PacketWithTries {
+        packet: SequencedAckedPacket { bytes: pack_packets(addr, packets).bytes, .. dropped_packet.packet.clone() },
+        tries: dropped_packet.tries,
+        ids: ids
+      })
+    };
+    (repack(ordered), repack(unordered))
+  }
+
+  fn is_ordered(message: &SequencedAckedPacket) -> bool {
+    message.clone().strip_kind().strip_delivery()
+      .map(|message| message.delivery == DeliveryMode::ReliableOrdered)
+      .unwrap_or(false)
+  }
+
+  // One event per reliable message the lost packet carried
+  pub fn lost_events(lost_packet: PacketWithTries) -> Vec<NetworkEvent> {
+    let attempts = lost_packet.tries;
+    let packets = lost_packet.packet.split_aggregate().unwrap_or(Vec::new());
+    packets.into_iter()
+      .zip(lost_packet.ids.into_iter())
+      .filter_map(|(packet, id)| {
+        packet.strip_kind().strip_delivery()
+          .map(|packet| NetworkEvent::Lost { id: id, packet: packet.into_packet(), attempts: attempts })
+      })
+      .collect()
+  }
+
+  // Oldest first, with the packet kind stripped so they read like what the application sent
+  pub fn drain_unacked_packets(packets_awaiting_ack: &mut AwaitingAckMap) -> Vec<Packet> {
+    packets_awaiting_ack.drain()
+      .map(|(_, (packet, timestamp, _, _))| (packet, timestamp))
+      .sorted_by(|&(_, ref a), &(_, ref b)| a.cmp(b))
+      .into_iter()
+      .flat_map(|(packet, _)| packet.split_aggregate().unwrap_or(Vec::new()).into_iter())
+      .filter_map(|packet| packet.strip_kind().strip_delivery())
+      .map(|packet| packet.into_packet())
+      .collect()
+  }
+
+  // The reliable messages still waiting on a peer's send rate, resends first
+  pub fn drain_queued_packets(queued_resends: Vec<PacketWithTries>, queued_packets: Vec<QueuedPacket>) -> Vec<Packet> {
+    let resent_packets =
+      queued_resends.into_iter()
+        .flat_map(|resend| resend.packet.split_aggregate().unwrap_or(Vec::new()).into_iter());
+    let new_packets =
+      queued_packets.into_iter()
+        .filter(|queued| queued.is_reliable)
+        .map(|queued| queued.packet.add_sequence_number(0).add_acks(0, 0));
+    resent_packets.chain(new_packets)
+      .filter_map(|packet| packet.strip_kind().strip_delivery())
+      .map(|packet| packet.into_packet())
+      .collect()
+  }
+
+  // Moves each peer between its good and bad send rates, telling the application when that changes
+  pub fn update_congestion(now: SteadyTime, config: &NetworkConfig, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) {
+    connections.iter_mut()
+      .filter(|&(_, ref connection)| connection.is_connected())
+      .for_each(|(addr, connection)| {
+        let srtt = connection.rtt.smoothed_rtt();
+        if let Some(rate) = connection.congestion.update(now, srtt, config) {
+          let _ = event_tx.send(NetworkEvent::SendRateChanged { addr: addr.clone(), packets_per_second: rate });
+        }
+      });
+  }
+
+  // Picks each peer's most overdue packets, by accumulated priority, until its byte budget for the tick runs out.
+  // A peer's first packet always goes, so nothing bigger than the budget waits forever.
+  pub fn select_by_priority(queued_packets: Vec<QueuedPacket>, peer_tick_budget: usize) -> (Vec<QueuedPacket>, Vec<QueuedPacket>) {
+    let mut queued_packets: Vec<QueuedPacket> =
+      queued_packets.into_iter()
+        .map(|mut queued| {
+          queued.accumulated = queued.accumulated.saturating_add(queued.priority as u32);
+          queued
+        })
+        .collect();
+    // Stable, so equal priorities keep their send order
+    queued_packets.sort_by(|a, b| b.accumulated.cmp(&a.accumulated));
+
+    let mut spent: HashMap<SocketAddr, usize> = HashMap::new();
+    queued_packets.into_iter()
+      .partition(|queued| {
+        let peer_spent = spent.entry(queued.packet.addr).or_insert(0);
+        let size = queued.packet.bytes.len();
+        if *peer_spent == 0 || *peer_spent + size <= peer_tick_budget {
+          *peer_spent += size;
+          true
+        } else {
+          false
+        }
+      })
+  }
+
+  // Holds back batches with reliable messages for peers that already have max_in_flight_per_peer packets awaiting acks
+  pub fn limit_in_flight(batches: Vec<Vec<QueuedPacket>>, max_in_flight_per_peer: usize, packets_awaiting_ack: &AwaitingAckMap) -> (Vec<Vec<QueuedPacket>>, Vec<Vec<QueuedPacket>>) {
+    let mut in_flight = count_by_peer(packets_awaiting_ack.keys().map(|&(addr, _)| addr));
+    batches.into_iter()
+      .partition(|batch| {
+        if !batch.iter().any(|queued| queued.is_reliable) {
+          return true;
+        }
+        let peer_in_flight = in_flight.entry(batch[0].packet.addr).or_insert(0);
+        if *peer_in_flight < max_in_flight_per_peer {
+          *peer_in_flight += 1;
+          true
+        } else {
+          false
+        }
+      })
+  }
+
+  // Drops each peer's oldest unreliable messages until it is back to max_queued_per_peer, if it can be
+  pub fn drop_oldest_unreliable(queued_packets: &mut Vec<QueuedPacket>, max_queued_per_peer: usize, event_tx: &Sender<NetworkEvent>) {
+    let mut excess: HashMap<SocketAddr, usize> =
+      count_by_peer(queued_packets.iter().map(|queued| queued.packet.addr)).into_iter()
+        .filter(|&(_, count)| count > max_queued_per_peer)
+        .map(|(addr, count)| (addr, count - max_queued_per_peer))
+        .collect();
+    if excess.is_empty() {
+      return;
+    }
+
+    let dropped_ids: Vec<MessageId> =
+      queued_packets.iter()
+        .filter(|queued| !queued.is_reliable)
+        .sorted_by(|a, b| a.id.0.cmp(&b.id.0))
+        .into_iter()
+        .filter(|queued| {
+          excess.get_mut(&queued.packet.addr)
+            .map(|peer_excess| {
+              let should_drop = *peer_excess > 0;
+              if should_drop {
+                *peer_excess -= 1;
+              }
+              should_drop
+            })
+            .unwrap_or(false)
+        })
+        .map(|queued| queued.id)
+        .collect();
+    queued_packets.retain(|queued| !dropped_ids.contains(&queued.id));
+    dropped_ids.into_iter().for_each(|id| {let _ = event_tx.send(NetworkEvent::Dropped(id));});
+  }
+
+  // Frees the application's queue slots for every message that left the director this tick, however it left
+  pub fn free_queue_slots(received_counts: HashMap<SocketAddr, usize>, queued_packets: &Vec<QueuedPacket>, queued_counts: &mut HashMap<SocketAddr, usize>, queue_slots: &QueueSlots) {
+    let still_queued = count_by_peer(queued_packets.iter().map(|queued| queued.packet.addr));
+    let addrs: Vec<SocketAddr> = received_counts.keys().chain(queued_counts.keys()).cloned().unique().collect();
+    addrs.into_iter().for_each(|addr| {
+      let waiting = queued_counts.get(&addr).cloned().unwrap_or(0) + received_counts.get(&addr).cloned().unwrap_or(0);
+      let left = waiting.saturating_sub(still_queued.get(&addr).cloned().unwrap_or(0));
+      if left > 0 {
+        queue_slots.free(addr, left);
+      }
+    });
+    *queued_counts = still_queued;
+  }
+
+  pub fn count_by_peer<I: Iterator<Item=SocketAddr>>(addrs: I) -> HashMap<SocketAddr, usize> {
+    let mut counts = HashMap::new();
+    addrs.for_each(|addr| *counts.entry(addr).or_insert(0) += 1);
+    counts
+  }
+
+  pub fn report_deferred(queued_packets: &mut Vec<QueuedPacket>, event_tx: &Sender<NetworkEvent>) {
+    queued_packets.iter_mut()
+      .filter(|queued| !queued.deferred)
+      .for_each(|queued| {
+        queued.deferred = true;
+        let _ = event_tx.send(NetworkEvent::Deferred(queued.id));
+      });
+  }
+
+  // Splits packets into those their peers' send rates allow out now and those that have to wait, keeping order
+  pub fn schedule_packets<T, F>(now: SteadyTime, packets: Vec<T>, addr_of: F, config: &NetworkConfig, connections: &mut HashMap<SocketAddr, Connection>) -> (Vec<T>, Vec<T>)
+    where F: Fn(&T) -> SocketAddr {
+    packets.into_iter()
+      .partition(|packet| {
+        connections.get_mut(&addr_of(packet))
+          .map(|connection| connection.congestion.try_send(now, config))
+          .unwrap_or(false)
+      })
+  }
+
+  pub fn forget_peer(addr: SocketAddr, seq_num_map: &mut HashMap<SocketAddr, u16>, ack_map: &mut HashMap<SocketAddr, PeerAcks>, packets_awaiting_ack: &mut AwaitingAckMap) {
+    seq_num_map.remove(&addr);
+    ack_map.remove(&addr);
+    let awaiting_keys: Vec<(SocketAddr, u16)> =
+      packets_awaiting_ack.keys()
+        .filter(|&&(packet_addr, _)| packet_addr == addr)
+        .cloned()
+        .collect();
+    awaiting_keys.iter().for_each(|key| {packets_awaiting_ack.remove(key);});
+  }
+
+  pub fn extract_dropped_packets(packets_awaiting_ack: &mut AwaitingAckMap, connections: &HashMap<SocketAddr, Connection>) -> Vec<PacketWithTries>{
     let now = SteadyTime::now();
+    let default_rtt = RttEstimator::new();
     // Notify send thread of dropped packets
     //   Get keys first to sate the borrow checker
     let dropped_packet_keys: Vec<(SocketAddr, u16)> =
       packets_awaiting_ack.iter()
-        .filter(|&(_, &(_, timestamp, _))| {
+        .filter(|&(key, &(_, timestamp, tries, _))| {
           let timestamp: SteadyTime = timestamp; // Compiler why?
           let time_elapsed: Duration = now - timestamp;
-          time_elapsed.num_seconds() > PACKET_DROP_TIME
+          let rtt = connections.get(&key.0).map(|connection| &connection.rtt).unwrap_or(&default_rtt);
+          time_elapsed > rtt.resend_timeout(tries)
         })
-        .map(|(key, &(_, _, _))| {
+        .map(|(key, &(_, _, _, _))| {
           let key: &(SocketAddr, u16) = key; // Compiler why?
           key.clone()
         }).collect();
@@ -103,24 +1128,91 @@ mod state {
       .map(|key| packets_awaiting_ack.remove(&key))
       .filter(|result| result.is_some())
       .map(|result| result.unwrap())
-      .map(|(packet, _, tries)| PacketWithTries {packet: packet, tries: tries})
+      .map(|(packet, _, tries, ids)| PacketWithTries {packet: packet, tries: tries, ids: ids})
+      .collect()
+  }
+
+  // Every seq number an incoming packet acknowledges, newest first
+  pub fn acked_seq_nums(packet: &SequencedAckedPacket) -> Vec<u16> {
+    let ack_num = packet.ack_num;
+    let ack_field = packet.ack_field;
+    // Include initial ack
+    Some(ack_num).into_iter()
+      .chain(
+        (0..32).map(|bit_idx| {
+          // Builds a bit mask, and checks if bit is present by comparing result to 0
+          (bit_idx, 0 != ((1 << bit_idx) & ack_field))
+        })
+        .filter(|&(_, was_acked)| was_acked)
+        .map(|(idx, _)| ack_num.wrapping_sub(idx + 1))
+      )
       .collect()
   }
 
-  pub fn delete_acked_packets(packet: &SequencedAckedPacket, packets_awaiting_ack: &mut HashMap<(SocketAddr, u16), (SequencedAckedPacket, SteadyTime, i32)>) {
+  // The freshest packet an ack covers gives the sample least inflated by the peer holding the ack back
+  pub fn rtt_sample(now: SteadyTime, packet: &SequencedAckedPacket, packets_awaiting_ack: &AwaitingAckMap) -> Option<Duration> {
+    acked_seq_nums(packet).into_iter()
+      .filter_map(|seq_num| packets_awaiting_ack.get(&(packet.addr, seq_num)))
+      .map(|&(_, timestamp, _, _)| now - timestamp)
+      .min()
+  }
+
+  // Packets the peer's acks show as missing, either because enough later packets made it
+  // or because they fell off the end of the ack field without ever being acked.
+  // Call after delete_acked_packets so acked packets are already gone.
+  pub fn extract_nacked_packets(packet: &SequencedAckedPacket, packets_awaiting_ack: &mut AwaitingAckMap) -> Vec<PacketWithTries> {
     let ack_num = packet.ack_num;
     let ack_field = packet.ack_field;
-    (0..32).map(|bit_idx| {
-      // Builds a bit mask, and checks if bit is present by comparing result to 0
-      (bit_idx, 0 != ((1 << bit_idx) & ack_field))
-    }).foreach(|(idx, was_acked)| {
-      if was_acked {
-        packets_awaiting_ack.remove(&(packet.addr, ack_num.wrapping_sub(idx + 1)));
+    let nacked_keys: Vec<(SocketAddr, u16)> =
+      packets_awaiting_ack.keys()
+        .filter(|&&(addr, seq_num)| addr == packet.addr && is_newer_seq(ack_num, seq_num))
+        .filter(|&&(_, seq_num)| {
+          let ack_delta = ack_num.wrapping_sub(seq_num) as u32;
+          if ack_delta > 32 {
+            true
+          } else {
+            // The ack num itself, plus the bits for packets between it and this one
+            let later_acks = 1 + (ack_field & ((1 << (ack_delta - 1)) - 1)).count_ones();
+            later_acks >= FAST_RESEND_THRESHOLD
+          }
+        })
+        .cloned()
+        .collect();
+
+    nacked_keys.iter()
+      .filter_map(|key| packets_awaiting_ack.remove(key))
+      .map(|(packet, _, tries, ids)| PacketWithTries {packet: packet, tries: tries, ids: ids})
+      .collect()
+  }
+
+  // Takes in the acks an incoming packet carries: feeds the rtt and loss estimates, reports acked
+  // messages and returns the packets those acks show as missing
+  pub fn apply_acks(now: SteadyTime, packet: &SequencedAckedPacket, packets_awaiting_ack: &mut AwaitingAckMap, connections: &mut HashMap<SocketAddr, Connection>, event_tx: &Sender<NetworkEvent>) -> Vec<PacketWithTries> {
+    let sample = rtt_sample(now, packet, packets_awaiting_ack);
+    let acked_count =
+      acked_seq_nums(packet).into_iter()
+        .filter(|seq_num| packets_awaiting_ack.contains_key(&(packet.addr, *seq_num)))
+        .count();
+    delete_acked_packets(packet, packets_awaiting_ack).into_iter()
+      .for_each(|id| {let _ = event_tx.send(NetworkEvent::Acked(id));});
+    let nacked_packets = extract_nacked_packets(packet, packets_awaiting_ack);
+    let acked = acked_seq_nums(packet);
+    if let Some(connection) = connections.get_mut(&packet.addr) {
+      if let Some(sample) = sample {
+        connection.rtt.add_sample(sample);
       }
-    });
+      connection.congestion.record_outcomes(acked_count, nacked_packets.len());
+      connection.channels.iter_mut().for_each(|channel| channel.inputs.acked(&acked));
+    }
+    nacked_packets
+  }
 
-    // Remove initial ack
-    packets_awaiting_ack.remove(&(packet.addr, ack_num));
+  // Returns the ids of the messages that were waiting on the acked packets
+  pub fn delete_acked_packets(packet: &SequencedAckedPacket, packets_awaiting_ack: &mut AwaitingAckMap) -> Vec<MessageId> {
+    acked_seq_nums(packet).into_iter()
+      .filter_map(|seq_num| packets_awaiting_ack.remove(&(packet.addr, seq_num)))
+      .flat_map(|(_, _, _, ids)| ids.into_iter())
+      .collect()
   }
 
   pub fn increment_seq_number(seq_num_map: &mut HashMap<SocketAddr, u16>, addr: SocketAddr) -> u16 {
@@ -130,13 +1222,19 @@ mod state {
   }
 
 
-  pub fn add_packet_to_waiting(packet: &SequencedAckedPacket, tries: i32, packets_awaiting_ack: &mut HashMap<(SocketAddr, u16), (SequencedAckedPacket, SteadyTime, i32)>) {
+  pub fn add_packet_to_waiting(packet: &SequencedAckedPacket, tries: i32, ids: Vec<MessageId>, packets_awaiting_ack: &mut AwaitingAckMap) {
     packets_awaiting_ack.insert(
       (packet.addr.clone(), packet.seq_num.clone()),
-      (packet.clone(), SteadyTime::now(), tries + 1)
+      (packet.clone(), SteadyTime::now(), tries + 1, ids)
     );
   }
 
+  pub fn has_seen_packet(packet: &SequencedAckedPacket, ack_map: &HashMap<SocketAddr, PeerAcks>) -> bool {
+    ack_map.get(&packet.addr)
+      .map(|peer_acks| peer_acks.has_seen(packet.seq_num))
+      .unwrap_or(false)
+  }
+
   pub fn add_packet_to_ack_map(addr: SocketAddr, seq_num: u16, ack_map: &mut HashMap<SocketAddr, PeerAcks>) {
     let peer_acks = ack_map.entry(addr).or_insert(PeerAcks { ack_num: 0, ack_field: 0 });
     peer_acks.add_seq_num(seq_num); // TODO: Rename this so it doesn't sound like we're making a new packet
@@ -152,24 +1250,104 @@ mod state {
     use super::{
       extract_dropped_packets,
       delete_acked_packets,
+      acked_seq_nums,
+      extract_nacked_packets,
+      rtt_sample,
       increment_seq_number,
       add_packet_to_waiting,
-      add_packet_to_ack_map
+      add_packet_to_ack_map,
+      has_seen_packet,
+      handle_control_packet,
+      end_old_session,
+      handshake_packet,
+      read_session,
+      accept_data_packet,
+      retry_connections,
+      expire_idle_connections,
+      collect_keepalives,
+      collect_ack_packets,
+      apply_acks,
+      drain_unacked_packets,
+      lost_events,
+      split_lost_messages,
+      batch_packets,
+      pack_batch,
+      schedule_packets,
+      update_congestion,
+      drain_queued_packets,
+      select_by_priority,
+      report_deferred,
+      limit_in_flight,
+      drop_oldest_unreliable,
+      free_queue_slots,
+      count_by_peer,
+      send_to_peer,
+      wire_size,
+      peer_stats,
+      peer_datagram_sizes,
+      collect_mtu_probes,
+      handle_probe_packet,
+      protect_packet,
+      recover_packet,
+      collect_input_packets,
+      frame_outgoing_packet,
+      deliver_packet,
+      fits_windows,
+      forget_peer,
+      start_blob,
+      cancel_blob,
+      handle_blob_packet,
+      collect_blob_packets,
     };
-    use packet_types::SequencedAckedPacket;
+    use std::sync::mpsc::channel;
+    use packet_types::{
+      SequencedAckedPacket,
+      PacketKind,
+      Packet,
+      OutgoingPacket,
+      DeliveryMode,
+      MessageId,
+      PacketWithTries,
+      QueuedPacket,
+      BlobId,
+    };
+    use types::{NetworkEvent, PeerStats};
+    use errors::NetworkError;
+    use connection::Connection;
+    use helpers::try_recv_all;
+    use fragment::max_packet_size;
     use time::{SteadyTime, Duration};
     use constants::{
       MAX_RESEND_ATTEMPTS,
+      ORDERED_WINDOW,
+      MAX_CONNECT_ATTEMPTS,
+      CONNECT_RESEND_TIME,
       PACKET_DROP_TIME,
+      MAX_DATAGRAM_SIZE,
+      SEND_BURST,
+      MARKER_LEN,
+      HEADER_LEN,
+      DATA_HEADER_LEN,
+      FEC_HEADER_LEN,
+      MAX_CONNECTIONS,
     };
-    use itertools::Itertools;
+    use ack::PeerAcks;
+    use config::NetworkConfig;
+    use backpressure::QueueSlots;
+    use mtu::{probe_payload, probe_ack_payload};
+    
+
+    fn queued_packet(packet: Packet, is_reliable: bool, id: MessageId) -> QueuedPacket {
+      QueuedPacket { packet: packet, is_reliable: is_reliable, id: id, priority: 1, accumulated: 0, deferred: false }
+    }
 
     #[test]
     fn extract_dropped_packets_test() {
       let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
       let mut packets_awaiting_ack = HashMap::new();
+      let connections = HashMap::new();
 
-      let dropped_packets = extract_dropped_packets(&mut packets_awaiting_ack);
+      let dropped_packets = extract_dropped_packets(&mut packets_awaiting_ack, &connections);
       assert_eq!(dropped_packets.len(), 0);
 
       let not_dropped_packet = SequencedAckedPacket {
@@ -179,8 +1357,8 @@ mod state {
         ack_field: 3,
         bytes: vec![1]
       };
-      packets_awaiting_ack.insert((addr.clone(), 1), (not_dropped_packet.clone(), SteadyTime::now(), 2));
-      let dropped_packets = extract_dropped_packets(&mut packets_awaiting_ack);
+      packets_awaiting_ack.insert((addr.clone(), 1), (not_dropped_packet.clone(), SteadyTime::now(), 2, vec![MessageId(1)]));
+      let dropped_packets = extract_dropped_packets(&mut packets_awaiting_ack, &connections);
       assert_eq!(dropped_packets.len(), 0);
 
       let dropped_packet = SequencedAckedPacket {
@@ -190,11 +1368,12 @@ mod state {
         ack_field: 3,
         bytes: vec![1]
       };
-      packets_awaiting_ack.insert((addr.clone(), 2), (dropped_packet.clone(), SteadyTime::now() - Duration::seconds(PACKET_DROP_TIME + 5), 1));
-      let dropped_packets = extract_dropped_packets(&mut packets_awaiting_ack);
+      packets_awaiting_ack.insert((addr.clone(), 2), (dropped_packet.clone(), SteadyTime::now() - Duration::seconds(PACKET_DROP_TIME + 5), 1, vec![MessageId(2)]));
+      let dropped_packets = extract_dropped_packets(&mut packets_awaiting_ack, &connections);
       assert_eq!(dropped_packets.len(), 1);
       assert_eq!(dropped_packets[0].packet, dropped_packet);
       assert_eq!(dropped_packets[0].tries, 1);
+      assert_eq!(dropped_packets[0].ids, vec![MessageId(2)]);
     }
 
     #[test]
@@ -210,8 +1389,9 @@ mod state {
           ack_field: 3,
           bytes: vec![1]
         }
-      }).foreach(|packet| {
-        packets_awaiting_ack.insert((packet.addr, packet.seq_num), (packet, SteadyTime::now(), 1));
+      }).for_each(|packet| {
+        let id = MessageId(packet.seq_num as usize);
+        packets_awaiting_ack.insert((packet.addr, packet.seq_num), (packet, SteadyTime::now(), 1, vec![id]));
       });
       assert_eq!(packets_awaiting_ack.keys().count(), 4);
 
@@ -232,8 +1412,9 @@ mod state {
         ack_field: 0,
         bytes: vec![1]
       };
-      delete_acked_packets(&ack_packet, &mut packets_awaiting_ack);
+      let acked_ids = delete_acked_packets(&ack_packet, &mut packets_awaiting_ack);
       assert_eq!(packets_awaiting_ack.keys().count(), 3);
+      assert_eq!(acked_ids, vec![MessageId(2)]);
 
       let ack_packet = SequencedAckedPacket {
         addr: addr.clone(),
@@ -246,6 +1427,97 @@ mod state {
       assert_eq!(packets_awaiting_ack.keys().count(), 0);
     }
 
+    #[test]
+    fn extract_dropped_packets_uses_peer_rto_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut packets_awaiting_ack = HashMap::new();
+      let mut connections = HashMap::new();
+      let mut connection = Connection::connected(now);
+      connection.rtt.add_sample(Duration::milliseconds(20));
+      connections.insert(addr, connection);
+
+      let packet = |seq_num| SequencedAckedPacket { addr: addr.clone(), seq_num: seq_num, ack_num: 0, ack_field: 0, bytes: vec![1] };
+      // Well past the fast peer's timeout, but still inside the backed off timeout for a third try
+      let sent_at = now - Duration::milliseconds(200);
+      packets_awaiting_ack.insert((addr.clone(), 1), (packet(1), sent_at, 1, vec![MessageId(1)]));
+      packets_awaiting_ack.insert((addr.clone(), 2), (packet(2), sent_at, 3, vec![MessageId(2)]));
+
+      let dropped_packets = extract_dropped_packets(&mut packets_awaiting_ack, &connections);
+      assert_eq!(dropped_packets.len(), 1);
+      assert_eq!(dropped_packets[0].ids, vec![MessageId(1)]);
+    }
+
+    #[test]
+    fn acked_seq_nums_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let packet = SequencedAckedPacket { addr: addr, seq_num: 1, ack_num: 1, ack_field: 0b101, bytes: vec![1] };
+      assert_eq!(acked_seq_nums(&packet), vec![1, 0, 65534]);
+    }
+
+    #[test]
+    fn extract_nacked_packets_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let other_addr =  SocketAddr::from_str("127.0.0.1:54235").unwrap();
+      let now = SteadyTime::now();
+      let mut packets_awaiting_ack = HashMap::new();
+      let packet = |addr, seq_num| SequencedAckedPacket { addr: addr, seq_num: seq_num, ack_num: 0, ack_field: 0, bytes: vec![1] };
+      vec![(addr, 20), (addr, 40), (other_addr, 20)].into_iter().for_each(|(packet_addr, seq_num)| {
+        packets_awaiting_ack.insert((packet_addr, seq_num), (packet(packet_addr, seq_num), now, 1, vec![MessageId(seq_num as usize)]));
+      });
+
+      // Only 2 later packets acked for 20
+      let ack_packet = SequencedAckedPacket { addr: addr.clone(), seq_num: 1, ack_num: 22, ack_field: 0b1, bytes: vec![1] };
+      assert_eq!(extract_nacked_packets(&ack_packet, &mut packets_awaiting_ack).len(), 0);
+
+      // 3 later packets acked for 20, while 40 is newer than the ack num
+      let ack_packet = SequencedAckedPacket { addr: addr.clone(), seq_num: 1, ack_num: 23, ack_field: 0b11, bytes: vec![1] };
+      let nacked = extract_nacked_packets(&ack_packet, &mut packets_awaiting_ack);
+      assert_eq!(nacked.iter().flat_map(|packet| packet.ids.clone().into_iter()).collect::<Vec<MessageId>>(), vec![MessageId(20)]);
+
+      // 40 falls off the end of the ack field
+      let ack_packet = SequencedAckedPacket { addr: addr.clone(), seq_num: 1, ack_num: 73, ack_field: 0, bytes: vec![1] };
+      let nacked = extract_nacked_packets(&ack_packet, &mut packets_awaiting_ack);
+      assert_eq!(nacked.iter().flat_map(|packet| packet.ids.clone().into_iter()).collect::<Vec<MessageId>>(), vec![MessageId(40)]);
+      assert_eq!(packets_awaiting_ack.contains_key(&(other_addr, 20)), true);
+    }
+
+    #[test]
+    fn rtt_sample_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut packets_awaiting_ack = HashMap::new();
+      let packet = |seq_num| SequencedAckedPacket { addr: addr.clone(), seq_num: seq_num, ack_num: 0, ack_field: 0, bytes: vec![1] };
+      packets_awaiting_ack.insert((addr.clone(), 4), (packet(4), now - Duration::milliseconds(90), 1, vec![MessageId(4)]));
+      packets_awaiting_ack.insert((addr.clone(), 5), (packet(5), now - Duration::milliseconds(30), 1, vec![MessageId(5)]));
+
+      let ack_packet = SequencedAckedPacket { addr: addr.clone(), seq_num: 1, ack_num: 3, ack_field: 0, bytes: vec![1] };
+      assert_eq!(rtt_sample(now, &ack_packet, &packets_awaiting_ack), None);
+
+      let ack_packet = SequencedAckedPacket { addr: addr.clone(), seq_num: 1, ack_num: 5, ack_field: 0b1, bytes: vec![1] };
+      assert_eq!(rtt_sample(now, &ack_packet, &packets_awaiting_ack), Some(Duration::milliseconds(30)));
+    }
+
+    #[test]
+    fn apply_acks_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut packets_awaiting_ack = HashMap::new();
+      let mut connections = HashMap::new();
+      let (event_tx, event_rx) = channel();
+      let packet = |seq_num| SequencedAckedPacket { addr: addr.clone(), seq_num: seq_num, ack_num: 0, ack_field: 0, bytes: vec![1] };
+      connections.insert(addr, Connection::connected(now));
+      (1..6).for_each(|seq_num| {packets_awaiting_ack.insert((addr.clone(), seq_num), (packet(seq_num), now - Duration::milliseconds(40), 1, vec![MessageId(seq_num as usize)]));});
+
+      // 5, 4 and 2 acked, so 1 is presumed lost while 3 may still turn up
+      let ack_packet = SequencedAckedPacket { addr: addr.clone(), seq_num: 0, ack_num: 5, ack_field: 0b101, bytes: vec![PacketKind::Ack.to_byte()] };
+      let nacked = apply_acks(now, &ack_packet, &mut packets_awaiting_ack, &mut connections, &event_tx);
+      assert_eq!(nacked.iter().flat_map(|packet| packet.ids.clone().into_iter()).collect::<Vec<MessageId>>(), vec![MessageId(1)]);
+      assert_eq!(packets_awaiting_ack.keys().cloned().collect::<Vec<(SocketAddr, u16)>>(), vec![(addr, 3)]);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::Acked(MessageId(5)), NetworkEvent::Acked(MessageId(4)), NetworkEvent::Acked(MessageId(2))]);
+      assert_eq!(connections.get(&addr).unwrap().rtt.resend_timeout(0) < Duration::seconds(1), true);
+    }
+
     #[test]
     fn increment_seq_number_test() {
       let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
@@ -266,8 +1538,888 @@ mod state {
       
     }
 
+    #[test]
+    fn has_seen_packet_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let mut ack_map = HashMap::new();
+      let packet = SequencedAckedPacket { addr: addr.clone(), seq_num: 5, ack_num: 0, ack_field: 0, bytes: vec![1] };
+
+      assert_eq!(has_seen_packet(&packet, &ack_map), false);
+      add_packet_to_ack_map(addr.clone(), 5, &mut ack_map);
+      assert_eq!(has_seen_packet(&packet, &ack_map), true);
+      add_packet_to_ack_map(addr.clone(), 50, &mut ack_map);
+      assert_eq!(has_seen_packet(&packet, &ack_map), true);
+    }
+
     #[test]
     fn add_packet_to_ack_map_test() {
     }
+
+    #[test]
+    fn handle_control_packet_connect_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut connections = HashMap::new();
+      let (event_tx, event_rx) = channel();
+
+      let reply = handle_control_packet(PacketKind::Connect, addr, Some(7), now, MAX_CONNECTIONS, &mut connections, &event_tx);
+      let session = connections.get(&addr).unwrap().session;
+      assert_eq!(reply, Some(handshake_packet(addr, PacketKind::Accept, session)));
+      assert_eq!(connections.get(&addr).unwrap().is_connected(), true);
+      assert_eq!(connections.get(&addr).unwrap().peer_session, Some(7));
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::Connected(addr)]);
+
+      // A repeated Connect is re-accepted without a second event
+      let reply = handle_control_packet(PacketKind::Connect, addr, Some(7), now, MAX_CONNECTIONS, &mut connections, &event_tx);
+      assert_eq!(reply, Some(handshake_packet(addr, PacketKind::Accept, session)));
+      assert_eq!(try_recv_all(&event_rx).len(), 0);
+
+      let reply = handle_control_packet(PacketKind::Disconnect, addr, None, now, MAX_CONNECTIONS, &mut connections, &event_tx);
+      assert_eq!(reply, None);
+      assert_eq!(connections.contains_key(&addr), false);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::Disconnected(addr)]);
+    }
+
+    #[test]
+    fn connects_past_max_connections_are_ignored() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let new_addr =  SocketAddr::from_str("127.0.0.1:54235").unwrap();
+      let now = SteadyTime::now();
+      let mut connections = HashMap::new();
+      let (event_tx, event_rx) = channel();
+
+      handle_control_packet(PacketKind::Connect, addr, Some(7), now, 1, &mut connections, &event_tx);
+      assert_eq!(handle_control_packet(PacketKind::Connect, new_addr, Some(8), now, 1, &mut connections, &event_tx), None);
+      assert_eq!(connections.contains_key(&new_addr), false);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::Connected(addr)]);
+
+      // Peers already connected are still re-accepted
+      let session = connections.get(&addr).unwrap().session;
+      let reply = handle_control_packet(PacketKind::Connect, addr, Some(7), now, 1, &mut connections, &event_tx);
+      assert_eq!(reply, Some(handshake_packet(addr, PacketKind::Accept, session)));
+    }
+
+    #[test]
+    fn handle_control_packet_accept_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut connections = HashMap::new();
+      let (event_tx, event_rx) = channel();
+
+      // Unsolicited accepts are ignored
+      handle_control_packet(PacketKind::Accept, addr, Some(7), now, MAX_CONNECTIONS, &mut connections, &event_tx);
+      assert_eq!(connections.contains_key(&addr), false);
+      assert_eq!(try_recv_all(&event_rx).len(), 0);
+
+      let connecting = Connection::connecting(now);
+      let session = connecting.session;
+      connections.insert(addr, connecting);
+      connections.get_mut(&addr).unwrap().stats.packets_sent = 2;
+      handle_control_packet(PacketKind::Accept, addr, Some(7), now, MAX_CONNECTIONS, &mut connections, &event_tx);
+      assert_eq!(connections.get(&addr).unwrap().is_connected(), true);
+      assert_eq!(connections.get(&addr).unwrap().stats.packets_sent, 2);
+      assert_eq!(connections.get(&addr).unwrap().session, session);
+      assert_eq!(connections.get(&addr).unwrap().peer_session, Some(7));
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::Connected(addr)]);
+    }
+
+    #[test]
+    fn end_old_session_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut connections = HashMap::new();
+      let mut seq_num_map = HashMap::new();
+      let mut ack_map = HashMap::new();
+      let mut packets_awaiting_ack = HashMap::new();
+      let (event_tx, event_rx) = channel();
+
+      assert_eq!(read_session(&handshake_packet(addr, PacketKind::Connect, 7).bytes), Some(7));
+      assert_eq!(read_session(&[PacketKind::Connect.to_byte()]), None);
+
+      handle_control_packet(PacketKind::Connect, addr, Some(7), now, MAX_CONNECTIONS, &mut connections, &event_tx);
+      increment_seq_number(&mut seq_num_map, addr);
+      add_packet_to_ack_map(addr, 40, &mut ack_map);
+      connections.get_mut(&addr).unwrap().channels[0].next_order_num(DeliveryMode::ReliableOrdered);
+      try_recv_all(&event_rx);
+
+      // The same session connecting again is just a repeated Connect
+      assert_eq!(end_old_session(addr, Some(7), &mut connections, &event_tx), false);
+      assert_eq!(end_old_session(addr, None, &mut connections, &event_tx), false);
+      assert_eq!(try_recv_all(&event_rx).len(), 0);
+
+      // A restarted peer starts over at seq 1 and order 0, so nothing of the old session may stay
+      assert_eq!(end_old_session(addr, Some(8), &mut connections, &event_tx), true);
+      forget_peer(addr, &mut seq_num_map, &mut ack_map, &mut packets_awaiting_ack);
+      handle_control_packet(PacketKind::Connect, addr, Some(8), now, MAX_CONNECTIONS, &mut connections, &event_tx);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::Disconnected(addr), NetworkEvent::Connected(addr)]);
+      assert_eq!(seq_num_map.contains_key(&addr), false);
+      assert_eq!(ack_map.contains_key(&addr), false);
+      let connection = connections.get_mut(&addr).unwrap();
+      assert_eq!(connection.peer_session, Some(8));
+      assert_eq!(connection.channels[0].next_order_num(DeliveryMode::ReliableOrdered), 0);
+    }
+
+    #[test]
+    fn accept_data_packet_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut connections = HashMap::new();
+      let (event_tx, event_rx) = channel();
+
+      assert_eq!(accept_data_packet(addr, now, &mut connections, &event_tx), false);
+      assert_eq!(connections.contains_key(&addr), false);
+
+      connections.insert(addr, Connection::connecting(now));
+      assert_eq!(accept_data_packet(addr, now, &mut connections, &event_tx), true);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::Connected(addr)]);
+    }
+
+    #[test]
+    fn retry_connections_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut connections = HashMap::new();
+      let (event_tx, event_rx) = channel();
+
+      connections.insert(addr, Connection::connecting(now));
+      assert_eq!(retry_connections(now, &mut connections, &event_tx).len(), 0);
+
+      let later = now + Duration::milliseconds(CONNECT_RESEND_TIME + 1);
+      let session = connections.get(&addr).unwrap().session;
+      assert_eq!(retry_connections(later, &mut connections, &event_tx), vec![(addr, session)]);
+      assert_eq!(connections.get(&addr).unwrap().attempts, 2);
+
+      connections.get_mut(&addr).unwrap().attempts = MAX_CONNECT_ATTEMPTS;
+      let much_later = later + Duration::milliseconds(CONNECT_RESEND_TIME + 1);
+      assert_eq!(retry_connections(much_later, &mut connections, &event_tx).len(), 0);
+      assert_eq!(connections.contains_key(&addr), false);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::ConnectionFailed(addr)]);
+    }
+
+    #[test]
+    fn expire_idle_connections_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let timeout = Duration::milliseconds(1000);
+      let mut connections = HashMap::new();
+      let (event_tx, event_rx) = channel();
+
+      connections.insert(addr, Connection::connected(now));
+      let result = expire_idle_connections(now + Duration::milliseconds(500), timeout, &mut connections, &event_tx);
+      assert_eq!(result.len(), 0);
+      assert_eq!(try_recv_all(&event_rx).len(), 0);
+
+      let result = expire_idle_connections(now + Duration::milliseconds(1500), timeout, &mut connections, &event_tx);
+      assert_eq!(result, vec![addr]);
+      assert_eq!(connections.contains_key(&addr), false);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::TimedOut(addr)]);
+    }
+
+    #[test]
+    fn collect_keepalives_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let interval = Duration::milliseconds(100);
+      let mut connections = HashMap::new();
+
+      connections.insert(addr, Connection::connected(now));
+      assert_eq!(collect_keepalives(now, interval, &mut connections).len(), 0);
+
+      let later = now + Duration::milliseconds(150);
+      assert_eq!(collect_keepalives(later, interval, &mut connections), vec![addr]);
+      // Sending the heartbeat resets the interval
+      assert_eq!(collect_keepalives(later, interval, &mut connections).len(), 0);
+    }
+
+    #[test]
+    fn collect_ack_packets_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let delay = Duration::milliseconds(20);
+      let mut connections = HashMap::new();
+      let mut ack_map = HashMap::new();
+      ack_map.insert(addr, PeerAcks { ack_num: 7, ack_field: 0b101 });
+
+      connections.insert(addr, Connection::connected(now));
+      let later = now + Duration::milliseconds(30);
+      assert_eq!(collect_ack_packets(later, delay, &mut connections, &ack_map).len(), 0);
+
+      connections.get_mut(&addr).unwrap().owe_ack(now);
+      assert_eq!(collect_ack_packets(now, delay, &mut connections, &ack_map).len(), 0);
+      assert_eq!(collect_ack_packets(later, delay, &mut connections, &ack_map), vec![
+        SequencedAckedPacket { addr: addr, seq_num: 0, ack_num: 7, ack_field: 0b101, bytes: vec![PacketKind::Ack.to_byte()] }
+      ]);
+      // The acks are paid, and count as sending for keepalives
+      assert_eq!(connections.get(&addr).unwrap().last_sent, later);
+      assert_eq!(collect_ack_packets(later, delay, &mut connections, &ack_map).len(), 0);
+    }
+
+    #[test]
+    fn frame_outgoing_packet_test() {
+      let max_size = max_packet_size(MAX_DATAGRAM_SIZE);
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut connections = HashMap::new();
+      let (event_tx, event_rx) = channel();
+      let outgoing = |delivery| OutgoingPacket { packet: Packet { addr: addr.clone(), bytes: vec![9] }, delivery: delivery, channel: 0, id: MessageId(3), priority: 7 };
+
+      assert_eq!(frame_outgoing_packet(outgoing(DeliveryMode::Unreliable), max_size, &mut connections, &event_tx).is_none(), true);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::Error(NetworkError::NotConnected(addr))]);
+
+      connections.insert(addr, Connection::connected(now));
+      let queued = frame_outgoing_packet(outgoing(DeliveryMode::Unreliable), max_size, &mut connections, &event_tx).unwrap();
+      assert_eq!(queued.is_reliable, false);
+      assert_eq!(queued.id, MessageId(3));
+      assert_eq!(queued.priority, 7);
+      assert_eq!(queued.packet.bytes, vec![PacketKind::Data.to_byte(), DeliveryMode::Unreliable.to_byte(), 0, 0, 9]);
+
+      frame_outgoing_packet(outgoing(DeliveryMode::ReliableOrdered), max_size, &mut connections, &event_tx);
+      let queued = frame_outgoing_packet(outgoing(DeliveryMode::ReliableOrdered), max_size, &mut connections, &event_tx).unwrap();
+      assert_eq!(queued.is_reliable, true);
+      assert_eq!(queued.packet.bytes, vec![PacketKind::Data.to_byte(), DeliveryMode::ReliableOrdered.to_byte(), 0, 1, 9]);
+
+      // Each channel counts its own order numbers
+      let outgoing_on_channel = OutgoingPacket { packet: Packet { addr: addr.clone(), bytes: vec![9] }, delivery: DeliveryMode::ReliableOrdered, channel: 2, id: MessageId(4), priority: 7 };
+      let queued = frame_outgoing_packet(outgoing_on_channel.clone(), max_size, &mut connections, &event_tx).unwrap();
+      assert_eq!(queued.packet.bytes, vec![PacketKind::Data.to_byte(), 0x20 | DeliveryMode::ReliableOrdered.to_byte(), 0, 0, 9]);
+
+      let invalid_channel = OutgoingPacket { channel: 16, .. outgoing_on_channel.clone() };
+      assert_eq!(frame_outgoing_packet(invalid_channel, max_size, &mut connections, &event_tx).is_none(), true);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::Error(NetworkError::InvalidChannel(addr, 16))]);
+
+      let too_large = OutgoingPacket { packet: Packet { addr: addr.clone(), bytes: vec![9; max_size] }, .. outgoing_on_channel };
+      assert_eq!(frame_outgoing_packet(too_large, max_size, &mut connections, &event_tx).is_none(), true);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::Error(NetworkError::PacketTooLarge(addr, max_size))]);
+
+      // Inputs go to their channel's stream rather than the send queue
+      assert_eq!(frame_outgoing_packet(outgoing(DeliveryMode::Input), max_size, &mut connections, &event_tx).is_none(), true);
+      assert_eq!(connections.get(&addr).unwrap().channels[0].inputs.has_new(), true);
+      assert_eq!(try_recv_all(&event_rx), vec![]);
+    }
+
+    #[test]
+    fn start_and_cancel_blob_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut connections = HashMap::new();
+      let (event_tx, event_rx) = channel();
+
+      start_blob(addr, BlobId(1), vec![1, 2, 3], MAX_DATAGRAM_SIZE, &mut connections, &event_tx);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::Error(NetworkError::NotConnected(addr))]);
+
+      connections.insert(addr, Connection::connected(now));
+      start_blob(addr, BlobId(1), vec![1, 2, 3], MAX_DATAGRAM_SIZE, &mut connections, &event_tx);
+      assert_eq!(try_recv_all(&event_rx).len(), 0);
+
+      assert_eq!(cancel_blob(addr, BlobId(1), &mut connections, &event_tx), true);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::BlobCancelled { addr: addr, id: BlobId(1) }]);
+      assert_eq!(cancel_blob(addr, BlobId(1), &mut connections, &event_tx), false);
+      assert_eq!(try_recv_all(&event_rx).len(), 0);
+    }
+
+    #[test]
+    fn blob_packets_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let config = NetworkConfig::default();
+      let mut sender_connections = HashMap::new();
+      let mut receiver_connections = HashMap::new();
+      let (event_tx, event_rx) = channel();
+      sender_connections.insert(addr, Connection::connected(now));
+
+      start_blob(addr, BlobId(1), vec![1, 2, 3], MAX_DATAGRAM_SIZE, &mut sender_connections, &event_tx);
+      let chunks = collect_blob_packets(now, &config, &mut sender_connections, &event_tx);
+      assert_eq!(chunks.iter().map(|packet| packet.kind()).collect::<Vec<_>>(), vec![Some(PacketKind::BlobChunk)]);
+      assert_eq!(chunks[0].seq_num, 0);
+
+      // Chunks from peers that are not connected are ignored
+      handle_blob_packet(&chunks[0], now, MAX_DATAGRAM_SIZE, &mut receiver_connections, &event_tx);
+      assert_eq!(try_recv_all(&event_rx).len(), 0);
+
+      receiver_connections.insert(addr, Connection::connected(now));
+      handle_blob_packet(&chunks[0], now, MAX_DATAGRAM_SIZE, &mut receiver_connections, &event_tx);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::BlobReceived { addr: addr, id: BlobId(1), bytes: vec![1, 2, 3] }]);
+
+      let acks = collect_blob_packets(now, &config, &mut receiver_connections, &event_tx);
+      assert_eq!(acks.iter().map(|packet| packet.kind()).collect::<Vec<_>>(), vec![Some(PacketKind::BlobAck)]);
+      handle_blob_packet(&acks[0], now, MAX_DATAGRAM_SIZE, &mut sender_connections, &event_tx);
+      assert_eq!(collect_blob_packets(now, &config, &mut sender_connections, &event_tx).len(), 0);
+      assert_eq!(try_recv_all(&event_rx), vec![
+        NetworkEvent::BlobSendProgress { addr: addr, id: BlobId(1), acked: 1, total: 1 },
+        NetworkEvent::BlobSent { addr: addr, id: BlobId(1) }
+      ]);
+    }
+
+    #[test]
+    fn deliver_packet_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut connections = HashMap::new();
+      let (event_tx, event_rx) = channel();
+      let packet = |bytes| SequencedAckedPacket { addr: addr.clone(), seq_num: 1, ack_num: 0, ack_field: 0, bytes: bytes };
+      connections.insert(addr, Connection::connected(now));
+
+      let ordered_byte = DeliveryMode::ReliableOrdered.to_byte();
+      let data_byte = PacketKind::Data.to_byte();
+      assert_eq!(deliver_packet(packet(vec![data_byte, ordered_byte, 0, 1, 7]), &mut connections, &event_tx).len(), 0);
+      let result = deliver_packet(packet(vec![data_byte, ordered_byte, 0, 0, 6]), &mut connections, &event_tx);
+      assert_eq!(result.into_iter().map(|packet| packet.bytes).collect::<Vec<Vec<u8>>>(), vec![vec![6], vec![7]]);
+
+      // A gap on one channel does not hold back another
+      assert_eq!(deliver_packet(packet(vec![data_byte, 0x10 | ordered_byte, 0, 1, 8]), &mut connections, &event_tx).len(), 0);
+      assert_eq!(deliver_packet(packet(vec![data_byte, 0x20 | ordered_byte, 0, 0, 9]), &mut connections, &event_tx).len(), 1);
+
+      // Every message in an aggregate is delivered
+      let aggregate = Packet::aggregate(addr, vec![
+        Packet { addr: addr.clone(), bytes: vec![data_byte, 0x30 | ordered_byte, 0, 0, 10] },
+        Packet { addr: addr.clone(), bytes: vec![data_byte, DeliveryMode::Unreliable.to_byte(), 0, 0, 11] }
+      ]);
+      let result = deliver_packet(packet(aggregate.bytes), &mut connections, &event_tx);
+      assert_eq!(result.into_iter().map(|packet| packet.bytes).collect::<Vec<Vec<u8>>>(), vec![vec![10], vec![11]]);
+
+      assert_eq!(deliver_packet(packet(vec![data_byte, 9, 0, 0]), &mut connections, &event_tx).len(), 0);
+      assert_eq!(deliver_packet(packet(vec![PacketKind::Aggregate.to_byte(), 0, 9]), &mut connections, &event_tx).len(), 0);
+      assert_eq!(try_recv_all(&event_rx), vec![
+        NetworkEvent::Error(NetworkError::MalformedPacket(addr)),
+        NetworkEvent::Error(NetworkError::MalformedPacket(addr))
+      ]);
+    }
+
+    #[test]
+    fn lost_event_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let packet = SequencedAckedPacket {
+        addr: addr.clone(),
+        seq_num: 7,
+        ack_num: 0,
+        ack_field: 0,
+        bytes: vec![PacketKind::Data.to_byte(), DeliveryMode::ReliableOrdered.to_byte(), 0, 3, 42]
+      };
+      let lost_packet = PacketWithTries { packet: packet, tries: MAX_RESEND_ATTEMPTS, ids: vec![MessageId(9)] };
+
+      let expected_event = NetworkEvent::Lost {
+        id: MessageId(9),
+        packet: Packet { addr: addr, bytes: vec![42] },
+        attempts: MAX_RESEND_ATTEMPTS
+      };
+      assert_eq!(lost_events(lost_packet), vec![expected_event]);
+
+      let message = |order_num, byte| Packet { addr: addr.clone(), bytes: vec![PacketKind::Data.to_byte(), DeliveryMode::ReliableOrdered.to_byte(), 0, order_num, byte] };
+      let aggregate = SequencedAckedPacket {
+        addr: addr.clone(),
+        seq_num: 8,
+        ack_num: 0,
+        ack_field: 0,
+        bytes: Packet::aggregate(addr, vec![message(4, 43), message(5, 44)]).bytes
+      };
+      let lost_packet = PacketWithTries { packet: aggregate, tries: MAX_RESEND_ATTEMPTS, ids: vec![MessageId(10), MessageId(11)] };
+      let events = lost_events(lost_packet);
+      assert_eq!(events.len(), 2);
+      assert_eq!(events[1], NetworkEvent::Lost { id: MessageId(11), packet: Packet { addr: addr, bytes: vec![44] }, attempts: MAX_RESEND_ATTEMPTS });
+    }
+
+    #[test]
+    fn ordered_messages_are_never_lost_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let (event_tx, _event_rx) = channel();
+      let message = |delivery: DeliveryMode, order_num, byte| Packet { addr: addr, bytes: vec![PacketKind::Data.to_byte(), delivery.to_byte(), 0, order_num, byte] };
+      let dropped = |messages, ids, tries| PacketWithTries {
+        packet: SequencedAckedPacket { addr: addr, seq_num: 8, ack_num: 0, ack_field: 0, bytes: Packet::aggregate(addr, messages).bytes },
+        tries: tries,
+        ids: ids
+      };
+
+      // Packets with resend attempts left are resent whole
+      let packet = dropped(vec![message(DeliveryMode::ReliableOrdered, 0, 42)], vec![MessageId(1)], MAX_RESEND_ATTEMPTS - 1);
+      assert_eq!(split_lost_messages(packet.clone()), (Some(packet), None));
+
+      // Out of attempts, only the unordered message is lost and the ordered one keeps being resent
+      let packet = dropped(vec![message(DeliveryMode::ReliableOrdered, 0, 42), message(DeliveryMode::ReliableUnordered, 0, 43)], vec![MessageId(1), MessageId(2)], MAX_RESEND_ATTEMPTS);
+      let (resend, lost) = split_lost_messages(packet);
+      let resend = resend.unwrap();
+      let lost = lost.unwrap();
+      assert_eq!((resend.ids, resend.tries), (vec![MessageId(1)], MAX_RESEND_ATTEMPTS));
+      assert_eq!(resend.packet.bytes, message(DeliveryMode::ReliableOrdered, 0, 42).bytes);
+      assert_eq!(lost.ids, vec![MessageId(2)]);
+      assert_eq!(lost_events(lost).len(), 1);
+
+      // So the channel keeps delivering once the resend gets through
+      let mut connections = HashMap::new();
+      connections.insert(addr, Connection::connected(now));
+      let later = SequencedAckedPacket { addr: addr, seq_num: 9, ack_num: 0, ack_field: 0, bytes: message(DeliveryMode::ReliableOrdered, 1, 44).bytes };
+      assert_eq!(deliver_packet(later, &mut connections, &event_tx), vec![]);
+      let delivered = deliver_packet(resend.packet, &mut connections, &event_tx);
+      assert_eq!(delivered.into_iter().map(|packet| packet.bytes).collect::<Vec<_>>(), vec![vec![42], vec![44]]);
+    }
+
+    #[test]
+    fn fits_windows_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut connections = HashMap::new();
+      let message = |delivery: DeliveryMode, order_num: u16| Packet { addr: addr, bytes: vec![PacketKind::Data.to_byte(), delivery.to_byte(), (order_num >> 8) as u8, order_num as u8, 1] };
+      let packet = |messages| SequencedAckedPacket { addr: addr, seq_num: 1, ack_num: 0, ack_field: 0, bytes: Packet::aggregate(addr, messages).bytes };
+      connections.insert(addr, Connection::connected(now));
+
+      assert_eq!(fits_windows(&packet(vec![message(DeliveryMode::ReliableOrdered, ORDERED_WINDOW - 1), message(DeliveryMode::Sequenced, ORDERED_WINDOW)]), &connections), true);
+      // One message too far ahead holds back the ack for the whole packet
+      assert_eq!(fits_windows(&packet(vec![message(DeliveryMode::Unreliable, 0), message(DeliveryMode::ReliableUnordered, ORDERED_WINDOW)]), &connections), false);
+    }
+
+    #[test]
+    fn aggregate_packets_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let other_addr =  SocketAddr::from_str("127.0.0.1:54235").unwrap();
+      let framed = |addr, byte| Packet { addr: addr, bytes: vec![PacketKind::Data.to_byte(), 0, 0, 0, byte] };
+      let aggregate_packets = |packets, max_payload_size: usize| batch_packets(packets, |_| max_payload_size).into_iter().map(pack_batch).collect::<Vec<_>>();
+
+      // A lone packet is left alone
+      let packed = aggregate_packets(vec![queued_packet(framed(addr, 1), true, MessageId(1))], 100);
+      assert_eq!(packed, vec![(framed(addr, 1), Some(framed(addr, 1)), vec![MessageId(1)])]);
+
+      let packed = aggregate_packets(vec![
+        queued_packet(framed(addr, 1), false, MessageId(1)),
+        queued_packet(framed(other_addr, 2), true, MessageId(2)),
+        queued_packet(framed(addr, 3), true, MessageId(3)),
+        queued_packet(framed(addr, 4), true, MessageId(4)),
+      ], 100);
+      assert_eq!(packed.len(), 2);
+      let (ref packet, ref resend_packet, ref ids) = packed[0];
+      assert_eq!(packet, &Packet::aggregate(addr, vec![framed(addr, 1), framed(addr, 3), framed(addr, 4)]));
+      // Only the reliable messages are resent
+      assert_eq!(resend_packet, &Some(Packet::aggregate(addr, vec![framed(addr, 3), framed(addr, 4)])));
+      assert_eq!(ids, &vec![MessageId(3), MessageId(4)]);
+      assert_eq!(packed[1], (framed(other_addr, 2), Some(framed(other_addr, 2)), vec![MessageId(2)]));
+
+      // Each message takes 7 bytes in an aggregate, so only two fit in 15
+      let packed = aggregate_packets(vec![
+        queued_packet(framed(addr, 1), false, MessageId(1)),
+        queued_packet(framed(addr, 2), false, MessageId(2)),
+        queued_packet(framed(addr, 3), false, MessageId(3)),
+      ], 15);
+      assert_eq!(packed, vec![
+        (Packet::aggregate(addr, vec![framed(addr, 1), framed(addr, 2)]), None, vec![]),
+        (framed(addr, 3), None, vec![])
+      ]);
+
+      // Peers with bigger datagrams get bigger batches
+      let batches = batch_packets(vec![
+        queued_packet(framed(addr, 1), false, MessageId(1)),
+        queued_packet(framed(other_addr, 2), false, MessageId(2)),
+        queued_packet(framed(addr, 3), false, MessageId(3)),
+        queued_packet(framed(other_addr, 4), false, MessageId(4)),
+      ], |batch_addr| if batch_addr == addr { 15 } else { 8 });
+      assert_eq!(batches.iter().map(|batch| batch.len()).collect::<Vec<usize>>(), vec![2, 1, 1]);
+    }
+
+    #[test]
+    fn schedule_packets_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let other_addr =  SocketAddr::from_str("127.0.0.1:54235").unwrap();
+      let unknown_addr =  SocketAddr::from_str("127.0.0.1:54236").unwrap();
+      let now = SteadyTime::now();
+      let config = NetworkConfig::default();
+      let mut connections = HashMap::new();
+      connections.insert(addr, Connection::connected(now));
+      connections.insert(other_addr, Connection::connected(now));
+
+      let packets = (0..SEND_BURST + 2).map(|index| (addr, index))
+        .chain(vec![(other_addr, 0), (unknown_addr, 0)].into_iter())
+        .collect::<Vec<(SocketAddr, u32)>>();
+      let (sent, held) = schedule_packets(now, packets, |&(addr, _)| addr, &config, &mut connections);
+      assert_eq!(sent, (0..SEND_BURST).map(|index| (addr, index)).chain(Some((other_addr, 0)).into_iter()).collect::<Vec<(SocketAddr, u32)>>());
+      assert_eq!(held, vec![(addr, SEND_BURST), (addr, SEND_BURST + 1), (unknown_addr, 0)]);
+
+      let (sent, held) = schedule_packets(now, held, |&(addr, _)| addr, &config, &mut connections);
+      assert_eq!(sent.len(), 0);
+      assert_eq!(held.len(), 3);
+    }
+
+    #[test]
+    fn update_congestion_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let config = NetworkConfig::default();
+      let mut connections = HashMap::new();
+      let (event_tx, event_rx) = channel();
+      connections.insert(addr, Connection::connected(now));
+
+      update_congestion(now, &config, &mut connections, &event_tx);
+      assert_eq!(try_recv_all(&event_rx), vec![]);
+
+      connections.get_mut(&addr).unwrap().rtt.add_sample(config.congestion_rtt_threshold * 2);
+      update_congestion(now, &config, &mut connections, &event_tx);
+      update_congestion(now, &config, &mut connections, &event_tx);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::SendRateChanged { addr: addr, packets_per_second: config.bad_send_rate }]);
+    }
+
+    #[test]
+    fn drain_queued_packets_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let framed = |byte| Packet { addr: addr, bytes: vec![PacketKind::Data.to_byte(), DeliveryMode::ReliableOrdered.to_byte(), 0, 0, byte] };
+      let resend = PacketWithTries {
+        packet: SequencedAckedPacket { addr: addr, seq_num: 4, ack_num: 0, ack_field: 0, bytes: Packet::aggregate(addr, vec![framed(1), framed(2)]).bytes },
+        tries: 1,
+        ids: vec![MessageId(1), MessageId(2)]
+      };
+      let queued = vec![queued_packet(framed(3), true, MessageId(3)), queued_packet(framed(4), false, MessageId(4))];
+
+      let result = drain_queued_packets(vec![resend], queued);
+      assert_eq!(result.into_iter().map(|packet| packet.bytes).collect::<Vec<Vec<u8>>>(), vec![vec![1], vec![2], vec![3]]);
+    }
+
+    #[test]
+    fn select_by_priority_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let other_addr =  SocketAddr::from_str("127.0.0.1:54235").unwrap();
+      let sized = |addr, size, priority, id| QueuedPacket { priority: priority, .. queued_packet(Packet { addr: addr, bytes: vec![0; size] }, false, MessageId(id)) };
+      let ids = |packets: &Vec<QueuedPacket>| packets.iter().map(|queued| queued.id).collect::<Vec<MessageId>>();
+
+      // Highest priority first, each peer with its own budget, and equal priorities in send order
+      let (selected, deferred) = select_by_priority(vec![
+        sized(addr, 60, 10, 1),
+        sized(addr, 60, 200, 2),
+        sized(other_addr, 60, 10, 3),
+        sized(addr, 60, 200, 4),
+      ], 100);
+      assert_eq!(ids(&selected), vec![MessageId(2), MessageId(3)]);
+      assert_eq!(ids(&deferred), vec![MessageId(4), MessageId(1)]);
+
+      // The low priority packet builds up until it beats fresh high priority ones
+      let mut queued = vec![sized(addr, 60, 100, 1)];
+      let mut sent = Vec::new();
+      for id in 2..6 {
+        queued.push(sized(addr, 60, 150, id));
+        let (selected, deferred) = select_by_priority(queued, 100);
+        sent.extend(ids(&selected).into_iter());
+        queued = deferred;
+      }
+      assert_eq!(sent, vec![MessageId(2), MessageId(1), MessageId(3), MessageId(4)]);
+
+      // A packet bigger than the budget still goes when it is first in line
+      let (selected, _) = select_by_priority(vec![sized(addr, 500, 1, 1)], 100);
+      assert_eq!(ids(&selected), vec![MessageId(1)]);
+    }
+
+    #[test]
+    fn report_deferred_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let (event_tx, event_rx) = channel();
+      let mut queued = vec![queued_packet(Packet { addr: addr, bytes: vec![1] }, false, MessageId(1))];
+
+      report_deferred(&mut queued, &event_tx);
+      report_deferred(&mut queued, &event_tx);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::Deferred(MessageId(1))]);
+    }
+
+    #[test]
+    fn limit_in_flight_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut packets_awaiting_ack = HashMap::new();
+      let packet = SequencedAckedPacket { addr: addr.clone(), seq_num: 1, ack_num: 0, ack_field: 0, bytes: vec![1] };
+      packets_awaiting_ack.insert((addr.clone(), 1), (packet, now, 1, vec![MessageId(1)]));
+      let batch = |id, is_reliable| vec![queued_packet(Packet { addr: addr, bytes: vec![1] }, is_reliable, MessageId(id))];
+
+      let (allowed, held) = limit_in_flight(vec![batch(2, true), batch(3, false), batch(4, true)], 2, &packets_awaiting_ack);
+      // Unreliable batches never wait on acks
+      assert_eq!(allowed, vec![batch(2, true), batch(3, false)]);
+      assert_eq!(held, vec![batch(4, true)]);
+    }
+
+    #[test]
+    fn drop_oldest_unreliable_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let other_addr =  SocketAddr::from_str("127.0.0.1:54235").unwrap();
+      let (event_tx, event_rx) = channel();
+      let queued = |addr, id, is_reliable| queued_packet(Packet { addr: addr, bytes: vec![1] }, is_reliable, MessageId(id));
+      let mut queued_packets = vec![
+        queued(addr, 4, false),
+        queued(addr, 1, true),
+        queued(addr, 2, false),
+        queued(addr, 3, false),
+        queued(other_addr, 5, false),
+      ];
+
+      drop_oldest_unreliable(&mut queued_packets, 2, &event_tx);
+      assert_eq!(queued_packets, vec![queued(addr, 4, false), queued(addr, 1, true), queued(other_addr, 5, false)]);
+      assert_eq!(try_recv_all(&event_rx), vec![NetworkEvent::Dropped(MessageId(2)), NetworkEvent::Dropped(MessageId(3))]);
+
+      // Reliable messages are never dropped, even over the limit
+      let mut queued_packets = vec![queued(addr, 1, true), queued(addr, 2, true)];
+      drop_oldest_unreliable(&mut queued_packets, 1, &event_tx);
+      assert_eq!(queued_packets.len(), 2);
+      assert_eq!(try_recv_all(&event_rx), vec![]);
+    }
+
+    #[test]
+    fn free_queue_slots_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let queue_slots = QueueSlots::new();
+      let mut queued_counts = HashMap::new();
+      let queued = |id| queued_packet(Packet { addr: addr, bytes: vec![1] }, false, MessageId(id));
+      (0..3).for_each(|_| {queue_slots.try_take(addr, 3);});
+
+      // Three arrive and one goes out
+      free_queue_slots(count_by_peer(vec![addr; 3].into_iter()), &vec![queued(1), queued(2)], &mut queued_counts, &queue_slots);
+      assert_eq!(queue_slots.try_take(addr, 3), true);
+      assert_eq!(queue_slots.try_take(addr, 3), false);
+
+      // The one just taken arrives and everything goes out
+      free_queue_slots(count_by_peer(vec![addr].into_iter()), &vec![], &mut queued_counts, &queue_slots);
+      assert_eq!(queued_counts.len(), 0);
+      assert_eq!((0..3).all(|_| queue_slots.try_take(addr, 3)), true);
+    }
+
+    #[test]
+    fn send_to_peer_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut connections = HashMap::new();
+      let (socket_send_tx, socket_send_rx) = channel();
+      let packet = SequencedAckedPacket { addr: addr.clone(), seq_num: 1, ack_num: 0, ack_field: 0, bytes: vec![1, 2, 3] };
+      connections.insert(addr, Connection::connected(now));
+
+      send_to_peer(packet.clone(), &socket_send_tx, &mut connections);
+      send_to_peer(packet.clone(), &socket_send_tx, &mut connections);
+      assert_eq!(try_recv_all(&socket_send_rx), vec![packet.clone(), packet]);
+      let stats = &connections.get(&addr).unwrap().stats;
+      assert_eq!(stats.packets_sent, 2);
+      assert_eq!(stats.bytes_sent, 2 * (MARKER_LEN + HEADER_LEN + 3) as u64);
+    }
+
+    #[test]
+    fn peer_stats_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let connecting_addr =  SocketAddr::from_str("127.0.0.1:54235").unwrap();
+      let now = SteadyTime::now();
+      let config = NetworkConfig::default();
+      let mut connections = HashMap::new();
+      let mut packets_awaiting_ack = HashMap::new();
+      let packet = |seq_num| SequencedAckedPacket { addr: addr.clone(), seq_num: seq_num, ack_num: 0, ack_field: 0, bytes: vec![1] };
+      (1..4).for_each(|seq_num| {packets_awaiting_ack.insert((addr.clone(), seq_num), (packet(seq_num), now, 1, vec![MessageId(seq_num as usize)]));});
+      connections.insert(addr, Connection::connected(now));
+      connections.insert(connecting_addr, Connection::connecting(now));
+      {
+        let connection = connections.get_mut(&addr).unwrap();
+        connection.stats.packets_sent = 5;
+        connection.stats.resends = 1;
+        connection.rtt.add_sample(Duration::milliseconds(40));
+        connection.congestion.record_outcomes(7, 1);
+      }
+
+      let stats = peer_stats(&connections, &packets_awaiting_ack, &config);
+      // Peers still connecting have nothing worth reporting yet
+      assert_eq!(stats.keys().cloned().collect::<Vec<SocketAddr>>(), vec![addr]);
+      assert_eq!(stats.get(&addr), Some(&PeerStats {
+        packets_sent: 5,
+        resends: 1,
+        smoothed_rtt: Some(Duration::milliseconds(40)),
+        loss_percent: 12.5,
+        packets_in_flight: 3,
+        send_rate: config.good_send_rate,
+        datagram_size: config.max_datagram_size,
+        .. PeerStats::default()
+      }));
+    }
+
+    #[test]
+    fn collect_mtu_probes_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let connecting_addr =  SocketAddr::from_str("127.0.0.1:54235").unwrap();
+      let now = SteadyTime::now();
+      let mut config = NetworkConfig::default();
+      let mut connections = HashMap::new();
+      connections.insert(addr, Connection::connected(now));
+      connections.insert(connecting_addr, Connection::connecting(now));
+
+      assert_eq!(collect_mtu_probes(now, &config, &mut connections), vec![]);
+
+      config.mtu_probing = true;
+      let probes = collect_mtu_probes(now, &config, &mut connections);
+      assert_eq!(probes, vec![SequencedAckedPacket { addr: addr, seq_num: 0, ack_num: 0, ack_field: 0, bytes: probe_payload(512) }]);
+      // Still waiting on the first probe
+      assert_eq!(collect_mtu_probes(now, &config, &mut connections), vec![]);
+
+      connections.get_mut(&addr).unwrap().mtu.probe_acked(512, &config);
+      assert_eq!(peer_datagram_sizes(&connections, &config).get(&addr), Some(&512));
+      assert_eq!(peer_datagram_sizes(&connections, &config).get(&connecting_addr), None);
+    }
+
+    #[test]
+    fn handle_probe_packet_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut config = NetworkConfig::default();
+      config.mtu_probing = true;
+      let mut connections = HashMap::new();
+      let probe = SequencedAckedPacket { addr: addr, seq_num: 0, ack_num: 0, ack_field: 0, bytes: probe_payload(1024) };
+      let probe_ack = |size| SequencedAckedPacket { addr: addr, seq_num: 0, ack_num: 0, ack_field: 0, bytes: probe_ack_payload(size) };
+
+      // Strangers get no answer
+      assert_eq!(handle_probe_packet(&probe, &config, &mut connections), None);
+
+      connections.insert(addr, Connection::connected(now));
+      assert_eq!(handle_probe_packet(&probe, &config, &mut connections), Some(probe_ack(1024)));
+
+      assert_eq!(handle_probe_packet(&probe_ack(512), &config, &mut connections), None);
+      assert_eq!(connections.get(&addr).unwrap().mtu.datagram_size(&config), 512);
+    }
+
+    #[test]
+    fn protect_packet_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut config = NetworkConfig::default();
+      let peer_sizes = HashMap::new();
+      let mut connections = HashMap::new();
+      let packet = |seq_num, len| SequencedAckedPacket { addr: addr, seq_num: seq_num, ack_num: 0, ack_field: 0, bytes: vec![PacketKind::Data.to_byte(); len] };
+      let reliable = |seq_num| SequencedAckedPacket {
+        addr: addr, seq_num: seq_num, ack_num: 0, ack_field: 0,
+        bytes: Packet { addr: addr, bytes: vec![1] }.add_delivery(DeliveryMode::ReliableOrdered, 0, 0).add_kind(PacketKind::Data).bytes
+      };
+      connections.insert(addr, Connection::connected(now));
+
+      assert_eq!(protect_packet(&packet(1, 10), now, &config, &peer_sizes, &mut connections), vec![]);
+
+      config.fec_group_size = 2;
+      assert_eq!(protect_packet(&packet(1, 10), now, &config, &peer_sizes, &mut connections), vec![]);
+      // Too big for its parity to fit in one datagram, so left out of the group
+      assert_eq!(protect_packet(&packet(2, config.max_datagram_size), now, &config, &peer_sizes, &mut connections), vec![]);
+      // Reliable packets are resent anyway, so they are left out unless asked for
+      assert_eq!(protect_packet(&reliable(3), now, &config, &peer_sizes, &mut connections), vec![]);
+      let parities = protect_packet(&packet(4, 20), now, &config, &peer_sizes, &mut connections);
+      assert_eq!(parities.len(), 1);
+      assert_eq!(parities[0].seq_num, 0);
+      assert_eq!(parities[0].bytes[0..5].to_vec(), vec![PacketKind::Parity.to_byte(), 0, 1, 0, 0b1001]);
+      assert_eq!(parities[0].bytes.len(), FEC_HEADER_LEN + 20);
+      let stats = connections.get(&addr).unwrap().stats.clone();
+      assert_eq!((stats.parities_sent, stats.parity_bytes_sent), (1, wire_size(&parities[0]) as u64));
+
+      config.fec_delivery_modes.push(DeliveryMode::ReliableOrdered);
+      protect_packet(&reliable(5), now, &config, &peer_sizes, &mut connections);
+      assert_eq!(protect_packet(&reliable(6), now, &config, &peer_sizes, &mut connections).len(), 1);
+
+      // Parities use up the send rate, so the packets after them wait
+      let connection = connections.get_mut(&addr).unwrap();
+      assert_eq!((0..SEND_BURST - 2).all(|_| connection.congestion.try_send(now, &config)), true);
+      assert_eq!(connection.congestion.try_send(now, &config), false);
+    }
+
+    #[test]
+    fn recover_packet_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut config = NetworkConfig::default();
+      config.fec_group_size = 2;
+      let peer_sizes = HashMap::new();
+      let mut connections = HashMap::new();
+      let first = SequencedAckedPacket { addr: addr, seq_num: 1, ack_num: 5, ack_field: 0b11, bytes: vec![PacketKind::Data.to_byte(), 0, 0, 0, 7] };
+      let second = SequencedAckedPacket { addr: addr, seq_num: 2, ack_num: 6, ack_field: 0b111, bytes: vec![PacketKind::Data.to_byte(), 0, 0, 1, 8, 9] };
+      connections.insert(addr, Connection::connected(now));
+      protect_packet(&first, now, &config, &peer_sizes, &mut connections);
+      let parity = protect_packet(&second, now, &config, &peer_sizes, &mut connections).remove(0);
+
+      connections.get_mut(&addr).unwrap().fec_decoder.record(&first);
+      assert_eq!(recover_packet(&parity, &mut connections), Some(second));
+      assert_eq!(recover_packet(&parity, &mut connections), None);
+      assert_eq!(connections.get(&addr).unwrap().stats.recovered, 1);
+    }
+
+    #[test]
+    fn collect_input_packets_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let config = NetworkConfig::default();
+      let mut connections = HashMap::new();
+      let mut seq_num_map = HashMap::new();
+      let mut ack_map = HashMap::new();
+      let mut packets_awaiting_ack = HashMap::new();
+      let (event_tx, _) = channel();
+      ack_map.insert(addr, PeerAcks { ack_num: 7, ack_field: 0b1 });
+      connections.insert(addr, Connection::connected(now));
+      let input_bytes = |first_num, inputs: Vec<u8>| {
+        vec![PacketKind::Data.to_byte(), 0x10 | DeliveryMode::Input.to_byte(), 0, first_num].into_iter()
+          .chain(inputs.into_iter().flat_map(|input| vec![0, 1, input].into_iter()))
+          .collect::<Vec<u8>>()
+      };
+
+      assert_eq!(collect_input_packets(now, &config, &HashMap::new(), &mut connections, &mut seq_num_map, &ack_map), vec![]);
+
+      connections.get_mut(&addr).unwrap().channels[1].inputs.push(vec![5]);
+      assert_eq!(collect_input_packets(now, &config, &HashMap::new(), &mut connections, &mut seq_num_map, &ack_map), vec![
+        SequencedAckedPacket { addr: addr, seq_num: 1, ack_num: 7, ack_field: 0b1, bytes: input_bytes(0, vec![5]) }
+      ]);
+      // Nothing new, so nothing to send
+      assert_eq!(collect_input_packets(now, &config, &HashMap::new(), &mut connections, &mut seq_num_map, &ack_map), vec![]);
+
+      connections.get_mut(&addr).unwrap().channels[1].inputs.push(vec![6]);
+      assert_eq!(collect_input_packets(now, &config, &HashMap::new(), &mut connections, &mut seq_num_map, &ack_map)[0].bytes, input_bytes(0, vec![5, 6]));
+
+      // Once the peer acks the first input packet, its input is no longer repeated
+      let ack_packet = SequencedAckedPacket { addr: addr, seq_num: 0, ack_num: 1, ack_field: 0, bytes: vec![PacketKind::Ack.to_byte()] };
+      apply_acks(now, &ack_packet, &mut packets_awaiting_ack, &mut connections, &event_tx);
+      connections.get_mut(&addr).unwrap().channels[1].inputs.push(vec![7]);
+      assert_eq!(collect_input_packets(now, &config, &HashMap::new(), &mut connections, &mut seq_num_map, &ack_map)[0].bytes, input_bytes(1, vec![6, 7]));
+
+      // Large inputs are trimmed to what fits in one datagram to the peer
+      let later = now + Duration::seconds(1);
+      (0..config.input_redundancy).for_each(|_| connections.get_mut(&addr).unwrap().channels[1].inputs.push(vec![1; 100]));
+      let packet = collect_input_packets(later, &config, &HashMap::new(), &mut connections, &mut seq_num_map, &ack_map).remove(0);
+      assert_eq!(wire_size(&packet) <= config.max_datagram_size, true);
+      let mut peer_sizes = HashMap::new();
+      peer_sizes.insert(addr, 1024);
+      connections.get_mut(&addr).unwrap().channels[1].inputs.push(vec![1; 100]);
+      let packet = collect_input_packets(later, &config, &peer_sizes, &mut connections, &mut seq_num_map, &ack_map).remove(0);
+      // The two that fit before, and the new one
+      assert_eq!(packet.bytes.len(), DATA_HEADER_LEN + 3 * 102);
+    }
+
+    #[test]
+    fn drain_unacked_packets_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let now = SteadyTime::now();
+      let mut packets_awaiting_ack = HashMap::new();
+
+      vec![(2, 20), (1, 10), (3, 30)].into_iter().for_each(|(seq_num, payload)| {
+        let packet = SequencedAckedPacket {
+          addr: addr.clone(),
+          seq_num: seq_num,
+          ack_num: 0,
+          ack_field: 0,
+          bytes: vec![PacketKind::Data.to_byte(), DeliveryMode::ReliableOrdered.to_byte(), 0, 0, payload]
+        };
+        let sent_at = now + Duration::milliseconds(seq_num as i64);
+        packets_awaiting_ack.insert((addr.clone(), seq_num), (packet, sent_at, 1, vec![MessageId(seq_num as usize)]));
+      });
+
+      let result = drain_unacked_packets(&mut packets_awaiting_ack);
+      assert_eq!(packets_awaiting_ack.keys().count(), 0);
+      assert_eq!(result.iter().map(|packet| packet.bytes.clone()).collect::<Vec<Vec<u8>>>(), vec![vec![10], vec![20], vec![30]]);
+      assert_eq!(result[0].addr, addr);
+    }
+
+    #[test]
+    fn forget_peer_test() {
+      let addr =  SocketAddr::from_str("127.0.0.1:54234").unwrap();
+      let other_addr =  SocketAddr::from_str("127.0.0.1:54235").unwrap();
+      let mut seq_num_map = HashMap::new();
+      let mut ack_map = HashMap::new();
+      let mut packets_awaiting_ack = HashMap::new();
+
+      increment_seq_number(&mut seq_num_map, addr.clone());
+      add_packet_to_ack_map(addr.clone(), 1, &mut ack_map);
+      vec![addr.clone(), other_addr.clone()].into_iter().for_each(|packet_addr| {
+        let packet = SequencedAckedPacket { addr: packet_addr, seq_num: 1, ack_num: 0, ack_field: 0, bytes: vec![1] };
+        add_packet_to_waiting(&packet, 0, vec![MessageId(0)], &mut packets_awaiting_ack);
+      });
+
+      forget_peer(addr, &mut seq_num_map, &mut ack_map, &mut packets_awaiting_ack);
+      assert_eq!(seq_num_map.contains_key(&addr), false);
+      assert_eq!(ack_map.contains_key(&addr), false);
+      assert_eq!(packets_awaiting_ack.keys().count(), 1);
+      assert_eq!(packets_awaiting_ack.contains_key(&(other_addr, 1)), true);
+    }
   }
 }
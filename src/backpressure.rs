@@ -0,0 +1,167 @@
+pub use self::backpressure::{
+  QueueSlots,
+  CloseOnDrop,
+};
+
+mod backpressure {
+  use std::collections::HashMap;
+  use std::net::SocketAddr;
+  use std::sync::{Arc, Mutex, Condvar};
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::time::Duration;
+  use constants::QUEUE_WAIT_TIME;
+
+  // Messages each peer has waiting between Network::send and the socket, shared by the
+  // application, which takes a slot per message, and the director, which frees them again.
+  // Closed once the director stops, so nothing waits on it forever.
+  #[derive(Clone, Debug)]
+  pub struct QueueSlots {
+    slots: Arc<(Mutex<HashMap<SocketAddr, usize>>, Condvar)>,
+    closed: Arc<AtomicBool>
+  }
+
+  impl QueueSlots {
+    pub fn new() -> QueueSlots {
+      QueueSlots { slots: Arc::new((Mutex::new(HashMap::new()), Condvar::new())), closed: Arc::new(AtomicBool::new(false)) }
+    }
+
+    pub fn is_closed(&self) -> bool {
+      self.closed.load(Ordering::SeqCst)
+    }
+
+    pub fn close(&self) {
+      let _slots = self.slots.0.lock();
+      self.closed.store(true, Ordering::SeqCst);
+      self.slots.1.notify_all();
+    }
+
+    // Takes a slot if the peer has fewer than limit messages waiting
+    pub fn try_take(&self, addr: SocketAddr, limit: usize) -> bool {
+      let mut slots = self.slots.0.lock().unwrap();
+      let taken = slots.entry(addr).or_insert(0);
+      if *taken < limit {
+        *taken += 1;
+        true
+      } else {
+        false
+      }
+    }
+
+    // Waits until the peer has fewer than limit messages waiting, then takes a slot.
+    // Returns false without one if the slots are closed first.
+    pub fn take(&self, addr: SocketAddr, limit: usize) -> bool {
+      let mut slots = self.slots.0.lock().unwrap();
+      while slots.get(&addr).cloned().unwrap_or(0) >= limit {
+        if self.is_closed() {
+          return false;
+        }
+        slots = self.slots.1.wait_timeout(slots, Duration::from_millis(QUEUE_WAIT_TIME)).unwrap().0;
+      }
+      *slots.entry(addr).or_insert(0) += 1;
+      true
+    }
+
+    // Takes a slot over the limit, for a message the director will make room for
+    pub fn force_take(&self, addr: SocketAddr) {
+      let mut slots = self.slots.0.lock().unwrap();
+      *slots.entry(addr).or_insert(0) += 1;
+    }
+
+    pub fn free(&self, addr: SocketAddr, count: usize) {
+      let mut slots = self.slots.0.lock().unwrap();
+      let is_empty = {
+        let taken = slots.entry(addr).or_insert(0);
+        *taken = taken.saturating_sub(count);
+        *taken == 0
+      };
+      if is_empty {
+        slots.remove(&addr);
+      }
+      self.slots.1.notify_all();
+    }
+
+    pub fn free_all(&self) {
+      self.slots.0.lock().unwrap().clear();
+      self.slots.1.notify_all();
+    }
+  }
+
+  // Held by the director thread, so the slots close when it ends, panics included
+  pub struct CloseOnDrop(pub QueueSlots);
+
+  impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+      self.0.close();
+    }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration;
+    use super::{QueueSlots, CloseOnDrop};
+
+    fn taken(slots: &QueueSlots, addr: SocketAddr) -> usize {
+      slots.slots.0.lock().unwrap().get(&addr).cloned().unwrap_or(0)
+    }
+
+    #[test]
+    fn try_take_stops_at_limit() {
+      let addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+      let other_addr = SocketAddr::from_str("127.0.0.1:1001").unwrap();
+      let slots = QueueSlots::new();
+
+      assert_eq!(slots.try_take(addr, 2), true);
+      assert_eq!(slots.try_take(addr, 2), true);
+      assert_eq!(slots.try_take(addr, 2), false);
+      assert_eq!(slots.try_take(other_addr, 2), true);
+
+      slots.free(addr, 1);
+      assert_eq!(taken(&slots, addr), 1);
+      assert_eq!(slots.try_take(addr, 2), true);
+
+      slots.force_take(addr);
+      assert_eq!(taken(&slots, addr), 3);
+      slots.free_all();
+      assert_eq!(taken(&slots, addr), 0);
+    }
+
+    #[test]
+    fn take_waits_for_a_free_slot() {
+      let addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+      let slots = QueueSlots::new();
+      slots.force_take(addr);
+
+      let director_slots = slots.clone();
+      let director = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        director_slots.free(addr, 1);
+      });
+
+      assert_eq!(slots.take(addr, 1), true);
+      assert_eq!(taken(&slots, addr), 1);
+      let _ = director.join();
+    }
+
+    #[test]
+    fn take_gives_up_once_closed() {
+      let addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+      let slots = QueueSlots::new();
+      slots.force_take(addr);
+
+      // The director panicking still closes the slots on its way out
+      let director_slots = slots.clone();
+      let director = thread::spawn(move || {
+        let _close_on_drop = CloseOnDrop(director_slots);
+        thread::sleep(Duration::from_millis(20));
+        panic!("director died");
+      });
+
+      assert_eq!(slots.take(addr, 1), false);
+      assert_eq!(slots.is_closed(), true);
+      assert_eq!(director.join().is_err(), true);
+    }
+  }
+}
@@ -0,0 +1,564 @@
+pub use self::blob::{
+  BlobTransfers,
+  cancel_payload,
+};
+
+mod blob {
+  use std::cmp;
+  use std::collections::HashMap;
+  use std::net::SocketAddr;
+  use byteorder::{ByteOrder, BigEndian};
+  use time::{Duration, SteadyTime};
+  use packet_types::{
+    PacketKind,
+    BlobId
+  };
+  use types::NetworkEvent;
+  use errors::NetworkError;
+  use constants::{
+    MARKER_LEN,
+    HEADER_LEN,
+    BLOB_WINDOW,
+    MAX_BLOB_SIZE,
+    MAX_INCOMING_BLOBS,
+    FINISHED_BLOB_TIME,
+    BLOB_IDLE_TIMEOUT,
+  };
+
+  // Kind, blob id, chunk index and total length ahead of each chunk's data
+  const CHUNK_HEADER_LEN: usize = 11;
+  // Kind, blob id and index count ahead of the acked chunk indices
+  const ACK_HEADER_LEN: usize = 4;
+  // Index counts have to fit in a byte
+  const MAX_ACKS_PER_PACKET: usize = 255;
+
+  #[derive(Debug)]
+  struct OutgoingBlob {
+    bytes: Vec<u8>,
+    acked: Vec<bool>,
+    acked_count: u32,
+    first_unacked: u32,
+    sent_at: Vec<Option<SteadyTime>>,
+    reported: u32
+  }
+
+  // Chunks are kept as they arrive, so a peer announcing a huge blob only costs what it actually sends
+  #[derive(Debug)]
+  struct IncomingBlob {
+    total_len: u32,
+    chunks: HashMap<u32, Vec<u8>>,
+    reported: u32,
+    // A sender that stops, or whose cancel is lost, would otherwise hold one of MAX_INCOMING_BLOBS forever
+    last_chunk: SteadyTime
+  }
+
+  // Both directions of every blob transfer with one peer. Chunks and acks are sized to fill
+  // max_datagram_size, so both peers need the same one.
+  #[derive(Debug)]
+  pub struct BlobTransfers {
+    outgoing: HashMap<u16, OutgoingBlob>,
+    incoming: HashMap<u16, IncomingBlob>,
+    // Blobs already handed to the application, whose chunks are only acked again until FINISHED_BLOB_TIME passes
+    finished: HashMap<u16, SteadyTime>,
+    pending_acks: HashMap<u16, Vec<u32>>
+  }
+
+  impl BlobTransfers {
+    pub fn new() -> BlobTransfers {
+      BlobTransfers {
+        outgoing: HashMap::new(),
+        incoming: HashMap::new(),
+        finished: HashMap::new(),
+        pending_acks: HashMap::new()
+      }
+    }
+
+    pub fn start_send(&mut self, id: BlobId, bytes: Vec<u8>, max_datagram_size: usize) {
+      let count = chunk_count(bytes.len() as u32, chunk_size(max_datagram_size)) as usize;
+      self.outgoing.insert(id.0, OutgoingBlob {
+        bytes,
+        acked: vec![false; count],
+        acked_count: 0,
+        first_unacked: 0,
+        sent_at: vec![None; count],
+        reported: 0
+      });
+    }
+
+    // Returns whether there was anything to cancel
+    pub fn cancel_send(&mut self, id: BlobId) -> bool {
+      self.outgoing.remove(&id.0).is_some()
+    }
+
+    // Handles a BlobChunk, BlobAck or BlobCancel payload, kind byte included
+    pub fn receive(&mut self, addr: SocketAddr, now: SteadyTime, bytes: &[u8], max_datagram_size: usize) -> Result<Vec<NetworkEvent>, NetworkError> {
+      match bytes.first().and_then(|byte| PacketKind::from_byte(*byte)) {
+        Some(PacketKind::BlobChunk) if bytes.len() >= CHUNK_HEADER_LEN => self.receive_chunk(addr, now, bytes, chunk_size(max_datagram_size)),
+        Some(PacketKind::BlobAck) if bytes.len() >= ACK_HEADER_LEN => self.receive_ack(addr, bytes),
+        Some(PacketKind::BlobCancel) if bytes.len() >= 3 => {
+          let id = BigEndian::read_u16(&bytes[1..3]);
+          self.pending_acks.remove(&id);
+          Ok(self.incoming.remove(&id)
+            .map(|_| vec![NetworkEvent::BlobCancelled { addr, id: BlobId(id) }])
+            .unwrap_or(Vec::new()))
+        },
+        _ => Err(NetworkError::MalformedPacket(addr))
+      }
+    }
+
+    fn receive_chunk(&mut self, addr: SocketAddr, now: SteadyTime, bytes: &[u8], chunk_size: usize) -> Result<Vec<NetworkEvent>, NetworkError> {
+      let id = BigEndian::read_u16(&bytes[1..3]);
+      let index = BigEndian::read_u32(&bytes[3..7]);
+      let total_len = BigEndian::read_u32(&bytes[7..11]);
+      if total_len > MAX_BLOB_SIZE || index >= chunk_count(total_len, chunk_size) {
+        return Err(NetworkError::MalformedPacket(addr));
+      }
+      let data_len = chunk_len(total_len, index, chunk_size);
+      if bytes.len() != CHUNK_HEADER_LEN + data_len {
+        return Err(NetworkError::MalformedPacket(addr));
+      }
+
+      // Chunks of new blobs are left unacked while the peer already has its fill in flight
+      let is_finished = self.finished.contains_key(&id);
+      if !is_finished && !self.incoming.contains_key(&id) && self.incoming.len() >= MAX_INCOMING_BLOBS {
+        return Ok(Vec::new());
+      }
+      self.pending_acks.entry(id).or_insert(Vec::new()).push(index);
+      if is_finished {
+        return Ok(Vec::new());
+      }
+
+      let is_complete = {
+        let blob = self.incoming.entry(id).or_insert_with(|| IncomingBlob {
+          total_len,
+          chunks: HashMap::new(),
+          reported: 0,
+          last_chunk: now
+        });
+        if blob.total_len != total_len {
+          return Err(NetworkError::MalformedPacket(addr));
+        }
+        blob.last_chunk = now;
+        blob.chunks.entry(index).or_insert_with(|| bytes[CHUNK_HEADER_LEN..CHUNK_HEADER_LEN + data_len].to_vec());
+        blob.chunks.len() as u32 == chunk_count(total_len, chunk_size)
+      };
+
+      if !is_complete {
+        return Ok(Vec::new());
+      }
+      self.finished.insert(id, now);
+      Ok(self.incoming.remove(&id)
+        .map(|mut blob| {
+          let bytes = (0..chunk_count(blob.total_len, chunk_size))
+            .flat_map(|index| blob.chunks.remove(&index).unwrap_or(Vec::new()).into_iter())
+            .collect();
+          vec![NetworkEvent::BlobReceived { addr, id: BlobId(id), bytes }]
+        })
+        .unwrap_or(Vec::new()))
+    }
+
+    fn receive_ack(&mut self, addr: SocketAddr, bytes: &[u8]) -> Result<Vec<NetworkEvent>, NetworkError> {
+      let id = BigEndian::read_u16(&bytes[1..3]);
+      let index_count = bytes[3] as usize;
+      if bytes.len() != ACK_HEADER_LEN + index_count * 4 {
+        return Err(NetworkError::MalformedPacket(addr));
+      }
+
+      // Acks for a finished or cancelled blob are stragglers
+      if let Some(blob) = self.outgoing.get_mut(&id) {
+        let indices =
+          bytes[ACK_HEADER_LEN..ACK_HEADER_LEN + index_count * 4].chunks(4)
+            .map(|index_bytes| BigEndian::read_u32(index_bytes) as usize);
+        for index in indices {
+          if index < blob.acked.len() && !blob.acked[index] {
+            blob.acked[index] = true;
+            blob.acked_count += 1;
+          }
+        }
+      }
+      Ok(Vec::new())
+    }
+
+    // Payloads that are due now: acks for chunks received since the last poll, then any chunks
+    // in each send window that were never sent or have gone unacked for resend_timeout.
+    // Also returns progress events, and the events for blobs that were fully acked.
+    // Acks go out whatever their number, but at most max_chunks chunks are sent, the rest waiting for a later poll
+    pub fn poll(&mut self, addr: SocketAddr, now: SteadyTime, resend_timeout: Duration, max_datagram_size: usize, max_chunks: usize) -> (Vec<Vec<u8>>, usize, Vec<NetworkEvent>) {
+      let mut payloads = Vec::new();
+      let mut chunks_sent = 0;
+      let mut events = Vec::new();
+      let chunk_size = chunk_size(max_datagram_size);
+
+      for (id, indices) in self.pending_acks.drain() {
+        for indices in indices.chunks(acks_per_packet(max_datagram_size)) {
+          payloads.push(ack_payload(id, indices));
+        }
+      }
+
+      let finished_time = Duration::milliseconds(FINISHED_BLOB_TIME);
+      self.finished.retain(|_, finished_at| now - *finished_at < finished_time);
+
+      let idle_timeout = Duration::milliseconds(BLOB_IDLE_TIMEOUT);
+      let idle_ids: Vec<u16> =
+        self.incoming.iter()
+          .filter(|&(_, blob)| now - blob.last_chunk >= idle_timeout)
+          .map(|(id, _)| *id)
+          .collect();
+      for id in idle_ids {
+        self.incoming.remove(&id);
+        events.push(NetworkEvent::BlobCancelled { addr, id: BlobId(id) });
+      }
+
+      for (id, blob) in self.incoming.iter_mut() {
+        let received_count = blob.chunks.len() as u32;
+        if received_count != blob.reported {
+          blob.reported = received_count;
+          events.push(NetworkEvent::BlobRecvProgress { addr, id: BlobId(*id), received: received_count, total: chunk_count(blob.total_len, chunk_size) });
+        }
+      }
+
+      let mut completed = Vec::new();
+      for (id, blob) in self.outgoing.iter_mut() {
+        if blob.acked_count != blob.reported {
+          blob.reported = blob.acked_count;
+          events.push(NetworkEvent::BlobSendProgress { addr, id: BlobId(*id), acked: blob.acked_count, total: blob.acked.len() as u32 });
+        }
+        if blob.acked_count as usize == blob.acked.len() {
+          completed.push(*id);
+          continue;
+        }
+
+        while blob.acked[blob.first_unacked as usize] {
+          blob.first_unacked += 1;
+        }
+        let window_end = cmp::min(blob.first_unacked as usize + BLOB_WINDOW, blob.acked.len());
+        for index in blob.first_unacked as usize..window_end {
+          let is_due = !blob.acked[index] && blob.sent_at[index].map(|sent_at| now - sent_at > resend_timeout).unwrap_or(true);
+          if is_due && chunks_sent < max_chunks {
+            blob.sent_at[index] = Some(now);
+            chunks_sent += 1;
+            payloads.push(chunk_payload(*id, index as u32, &blob.bytes, chunk_size));
+          }
+        }
+      }
+
+      for id in completed.into_iter() {
+        self.outgoing.remove(&id);
+        events.push(NetworkEvent::BlobSent { addr, id: BlobId(id) });
+      }
+
+      (payloads, chunks_sent, events)
+    }
+  }
+
+  // Data bytes per chunk, so a chunk and its headers fill one datagram
+  pub fn chunk_size(max_datagram_size: usize) -> usize {
+    max_datagram_size - MARKER_LEN - HEADER_LEN - CHUNK_HEADER_LEN
+  }
+
+  // Chunk indices per blob ack, so an ack fills at most one datagram
+  pub fn acks_per_packet(max_datagram_size: usize) -> usize {
+    cmp::min((max_datagram_size - MARKER_LEN - HEADER_LEN - ACK_HEADER_LEN) / 4, MAX_ACKS_PER_PACKET)
+  }
+
+  // Every blob has at least one chunk, so even an empty one reaches the peer
+  fn chunk_count(total_len: u32, chunk_size: usize) -> u32 {
+    cmp::max(1, (total_len as usize + chunk_size - 1) / chunk_size) as u32
+  }
+
+  fn chunk_len(total_len: u32, index: u32, chunk_size: usize) -> usize {
+    cmp::min(chunk_size, total_len as usize - index as usize * chunk_size)
+  }
+
+  fn chunk_payload(id: u16, index: u32, bytes: &[u8], chunk_size: usize) -> Vec<u8> {
+    let mut header = [0; CHUNK_HEADER_LEN];
+    header[0] = PacketKind::BlobChunk.to_byte();
+    BigEndian::write_u16(&mut header[1..3], id);
+    BigEndian::write_u32(&mut header[3..7], index);
+    BigEndian::write_u32(&mut header[7..11], bytes.len() as u32);
+    let start = index as usize * chunk_size;
+    let end = start + chunk_len(bytes.len() as u32, index, chunk_size);
+    header.iter().chain(bytes[start..end].iter()).cloned().collect()
+  }
+
+  fn ack_payload(id: u16, indices: &[u32]) -> Vec<u8> {
+    let mut header = [0; ACK_HEADER_LEN];
+    header[0] = PacketKind::BlobAck.to_byte();
+    BigEndian::write_u16(&mut header[1..3], id);
+    header[3] = indices.len() as u8;
+    let mut index_bytes = vec![0; indices.len() * 4];
+    for (position, index) in indices.iter().enumerate() {
+      BigEndian::write_u32(&mut index_bytes[position * 4..position * 4 + 4], *index);
+    }
+    header.iter().cloned().chain(index_bytes.into_iter()).collect()
+  }
+
+  pub fn cancel_payload(id: BlobId) -> Vec<u8> {
+    let mut bytes = vec![PacketKind::BlobCancel.to_byte(), 0, 0];
+    BigEndian::write_u16(&mut bytes[1..3], id.0);
+    bytes
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use byteorder::{ByteOrder, BigEndian};
+    use time::{Duration, SteadyTime};
+    use packet_types::{PacketKind, BlobId};
+    use types::NetworkEvent;
+    use errors::NetworkError;
+    use constants::{MARKER_LEN, HEADER_LEN, MAX_DATAGRAM_SIZE, MIN_DATAGRAM_SIZE, UDP_DATAGRAM_LIMIT, BLOB_WINDOW, MAX_BLOB_SIZE, MAX_INCOMING_BLOBS, FINISHED_BLOB_TIME, BLOB_IDLE_TIMEOUT};
+    use super::{BlobTransfers, cancel_payload, chunk_size, acks_per_packet};
+
+    fn addr() -> SocketAddr {
+      SocketAddr::from_str("127.0.0.1:1000").unwrap()
+    }
+
+    fn kinds(payloads: &Vec<Vec<u8>>) -> Vec<Option<PacketKind>> {
+      payloads.iter().map(|payload| PacketKind::from_byte(payload[0])).collect()
+    }
+
+    // Passes every payload from one side to the other, returning the receiver's events
+    fn deliver(payloads: Vec<Vec<u8>>, to: &mut BlobTransfers) -> Vec<NetworkEvent> {
+      payloads.into_iter().flat_map(|payload| to.receive(addr(), SteadyTime::now(), &payload, MAX_DATAGRAM_SIZE).unwrap().into_iter()).collect()
+    }
+
+    #[test]
+    fn transfers_a_blob() {
+      let now = SteadyTime::now();
+      let timeout = Duration::milliseconds(100);
+      let mut sender = BlobTransfers::new();
+      let mut receiver = BlobTransfers::new();
+      let blob: Vec<u8> = (0..chunk_size(MAX_DATAGRAM_SIZE) * 2 + 7).map(|byte| byte as u8).collect();
+      sender.start_send(BlobId(4), blob.clone(), MAX_DATAGRAM_SIZE);
+
+      let (payloads, _, events) = sender.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      assert_eq!(kinds(&payloads), vec![Some(PacketKind::BlobChunk); 3]);
+      assert_eq!(events.len(), 0);
+
+      let events = deliver(payloads, &mut receiver);
+      assert_eq!(events, vec![NetworkEvent::BlobReceived { addr: addr(), id: BlobId(4), bytes: blob }]);
+
+      let (acks, _, _) = receiver.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      assert_eq!(kinds(&acks), vec![Some(PacketKind::BlobAck)]);
+      assert_eq!(deliver(acks, &mut sender).len(), 0);
+
+      let (payloads, _, events) = sender.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      assert_eq!(payloads.len(), 0);
+      assert_eq!(events, vec![
+        NetworkEvent::BlobSendProgress { addr: addr(), id: BlobId(4), acked: 3, total: 3 },
+        NetworkEvent::BlobSent { addr: addr(), id: BlobId(4) }
+      ]);
+    }
+
+    #[test]
+    fn chunks_and_acks_fill_the_datagram_size() {
+      let now = SteadyTime::now();
+      let timeout = Duration::milliseconds(100);
+      for &size in [MIN_DATAGRAM_SIZE, UDP_DATAGRAM_LIMIT].iter() {
+        let mut sender = BlobTransfers::new();
+        let mut receiver = BlobTransfers::new();
+        let blob: Vec<u8> = (0..chunk_size(size) * 3 + 1).map(|byte| byte as u8).collect();
+        sender.start_send(BlobId(1), blob.clone(), size);
+
+        let (payloads, _, _) = sender.poll(addr(), now, timeout, size, BLOB_WINDOW);
+        assert_eq!(payloads.len(), 4);
+        assert_eq!(payloads.iter().all(|payload| MARKER_LEN + HEADER_LEN + payload.len() <= size), true);
+        assert_eq!(payloads[0].len(), size - MARKER_LEN - HEADER_LEN);
+        let events = payloads.into_iter().flat_map(|payload| receiver.receive(addr(), now, &payload, size).unwrap().into_iter()).collect::<Vec<_>>();
+        assert_eq!(events, vec![NetworkEvent::BlobReceived { addr: addr(), id: BlobId(1), bytes: blob }]);
+      }
+
+      // Acks for more chunks than fit in one datagram are split up
+      let mut receiver = BlobTransfers::new();
+      let mut sender = BlobTransfers::new();
+      let count = acks_per_packet(MIN_DATAGRAM_SIZE) + 1;
+      sender.start_send(BlobId(1), vec![7; chunk_size(MIN_DATAGRAM_SIZE) * count], MIN_DATAGRAM_SIZE);
+      let (payloads, _, _) = sender.poll(addr(), now, timeout, MIN_DATAGRAM_SIZE, BLOB_WINDOW);
+      payloads.iter().for_each(|payload| {receiver.receive(addr(), now, payload, MIN_DATAGRAM_SIZE).unwrap();});
+      let (acks, _, _) = receiver.poll(addr(), now, timeout, MIN_DATAGRAM_SIZE, BLOB_WINDOW);
+      assert_eq!(acks.len(), 2);
+      assert_eq!(acks.iter().all(|ack| MARKER_LEN + HEADER_LEN + ack.len() <= MIN_DATAGRAM_SIZE), true);
+      assert_eq!(acks_per_packet(UDP_DATAGRAM_LIMIT), 255);
+    }
+
+    #[test]
+    fn only_sends_as_many_chunks_as_allowed() {
+      let now = SteadyTime::now();
+      let timeout = Duration::milliseconds(100);
+      let mut sender = BlobTransfers::new();
+      sender.start_send(BlobId(1), vec![7; chunk_size(MAX_DATAGRAM_SIZE) * 5], MAX_DATAGRAM_SIZE);
+
+      let (payloads, chunks_sent, _) = sender.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, 2);
+      assert_eq!((payloads.len(), chunks_sent), (2, 2));
+      // The rest go out with a later poll, without waiting for a resend
+      let (payloads, chunks_sent, _) = sender.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      assert_eq!((payloads.len(), chunks_sent), (3, 3));
+    }
+
+    #[test]
+    fn only_sends_a_window_and_resends_unacked_chunks() {
+      let now = SteadyTime::now();
+      let timeout = Duration::milliseconds(100);
+      let mut sender = BlobTransfers::new();
+      let mut receiver = BlobTransfers::new();
+      sender.start_send(BlobId(1), vec![7; chunk_size(MAX_DATAGRAM_SIZE) * (BLOB_WINDOW + 2)], MAX_DATAGRAM_SIZE);
+
+      let (mut payloads, _, _) = sender.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      assert_eq!(payloads.len(), BLOB_WINDOW);
+      assert_eq!(sender.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW).0.len(), 0);
+
+      // The first chunk goes missing, so the window cannot slide past it
+      payloads.remove(0);
+      deliver(payloads, &mut receiver);
+      let (acks, _, events) = receiver.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      assert_eq!(events, vec![NetworkEvent::BlobRecvProgress { addr: addr(), id: BlobId(1), received: BLOB_WINDOW as u32 - 1, total: BLOB_WINDOW as u32 + 2 }]);
+      deliver(acks, &mut sender);
+      assert_eq!(sender.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW).0.len(), 0);
+
+      let later = now + Duration::milliseconds(101);
+      let (payloads, _, _) = sender.poll(addr(), later, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      assert_eq!(payloads.len(), 1);
+      deliver(payloads, &mut receiver);
+      deliver(receiver.poll(addr(), later, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW).0, &mut sender);
+
+      let (payloads, _, _) = sender.poll(addr(), later, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      assert_eq!(payloads.len(), 2);
+      let events = deliver(payloads, &mut receiver);
+      assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn finished_blobs_are_acked_again() {
+      let now = SteadyTime::now();
+      let timeout = Duration::milliseconds(100);
+      let mut sender = BlobTransfers::new();
+      let mut receiver = BlobTransfers::new();
+      sender.start_send(BlobId(1), vec![1, 2, 3], MAX_DATAGRAM_SIZE);
+
+      let (payloads, _, _) = sender.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      assert_eq!(deliver(payloads.clone(), &mut receiver).len(), 1);
+      receiver.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+
+      assert_eq!(deliver(payloads, &mut receiver).len(), 0);
+      assert_eq!(kinds(&receiver.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW).0), vec![Some(PacketKind::BlobAck)]);
+    }
+
+    #[test]
+    fn finished_blob_ids_can_be_reused() {
+      let now = SteadyTime::now();
+      let timeout = Duration::milliseconds(100);
+      let mut receiver = BlobTransfers::new();
+      let mut sender = BlobTransfers::new();
+      sender.start_send(BlobId(1), vec![1, 2, 3], MAX_DATAGRAM_SIZE);
+      let (payloads, _, _) = sender.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+
+      assert_eq!(receiver.receive(addr(), now, &payloads[0], MAX_DATAGRAM_SIZE).unwrap().len(), 1);
+      receiver.poll(addr(), now + Duration::milliseconds(FINISHED_BLOB_TIME - 1), timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      assert_eq!(receiver.receive(addr(), now, &payloads[0], MAX_DATAGRAM_SIZE).unwrap().len(), 0);
+
+      // Once the id has been finished for long enough, the same id is a new blob
+      receiver.poll(addr(), now + Duration::milliseconds(FINISHED_BLOB_TIME), timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      assert_eq!(receiver.receive(addr(), now, &payloads[0], MAX_DATAGRAM_SIZE).unwrap(), vec![
+        NetworkEvent::BlobReceived { addr: addr(), id: BlobId(1), bytes: vec![1, 2, 3] }
+      ]);
+    }
+
+    #[test]
+    fn huge_blobs_only_hold_what_arrived() {
+      let mut receiver = BlobTransfers::new();
+      let mut chunk = vec![PacketKind::BlobChunk.to_byte(), 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+      BigEndian::write_u32(&mut chunk[7..11], MAX_BLOB_SIZE);
+      chunk.extend(vec![7; chunk_size(MAX_DATAGRAM_SIZE)].into_iter());
+
+      assert_eq!(receiver.receive(addr(), SteadyTime::now(), &chunk, MAX_DATAGRAM_SIZE).unwrap().len(), 0);
+      let blob = receiver.incoming.get(&1).unwrap();
+      assert_eq!(blob.chunks.values().map(|chunk| chunk.len()).sum::<usize>(), chunk_size(MAX_DATAGRAM_SIZE));
+    }
+
+    #[test]
+    fn cancel_stops_both_sides() {
+      let now = SteadyTime::now();
+      let timeout = Duration::milliseconds(100);
+      let mut sender = BlobTransfers::new();
+      let mut receiver = BlobTransfers::new();
+      sender.start_send(BlobId(1), vec![7; chunk_size(MAX_DATAGRAM_SIZE) * 2], MAX_DATAGRAM_SIZE);
+
+      let (mut payloads, _, _) = sender.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      payloads.truncate(1);
+      deliver(payloads, &mut receiver);
+
+      assert_eq!(sender.cancel_send(BlobId(1)), true);
+      assert_eq!(sender.cancel_send(BlobId(1)), false);
+      assert_eq!(sender.poll(addr(), now + Duration::milliseconds(101), timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW).0.len(), 0);
+
+      let events = deliver(vec![cancel_payload(BlobId(1))], &mut receiver);
+      assert_eq!(events, vec![NetworkEvent::BlobCancelled { addr: addr(), id: BlobId(1) }]);
+      assert_eq!(receiver.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW).0.len(), 0);
+    }
+
+    #[test]
+    fn idle_incoming_blobs_are_given_up_on() {
+      let now = SteadyTime::now();
+      let timeout = Duration::milliseconds(100);
+      let idle_timeout = Duration::milliseconds(BLOB_IDLE_TIMEOUT);
+      let mut sender = BlobTransfers::new();
+      let mut receiver = BlobTransfers::new();
+      for id in 0..MAX_INCOMING_BLOBS + 1 {
+        sender.start_send(BlobId(id as u16), vec![7; chunk_size(MAX_DATAGRAM_SIZE) * 3], MAX_DATAGRAM_SIZE);
+      }
+      let (payloads, _, _) = sender.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      let chunks = |index: u8| payloads.iter().filter(|payload| payload[6] == index).cloned().collect::<Vec<_>>();
+      let receive = |receiver: &mut BlobTransfers, at: SteadyTime, chunks: Vec<Vec<u8>>| {
+        chunks.iter().for_each(|chunk| {receiver.receive(addr(), at, chunk, MAX_DATAGRAM_SIZE).unwrap();});
+      };
+
+      // The senders stop, and their cancels never arrive, but every chunk resets the wait
+      receive(&mut receiver, now, chunks(0));
+      receive(&mut receiver, now + Duration::milliseconds(10), chunks(1));
+      let (_, _, events) = receiver.poll(addr(), now + idle_timeout, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      assert_eq!(events.iter().any(|event| match *event { NetworkEvent::BlobCancelled { .. } => true, _ => false }), false);
+
+      let later = now + Duration::milliseconds(10) + idle_timeout;
+      let (_, _, events) = receiver.poll(addr(), later, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      let cancelled = events.iter().filter(|event| match **event { NetworkEvent::BlobCancelled { .. } => true, _ => false }).count();
+      assert_eq!(cancelled, MAX_INCOMING_BLOBS);
+
+      // Which frees their slots for new blobs
+      receive(&mut receiver, later, chunks(0));
+      assert_eq!(receiver.poll(addr(), later, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW).0.len(), MAX_INCOMING_BLOBS);
+    }
+
+    #[test]
+    fn incoming_blobs_are_limited() {
+      let now = SteadyTime::now();
+      let timeout = Duration::milliseconds(100);
+      let mut sender = BlobTransfers::new();
+      let mut receiver = BlobTransfers::new();
+      for id in 0..MAX_INCOMING_BLOBS + 1 {
+        sender.start_send(BlobId(id as u16), vec![7; chunk_size(MAX_DATAGRAM_SIZE) * 2], MAX_DATAGRAM_SIZE);
+      }
+
+      // Only the first chunk of each blob arrives
+      let (payloads, _, _) = sender.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW);
+      let first_chunks: Vec<Vec<u8>> = payloads.into_iter().filter(|payload| payload[6] == 0).collect();
+      deliver(first_chunks, &mut receiver);
+      assert_eq!(receiver.poll(addr(), now, timeout, MAX_DATAGRAM_SIZE, BLOB_WINDOW).0.len(), MAX_INCOMING_BLOBS);
+    }
+
+    #[test]
+    fn rejects_malformed_payloads() {
+      let mut receiver = BlobTransfers::new();
+      assert_eq!(receiver.receive(addr(), SteadyTime::now(), &[PacketKind::BlobChunk.to_byte(), 0, 1], MAX_DATAGRAM_SIZE), Err(NetworkError::MalformedPacket(addr())));
+
+      // Chunk 1 of a blob that only has one chunk
+      let chunk = vec![PacketKind::BlobChunk.to_byte(), 0, 1, 0, 0, 0, 1, 0, 0, 0, 3, 1, 2, 3];
+      assert_eq!(receiver.receive(addr(), SteadyTime::now(), &chunk, MAX_DATAGRAM_SIZE), Err(NetworkError::MalformedPacket(addr())));
+
+      // An ack claiming more indices than it carries
+      let ack = vec![PacketKind::BlobAck.to_byte(), 0, 1, 2, 0, 0, 0, 0];
+      assert_eq!(receiver.receive(addr(), SteadyTime::now(), &ack, MAX_DATAGRAM_SIZE), Err(NetworkError::MalformedPacket(addr())));
+    }
+  }
+}
@@ -0,0 +1,130 @@
+pub use self::config::{
+  NetworkConfig,
+  OverflowPolicy,
+};
+
+mod config {
+  use std::cmp;
+  use time::Duration;
+  use byteorder::{ByteOrder, BigEndian};
+  use packet_types::DeliveryMode;
+  use constants::{
+    MAX_CONNECTIONS,
+    KEEPALIVE_INTERVAL,
+    PEER_TIMEOUT,
+    ACK_DELAY,
+    MAX_DATAGRAM_SIZE,
+    GOOD_SEND_RATE,
+    BAD_SEND_RATE,
+    CONGESTION_RTT_THRESHOLD,
+    PEER_TICK_BUDGET,
+    MAX_QUEUED_PER_PEER,
+    MAX_IN_FLIGHT_PER_PEER,
+    MAX_PROBE_SIZE,
+    INPUT_REDUNDANCY,
+    DEFAULT_PROTOCOL_ID,
+    DEFAULT_PROTOCOL_VERSION,
+    MARKER_LEN,
+  };
+
+  // What Network::send does when a peer already has max_queued_per_peer messages waiting
+  #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+  pub enum OverflowPolicy {
+    // Waits for the director to send something to the peer
+    Block,
+    // Refuses the message with a WouldBlock error
+    WouldBlock,
+    // Makes room by dropping the peer's oldest waiting unreliable message.
+    // Reliable messages are refused with WouldBlock if there is nothing to drop.
+    DropOldestUnreliable
+  }
+
+  #[derive(Clone, Debug)]
+  pub struct NetworkConfig {
+    // Peers, connecting ones included, past which Connects from new peers are ignored
+    pub max_connections: usize,
+    // How long a connection may go without sending before a heartbeat is sent
+    pub keepalive_interval: Duration,
+    // How long a connection may go without receiving before it is timed out
+    pub peer_timeout: Duration,
+    // How long acks owed to a peer wait for outgoing data before going out in an ack-only packet
+    pub ack_delay: Duration,
+    // Largest datagram sent or accepted, marker and headers included. Bigger packets are fragmented.
+    // Both peers need the same value, and it cannot go below MIN_DATAGRAM_SIZE.
+    pub max_datagram_size: usize,
+    // Sequenced packets per second sent to each peer, while its link looks healthy and while it looks congested
+    pub good_send_rate: u32,
+    pub bad_send_rate: u32,
+    // Smoothed rtt above which a peer's link counts as congested
+    pub congestion_rtt_threshold: Duration,
+    // Bytes of new messages sent to each peer per director tick, highest accumulated priority first.
+    // A peer's first message in a tick always goes, however big.
+    pub peer_tick_budget: usize,
+    // Messages a peer can have waiting to be sent, and what happens to more
+    pub max_queued_per_peer: usize,
+    pub overflow_policy: OverflowPolicy,
+    // Reliable packets a peer can have awaiting acks before its new reliable messages are held back.
+    // Anything more than 32 behind the peer's newest ack counts as lost, so going above that only causes resends.
+    pub max_in_flight_per_peer: usize,
+    // Probes each connected peer with bigger and bigger datagrams, up to max_probe_size, and sends it
+    // datagrams as big as the largest probe it acked. Both peers need it on, so they accept the probes.
+    pub mtu_probing: bool,
+    pub max_probe_size: usize,
+    // Sends each peer a parity packet after every fec_group_size sequenced packets (at most 16),
+    // from which it can rebuild any one of them that went missing. 0 turns it off.
+    pub fec_group_size: usize,
+    // Only packets carrying a message sent with one of these modes are protected. Reliable ones are
+    // resent anyway, so by default only Unreliable, Sequenced and Input packets are.
+    pub fec_delivery_modes: Vec<DeliveryMode>,
+    // Most unacked inputs repeated in each input packet. Older ones are given up on.
+    pub input_redundancy: usize,
+    // Start every datagram, so packets from other protocols, or other versions of this one, are dropped
+    pub protocol_id: u32,
+    pub protocol_version: u16
+  }
+
+  impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+      NetworkConfig {
+        max_connections: MAX_CONNECTIONS,
+        keepalive_interval: Duration::milliseconds(KEEPALIVE_INTERVAL),
+        peer_timeout: Duration::milliseconds(PEER_TIMEOUT),
+        ack_delay: Duration::milliseconds(ACK_DELAY),
+        max_datagram_size: MAX_DATAGRAM_SIZE,
+        good_send_rate: GOOD_SEND_RATE,
+        bad_send_rate: BAD_SEND_RATE,
+        congestion_rtt_threshold: Duration::milliseconds(CONGESTION_RTT_THRESHOLD),
+        peer_tick_budget: PEER_TICK_BUDGET,
+        max_queued_per_peer: MAX_QUEUED_PER_PEER,
+        overflow_policy: OverflowPolicy::WouldBlock,
+        max_in_flight_per_peer: MAX_IN_FLIGHT_PER_PEER,
+        mtu_probing: false,
+        max_probe_size: MAX_PROBE_SIZE,
+        fec_group_size: 0,
+        fec_delivery_modes: vec![DeliveryMode::Unreliable, DeliveryMode::Sequenced, DeliveryMode::Input],
+        input_redundancy: INPUT_REDUNDANCY,
+        protocol_id: DEFAULT_PROTOCOL_ID,
+        protocol_version: DEFAULT_PROTOCOL_VERSION
+      }
+    }
+  }
+
+  impl NetworkConfig {
+    // The protocol id and version, MARKER_LEN bytes
+    pub fn marker(&self) -> Vec<u8> {
+      let mut marker = vec![0; MARKER_LEN];
+      BigEndian::write_u32(&mut marker[0..4], self.protocol_id);
+      BigEndian::write_u16(&mut marker[4..6], self.protocol_version);
+      marker
+    }
+
+    // Largest datagram accepted from peers, probes included
+    pub fn max_receive_size(&self) -> usize {
+      if self.mtu_probing {
+        cmp::max(self.max_datagram_size, self.max_probe_size)
+      } else {
+        self.max_datagram_size
+      }
+    }
+  }
+}
@@ -0,0 +1,304 @@
+pub use self::congestion::{
+  CongestionControl,
+};
+
+mod congestion {
+  use std::cmp;
+  use time::{Duration, SteadyTime};
+  use config::NetworkConfig;
+  use constants::{
+    INITIAL_CONGESTION_PENALTY,
+    MIN_CONGESTION_PENALTY,
+    MAX_CONGESTION_PENALTY,
+    CONGESTION_RECOVERY_TIME,
+    CONGESTION_LOSS_THRESHOLD,
+    LOSS_WINDOW,
+    MIN_LOSS_SAMPLES,
+    SEND_BURST,
+  };
+
+  #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+  pub enum CongestionMode {
+    Good,
+    Bad
+  }
+
+  // Outcomes are kept in a u64, so the window cannot be any bigger
+  const _: [(); 0] = [(); (LOSS_WINDOW > 64) as usize];
+
+  // Good/bad send rate control. A peer drops to the bad rate as soon as its rtt or loss crosses
+  // the thresholds, and only goes back to the good rate once conditions have held for the penalty time.
+  // Falling back soon after recovering doubles the penalty, and staying good for a while halves it.
+  #[derive(Clone, Copy, Debug)]
+  pub struct CongestionControl {
+    mode: CongestionMode,
+    // When the current mode was entered
+    mode_since: SteadyTime,
+    // Start of the current stretch of good conditions, or of the last penalty relief while good
+    good_since: SteadyTime,
+    penalty: Duration,
+    // One bit per recent reliable packet, newest lowest, set for the ones that went unacked
+    outcomes: u64,
+    outcome_count: usize,
+    // Packets that may be sent right now, refilled at the current send rate
+    allowance: f64,
+    last_refill: SteadyTime
+  }
+
+  impl CongestionControl {
+    pub fn new(now: SteadyTime) -> CongestionControl {
+      CongestionControl {
+        mode: CongestionMode::Good,
+        mode_since: now,
+        good_since: now,
+        penalty: Duration::milliseconds(INITIAL_CONGESTION_PENALTY),
+        outcomes: 0,
+        outcome_count: 0,
+        allowance: SEND_BURST as f64,
+        last_refill: now
+      }
+    }
+
+    // Packets per second
+    pub fn send_rate(&self, config: &NetworkConfig) -> u32 {
+      match self.mode {
+        CongestionMode::Good => config.good_send_rate,
+        CongestionMode::Bad => config.bad_send_rate
+      }
+    }
+
+    // Percent of the last LOSS_WINDOW reliable packets that were lost
+    pub fn loss_percent(&self) -> f64 {
+      if self.outcome_count == 0 {
+        return 0.0;
+      }
+      let window = self.outcomes & (u64::max_value() >> (64 - LOSS_WINDOW));
+      window.count_ones() as f64 * 100.0 / self.outcome_count as f64
+    }
+
+    pub fn record_outcomes(&mut self, acked: usize, lost: usize) {
+      let outcomes = (0..acked).map(|_| false).chain((0..lost).map(|_| true));
+      for is_lost in outcomes {
+        self.outcomes = (self.outcomes << 1) | is_lost as u64;
+        self.outcome_count = cmp::min(self.outcome_count + 1, LOSS_WINDOW);
+      }
+    }
+
+    // Returns the new send rate if the mode changed
+    pub fn update(&mut self, now: SteadyTime, srtt: Option<Duration>, config: &NetworkConfig) -> Option<u32> {
+      let recovery_time = Duration::milliseconds(CONGESTION_RECOVERY_TIME);
+      let is_congested =
+        srtt.map(|srtt| srtt > config.congestion_rtt_threshold).unwrap_or(false) ||
+        (self.outcome_count >= MIN_LOSS_SAMPLES && self.loss_percent() > CONGESTION_LOSS_THRESHOLD as f64);
+
+      match (self.mode, is_congested) {
+        (CongestionMode::Good, true) => {
+          if now - self.mode_since < recovery_time {
+            self.penalty = cmp::min(self.penalty * 2, Duration::milliseconds(MAX_CONGESTION_PENALTY));
+          }
+          self.set_mode(now, CongestionMode::Bad);
+          Some(self.send_rate(config))
+        },
+        (CongestionMode::Good, false) => {
+          if now - self.good_since >= recovery_time {
+            self.penalty = cmp::max(self.penalty / 2, Duration::milliseconds(MIN_CONGESTION_PENALTY));
+            self.good_since = now;
+          }
+          None
+        },
+        (CongestionMode::Bad, true) => {
+          self.good_since = now;
+          None
+        },
+        (CongestionMode::Bad, false) => {
+          if now - self.good_since >= self.penalty {
+            self.set_mode(now, CongestionMode::Good);
+            Some(self.send_rate(config))
+          } else {
+            None
+          }
+        }
+      }
+    }
+
+    fn set_mode(&mut self, now: SteadyTime, mode: CongestionMode) {
+      self.mode = mode;
+      self.mode_since = now;
+      self.good_since = now;
+    }
+
+    // Takes one packet's worth of allowance, if there is any
+    pub fn try_send(&mut self, now: SteadyTime, config: &NetworkConfig) -> bool {
+      self.refill(now, config);
+      if self.allowance >= 1.0 {
+        self.allowance -= 1.0;
+        true
+      } else {
+        false
+      }
+    }
+
+    // Takes one packet's worth of allowance for a packet that goes out regardless, like a parity.
+    // The allowance can go into debt, which holds back the packets after it.
+    pub fn charge(&mut self, now: SteadyTime, config: &NetworkConfig) {
+      self.refill(now, config);
+      self.allowance -= 1.0;
+    }
+
+    // Whole packets' worth of allowance there is right now, for senders that decide how many to take later
+    pub fn available(&mut self, now: SteadyTime, config: &NetworkConfig) -> usize {
+      self.refill(now, config);
+      if self.allowance >= 1.0 { self.allowance as usize } else { 0 }
+    }
+
+    pub fn spend(&mut self, count: usize) {
+      self.allowance -= count as f64;
+    }
+
+    fn refill(&mut self, now: SteadyTime, config: &NetworkConfig) {
+      let elapsed_secs = (now - self.last_refill).num_microseconds().unwrap_or(0) as f64 / 1000000.0;
+      let refill = elapsed_secs * self.send_rate(config) as f64;
+      self.allowance = (self.allowance + refill).min(SEND_BURST as f64);
+      self.last_refill = now;
+    }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use time::{Duration, SteadyTime};
+    use config::NetworkConfig;
+    use constants::{
+      INITIAL_CONGESTION_PENALTY,
+      CONGESTION_RECOVERY_TIME,
+      LOSS_WINDOW,
+      MIN_LOSS_SAMPLES,
+      SEND_BURST,
+    };
+    use super::{CongestionControl, CongestionMode};
+
+    #[test]
+    fn high_rtt_switches_to_bad_until_penalty_passes() {
+      let now = SteadyTime::now();
+      let config = NetworkConfig::default();
+      let mut congestion = CongestionControl::new(now);
+      let slow = Some(config.congestion_rtt_threshold + Duration::milliseconds(1));
+      let fast = Some(Duration::milliseconds(20));
+
+      assert_eq!(congestion.update(now, fast, &config), None);
+      assert_eq!(congestion.update(now, slow, &config), Some(config.bad_send_rate));
+      assert_eq!(congestion.mode, CongestionMode::Bad);
+      // Entering bad straight after starting good doubles the penalty
+      let penalty = Duration::milliseconds(INITIAL_CONGESTION_PENALTY * 2);
+
+      let later = now + Duration::milliseconds(100);
+      assert_eq!(congestion.update(later, slow, &config), None);
+      assert_eq!(congestion.update(later + penalty - Duration::milliseconds(1), fast, &config), None);
+      assert_eq!(congestion.update(later + penalty, fast, &config), Some(config.good_send_rate));
+      assert_eq!(congestion.mode, CongestionMode::Good);
+    }
+
+    #[test]
+    fn long_good_stretches_halve_the_penalty() {
+      let now = SteadyTime::now();
+      let config = NetworkConfig::default();
+      let mut congestion = CongestionControl::new(now);
+      let recovery_time = Duration::milliseconds(CONGESTION_RECOVERY_TIME);
+
+      assert_eq!(congestion.update(now + recovery_time, None, &config), None);
+      assert_eq!(congestion.penalty, Duration::milliseconds(INITIAL_CONGESTION_PENALTY / 2));
+    }
+
+    #[test]
+    fn loss_switches_to_bad() {
+      let now = SteadyTime::now();
+      let config = NetworkConfig::default();
+      let mut congestion = CongestionControl::new(now);
+
+      congestion.record_outcomes(1000, 0);
+      assert_eq!(congestion.loss_percent(), 0.0);
+      assert_eq!(congestion.update(now, None, &config), None);
+
+      // An isolated loss is nowhere near congestion
+      congestion.record_outcomes(0, 1);
+      assert_eq!(congestion.loss_percent(), 100.0 / LOSS_WINDOW as f64);
+      assert_eq!(congestion.update(now, None, &config), None);
+
+      congestion.record_outcomes(0, 6);
+      assert_eq!(congestion.update(now, None, &config), Some(config.bad_send_rate));
+    }
+
+    #[test]
+    fn old_losses_leave_the_window() {
+      let now = SteadyTime::now();
+      let mut congestion = CongestionControl::new(now);
+
+      congestion.record_outcomes(0, LOSS_WINDOW);
+      assert_eq!(congestion.loss_percent(), 100.0);
+      congestion.record_outcomes(LOSS_WINDOW - 1, 0);
+      assert_eq!(congestion.loss_percent(), 100.0 / LOSS_WINDOW as f64);
+      congestion.record_outcomes(1, 0);
+      assert_eq!(congestion.loss_percent(), 0.0);
+    }
+
+    #[test]
+    fn loss_needs_enough_samples() {
+      let now = SteadyTime::now();
+      let config = NetworkConfig::default();
+      let mut congestion = CongestionControl::new(now);
+
+      congestion.record_outcomes(1, 1);
+      assert_eq!(congestion.loss_percent(), 50.0);
+      assert_eq!(congestion.update(now, None, &config), None);
+
+      congestion.record_outcomes(MIN_LOSS_SAMPLES - 4, 2);
+      assert_eq!(congestion.update(now, None, &config), Some(config.bad_send_rate));
+    }
+
+    #[test]
+    fn try_send_follows_send_rate() {
+      let now = SteadyTime::now();
+      let config = NetworkConfig::default();
+      let mut congestion = CongestionControl::new(now);
+
+      assert_eq!((0..SEND_BURST).all(|_| congestion.try_send(now, &config)), true);
+      assert_eq!(congestion.try_send(now, &config), false);
+
+      let one_packet = Duration::milliseconds(1000 / config.good_send_rate as i64 + 1);
+      assert_eq!(congestion.try_send(now + one_packet, &config), true);
+      assert_eq!(congestion.try_send(now + one_packet, &config), false);
+
+      // The allowance never builds past a burst
+      let much_later = now + Duration::seconds(10);
+      assert_eq!((0..SEND_BURST).all(|_| congestion.try_send(much_later, &config)), true);
+      assert_eq!(congestion.try_send(much_later, &config), false);
+    }
+
+    #[test]
+    fn available_allowance_is_shared_with_try_send() {
+      let now = SteadyTime::now();
+      let config = NetworkConfig::default();
+      let mut congestion = CongestionControl::new(now);
+
+      assert_eq!(congestion.available(now, &config), SEND_BURST as usize);
+      congestion.spend(SEND_BURST as usize - 1);
+      assert_eq!(congestion.try_send(now, &config), true);
+      assert_eq!(congestion.available(now, &config), 0);
+
+      let one_packet = Duration::milliseconds(1000 / config.good_send_rate as i64 + 1);
+      assert_eq!(congestion.available(now + one_packet, &config), 1);
+    }
+
+    #[test]
+    fn charged_packets_hold_back_later_ones() {
+      let now = SteadyTime::now();
+      let config = NetworkConfig::default();
+      let mut congestion = CongestionControl::new(now);
+      let one_packet = Duration::milliseconds(1000 / config.good_send_rate as i64 + 1);
+
+      assert_eq!((0..SEND_BURST).all(|_| congestion.try_send(now, &config)), true);
+      congestion.charge(now, &config);
+      assert_eq!(congestion.try_send(now + one_packet, &config), false);
+      assert_eq!(congestion.try_send(now + one_packet + one_packet, &config), true);
+    }
+  }
+}
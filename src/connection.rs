@@ -0,0 +1,211 @@
+pub use self::connection::{
+  Connection,
+};
+
+mod connection {
+  use time::{self, Duration, SteadyTime};
+  use constants::{
+    CONNECT_RESEND_TIME,
+    MAX_CHANNELS,
+  };
+  use ordering::DeliveryChannel;
+  use rtt::RttEstimator;
+  use blob::BlobTransfers;
+  use congestion::CongestionControl;
+  use mtu::MtuProbe;
+  use fec::{FecEncoder, FecDecoder};
+  use types::PeerStats;
+
+  #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+  pub enum ConnectionState {
+    Connecting,
+    Connected
+  }
+
+  #[derive(Debug)]
+  pub struct Connection {
+    pub state: ConnectionState,
+    // Sent in our Connect and Accept packets, and new for every connection
+    pub session: u32,
+    // The session the peer connected or accepted with, if it sent one
+    pub peer_session: Option<u32>,
+    pub last_attempt: SteadyTime,
+    pub attempts: i32,
+    pub last_sent: SteadyTime,
+    pub last_received: SteadyTime,
+    // When we first received a packet that no outgoing packet has acked yet
+    pub acks_owed_since: Option<SteadyTime>,
+    pub channels: Vec<DeliveryChannel>,
+    pub rtt: RttEstimator,
+    pub congestion: CongestionControl,
+    pub mtu: MtuProbe,
+    pub fec_encoder: FecEncoder,
+    pub fec_decoder: FecDecoder,
+    // Just the counters, the director fills in the rest when it publishes them
+    pub stats: PeerStats,
+    pub blobs: BlobTransfers
+  }
+
+  impl Connection {
+    pub fn connecting(now: SteadyTime) -> Connection {
+      Connection {
+        state: ConnectionState::Connecting,
+        session: new_session(),
+        peer_session: None,
+        last_attempt: now,
+        attempts: 1,
+        last_sent: now,
+        last_received: now,
+        acks_owed_since: None,
+        channels: new_channels(),
+        rtt: RttEstimator::new(),
+        congestion: CongestionControl::new(now),
+        mtu: MtuProbe::new(),
+        fec_encoder: FecEncoder::new(),
+        fec_decoder: FecDecoder::new(),
+        stats: PeerStats::default(),
+        blobs: BlobTransfers::new()
+      }
+    }
+
+    pub fn connected(now: SteadyTime) -> Connection {
+      Connection {
+        state: ConnectionState::Connected,
+        session: new_session(),
+        peer_session: None,
+        last_attempt: now,
+        attempts: 0,
+        last_sent: now,
+        last_received: now,
+        acks_owed_since: None,
+        channels: new_channels(),
+        rtt: RttEstimator::new(),
+        congestion: CongestionControl::new(now),
+        mtu: MtuProbe::new(),
+        fec_encoder: FecEncoder::new(),
+        fec_decoder: FecDecoder::new(),
+        stats: PeerStats::default(),
+        blobs: BlobTransfers::new()
+      }
+    }
+
+    pub fn channel_mut(&mut self, channel: u8) -> Option<&mut DeliveryChannel> {
+      self.channels.get_mut(channel as usize)
+    }
+
+    pub fn is_connected(&self) -> bool {
+      self.state == ConnectionState::Connected
+    }
+
+    pub fn needs_connect_attempt(&self, now: SteadyTime) -> bool {
+      let time_elapsed: Duration = now - self.last_attempt;
+      self.state == ConnectionState::Connecting && time_elapsed.num_milliseconds() > CONNECT_RESEND_TIME
+    }
+
+    pub fn needs_keepalive(&self, now: SteadyTime, keepalive_interval: Duration) -> bool {
+      self.is_connected() && now - self.last_sent > keepalive_interval
+    }
+
+    pub fn owe_ack(&mut self, now: SteadyTime) {
+      if self.acks_owed_since.is_none() {
+        self.acks_owed_since = Some(now);
+      }
+    }
+
+    pub fn needs_ack(&self, now: SteadyTime, ack_delay: Duration) -> bool {
+      self.is_connected() && self.acks_owed_since.map(|since| now - since >= ack_delay).unwrap_or(false)
+    }
+
+    pub fn is_timed_out(&self, now: SteadyTime, peer_timeout: Duration) -> bool {
+      self.is_connected() && now - self.last_received > peer_timeout
+    }
+  }
+
+  // Only has to differ from the last session with the same peer, so a restarted process is told apart
+  fn new_session() -> u32 {
+    let ns = time::precise_time_ns();
+    (ns ^ (ns >> 32)) as u32
+  }
+
+  fn new_channels() -> Vec<DeliveryChannel> {
+    (0..MAX_CHANNELS).map(|_| DeliveryChannel::new()).collect()
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use time::{Duration, SteadyTime};
+    use constants::{
+      CONNECT_RESEND_TIME,
+      MAX_CHANNELS,
+    };
+    use super::{Connection, ConnectionState};
+
+    #[test]
+    fn connecting_needs_attempt_after_resend_time() {
+      let now = SteadyTime::now();
+      let connection = Connection::connecting(now);
+      assert_eq!(connection.state, ConnectionState::Connecting);
+      assert_eq!(connection.needs_connect_attempt(now), false);
+
+      let later = now + Duration::milliseconds(CONNECT_RESEND_TIME + 1);
+      assert_eq!(connection.needs_connect_attempt(later), true);
+    }
+
+    #[test]
+    fn connected_never_needs_attempt() {
+      let now = SteadyTime::now();
+      let connection = Connection::connected(now);
+      assert_eq!(connection.is_connected(), true);
+
+      let later = now + Duration::milliseconds(CONNECT_RESEND_TIME + 1);
+      assert_eq!(connection.needs_connect_attempt(later), false);
+    }
+
+    #[test]
+    fn channel_mut_only_knows_max_channels() {
+      let mut connection = Connection::connected(SteadyTime::now());
+      assert_eq!(connection.channel_mut(0).is_some(), true);
+      assert_eq!(connection.channel_mut(MAX_CHANNELS - 1).is_some(), true);
+      assert_eq!(connection.channel_mut(MAX_CHANNELS).is_none(), true);
+    }
+
+    #[test]
+    fn connected_needs_keepalive_after_interval() {
+      let now = SteadyTime::now();
+      let interval = Duration::milliseconds(100);
+      let connection = Connection::connected(now);
+      assert_eq!(connection.needs_keepalive(now, interval), false);
+      assert_eq!(connection.needs_keepalive(now + Duration::milliseconds(101), interval), true);
+
+      let connection = Connection::connecting(now);
+      assert_eq!(connection.needs_keepalive(now + Duration::milliseconds(101), interval), false);
+    }
+
+    #[test]
+    fn owed_acks_wait_for_ack_delay() {
+      let now = SteadyTime::now();
+      let delay = Duration::milliseconds(20);
+      let mut connection = Connection::connected(now);
+      assert_eq!(connection.needs_ack(now + delay, delay), false);
+
+      connection.owe_ack(now);
+      connection.owe_ack(now + Duration::milliseconds(10));
+      assert_eq!(connection.needs_ack(now + Duration::milliseconds(19), delay), false);
+      assert_eq!(connection.needs_ack(now + delay, delay), true);
+
+      connection.acks_owed_since = None;
+      assert_eq!(connection.needs_ack(now + delay, delay), false);
+    }
+
+    #[test]
+    fn connected_times_out_after_silence() {
+      let now = SteadyTime::now();
+      let timeout = Duration::milliseconds(1000);
+      let mut connection = Connection::connected(now);
+      assert_eq!(connection.is_timed_out(now + Duration::milliseconds(1001), timeout), true);
+
+      connection.last_received = now + Duration::milliseconds(500);
+      assert_eq!(connection.is_timed_out(now + Duration::milliseconds(1001), timeout), false);
+    }
+  }
+}
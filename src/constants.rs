@@ -1,11 +1,119 @@
 pub use self::constants::{
-  UDP_MARKER,
+  MARKER_LEN,
+  DEFAULT_PROTOCOL_ID,
+  DEFAULT_PROTOCOL_VERSION,
   PACKET_DROP_TIME,
-  MAX_RESEND_ATTEMPTS
+  MAX_RESEND_ATTEMPTS,
+  CONNECT_RESEND_TIME,
+  MAX_CONNECT_ATTEMPTS,
+  DISCONNECT_REPEAT_COUNT,
+  MAX_CONNECTIONS,
+  KEEPALIVE_INTERVAL,
+  PEER_TIMEOUT,
+  ACK_DELAY,
+  RECV_POLL_TIME,
+  QUEUE_WAIT_TIME,
+  HEADER_LEN,
+  DATA_HEADER_LEN,
+  ORDERED_WINDOW,
+  MAX_CHANNELS,
+  INITIAL_RTO,
+  MIN_RTO,
+  FAST_RESEND_THRESHOLD,
+  MAX_DATAGRAM_SIZE,
+  MIN_DATAGRAM_SIZE,
+  UDP_DATAGRAM_LIMIT,
+  FRAGMENT_HEADER_LEN,
+  MAX_FRAGMENTS,
+  FRAGMENT_TIMEOUT,
+  MAX_FRAGMENT_GROUPS_PER_PEER,
+  MAX_FRAGMENT_BYTES,
+  BLOB_WINDOW,
+  MAX_BLOB_SIZE,
+  MAX_INCOMING_BLOBS,
+  FINISHED_BLOB_TIME,
+  BLOB_IDLE_TIMEOUT,
+  GOOD_SEND_RATE,
+  BAD_SEND_RATE,
+  CONGESTION_RTT_THRESHOLD,
+  CONGESTION_LOSS_THRESHOLD,
+  LOSS_WINDOW,
+  MIN_LOSS_SAMPLES,
+  INITIAL_CONGESTION_PENALTY,
+  MIN_CONGESTION_PENALTY,
+  MAX_CONGESTION_PENALTY,
+  CONGESTION_RECOVERY_TIME,
+  SEND_BURST,
+  DEFAULT_PRIORITY,
+  PEER_TICK_BUDGET,
+  MAX_QUEUED_PER_PEER,
+  MAX_IN_FLIGHT_PER_PEER,
+  MTU_PROBE_SIZES,
+  MAX_PROBE_SIZE,
+  MTU_PROBE_ATTEMPTS,
+  FEC_HEADER_LEN,
+  MAX_FEC_GROUP_SIZE,
+  FEC_HISTORY,
+  INPUT_REDUNDANCY,
+  INPUT_SENT_HISTORY,
 };
 
 mod constants {
-  pub const UDP_MARKER: &'static [u8] = b"012";
-  pub const PACKET_DROP_TIME: i64 = 5; // Seconds
+  pub const MARKER_LEN: usize = 6; // Protocol id and version bytes starting every datagram
+  pub const DEFAULT_PROTOCOL_ID: u32 = 0x00303132; // b"012", games should pick their own
+  pub const DEFAULT_PROTOCOL_VERSION: u16 = 0;
+  pub const PACKET_DROP_TIME: i64 = 5; // Seconds, also the longest the resend timeout can back off to
   pub const MAX_RESEND_ATTEMPTS: i32 = 5;
+  pub const CONNECT_RESEND_TIME: i64 = 500; // Milliseconds
+  pub const MAX_CONNECT_ATTEMPTS: i32 = 10;
+  pub const DISCONNECT_REPEAT_COUNT: usize = 3;
+  pub const MAX_CONNECTIONS: usize = 64; // Peers, connecting ones included
+  pub const KEEPALIVE_INTERVAL: i64 = 1000; // Milliseconds
+  pub const PEER_TIMEOUT: i64 = 10000; // Milliseconds
+  pub const ACK_DELAY: i64 = 20; // Milliseconds an ack waits for outgoing data to ride on
+  pub const RECV_POLL_TIME: u64 = 50; // Milliseconds
+  pub const QUEUE_WAIT_TIME: u64 = 50; // Milliseconds a blocked send waits between checks that the director is still running
+  pub const HEADER_LEN: usize = 8; // Sequence, ack and ack field bytes following the marker
+  pub const DATA_HEADER_LEN: usize = 4; // Kind, delivery and order number bytes ahead of a data packet's payload
+  pub const ORDERED_WINDOW: u16 = 1024; // How far past the oldest missing message reliable messages are taken, and ordered ones buffered
+  pub const MAX_CHANNELS: u8 = 16; // Channel numbers have to fit in a nibble
+  pub const INITIAL_RTO: i64 = 1000; // Milliseconds, until the first round trip is measured
+  pub const MIN_RTO: i64 = 50; // Milliseconds
+  pub const FAST_RESEND_THRESHOLD: u32 = 3; // Later packets acked before an unacked one is presumed lost
+  pub const MAX_DATAGRAM_SIZE: usize = 256; // Bytes, marker and headers included
+  pub const MIN_DATAGRAM_SIZE: usize = 64; // Bytes, so every header and some payload fit in one datagram
+  pub const UDP_DATAGRAM_LIMIT: usize = 65507; // Bytes, the most an IPv4 UDP datagram can carry
+  pub const FRAGMENT_HEADER_LEN: usize = 3; // Kind, index and count bytes ahead of each fragment
+  pub const MAX_FRAGMENTS: usize = 255; // Fragment counts have to fit in a byte
+  pub const FRAGMENT_TIMEOUT: i64 = 5000; // Milliseconds an incomplete fragmented packet is held
+  pub const MAX_FRAGMENT_GROUPS_PER_PEER: usize = 32; // Incomplete fragmented packets held for one peer
+  pub const MAX_FRAGMENT_BYTES: usize = 4 * 1024 * 1024; // Bytes of fragments held for every peer together
+  pub const BLOB_WINDOW: usize = 64; // Unacked chunks a blob may have in flight
+  pub const MAX_BLOB_SIZE: u32 = 64 * 1024 * 1024; // Bytes
+  pub const MAX_INCOMING_BLOBS: usize = 4; // Blobs a peer can have partially received at once
+  pub const FINISHED_BLOB_TIME: i64 = 30000; // Milliseconds a received blob's straggling chunks are still acked, before its id can be reused
+  pub const BLOB_IDLE_TIMEOUT: i64 = 15000; // Milliseconds a partly received blob waits for its next chunk before it is given up on
+  pub const GOOD_SEND_RATE: u32 = 30; // Packets per second to a peer with a healthy link
+  pub const BAD_SEND_RATE: u32 = 10; // Packets per second to a congested peer
+  pub const CONGESTION_RTT_THRESHOLD: i64 = 250; // Milliseconds of smoothed rtt that count as congestion
+  pub const CONGESTION_LOSS_THRESHOLD: u32 = 10; // Percent of reliable packets lost that counts as congestion
+  pub const LOSS_WINDOW: usize = 64; // Most recent reliable packet outcomes loss is measured over
+  pub const MIN_LOSS_SAMPLES: usize = 20; // Outcomes needed before loss can count as congestion
+  pub const INITIAL_CONGESTION_PENALTY: i64 = 4000; // Milliseconds of good conditions before leaving the bad rate
+  pub const MIN_CONGESTION_PENALTY: i64 = 1000; // Milliseconds
+  pub const MAX_CONGESTION_PENALTY: i64 = 60000; // Milliseconds
+  pub const CONGESTION_RECOVERY_TIME: i64 = 10000; // Milliseconds at the good rate that halve the penalty
+  pub const SEND_BURST: u32 = 4; // Packets that can go out back to back once a peer's allowance builds up
+  pub const DEFAULT_PRIORITY: u8 = 128;
+  pub const PEER_TICK_BUDGET: usize = 1024; // Bytes of new messages sent to a peer each director tick
+  pub const MAX_QUEUED_PER_PEER: usize = 256; // Messages
+  pub const MAX_IN_FLIGHT_PER_PEER: usize = 32; // Reliable packets awaiting acks
+  pub const MTU_PROBE_SIZES: [usize; 5] = [512, 1024, 1200, 1280, 1400]; // Bytes, marker and headers included
+  pub const MAX_PROBE_SIZE: usize = 1400; // Bytes, leaves room for the IP and UDP headers and a little tunnel overhead
+  pub const MTU_PROBE_ATTEMPTS: u32 = 3; // Unacked probes of one size before it counts as too big
+  pub const FEC_HEADER_LEN: usize = 13; // Kind, first seq #, mask, and the xored ack headers and length ahead of a parity's bytes
+  pub const MAX_FEC_GROUP_SIZE: usize = 16; // Group members have to fit in the parity's u16 mask
+  pub const FEC_HISTORY: usize = 64; // Data packets kept from each peer for rebuilding missing ones
+  pub const INPUT_REDUNDANCY: usize = 8; // Unacked inputs repeated in each input packet
+  pub const INPUT_SENT_HISTORY: usize = 33; // Input packets remembered per channel, as far back as an ack field reaches
 }
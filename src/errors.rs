@@ -1,22 +1,87 @@
 pub use self::errors::{
+  NetworkError,
   socket_bind_err,
   socket_recv_err,
   socket_send_err,
 };
 
 mod errors {
-  use std::io::Error;
+  use std::error::Error;
+  use std::fmt;
+  use std::io;
+  use std::net::SocketAddr;
 
-  pub fn socket_bind_err(err: Error) {
-    println!("UDP: Error binding socket: {}", err)
+  // io::Error is neither Clone nor Eq, so only its kind and message are kept
+  #[derive(Clone, Debug, PartialEq, Eq)]
+  pub enum NetworkError {
+    SocketBind(io::ErrorKind, String),
+    SocketSend(SocketAddr, io::ErrorKind, String),
+    SocketRecv(io::ErrorKind, String),
+    MalformedPacket(SocketAddr),
+    NotConnected(SocketAddr),
+    InvalidChannel(SocketAddr, u8),
+    PacketTooLarge(SocketAddr, usize),
+    InvalidDatagramSize(usize),
+    // A NetworkConfig setting, by field name, that would keep anything from being sent
+    InvalidConfig(&'static str),
+    WouldBlock(SocketAddr),
+    // The director has stopped, after a shutdown or a panic, so nothing more can be sent
+    Shutdown,
+    // A peer speaking our protocol id, but another version of it
+    ProtocolVersionMismatch(SocketAddr, u16)
   }
 
-  pub fn socket_recv_err(err: Error) {
-    println!("UDP: Error receiving from socket: {}", err)
+  impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match *self {
+        NetworkError::SocketBind(_, ref msg) => write!(f, "UDP: Error binding socket: {}", msg),
+        NetworkError::SocketSend(addr, _, ref msg) => write!(f, "UDP: Error sending to {}: {}", addr, msg),
+        NetworkError::SocketRecv(_, ref msg) => write!(f, "UDP: Error receiving from socket: {}", msg),
+        NetworkError::MalformedPacket(addr) => write!(f, "UDP: Malformed packet from {}", addr),
+        NetworkError::NotConnected(addr) => write!(f, "UDP: Not connected to {}", addr),
+        NetworkError::InvalidChannel(addr, channel) => write!(f, "UDP: No channel {} for {}", channel, addr),
+        NetworkError::PacketTooLarge(addr, size) => write!(f, "UDP: {} byte packet for {} is too large", size, addr),
+        NetworkError::InvalidDatagramSize(size) => write!(f, "UDP: {} is not a usable datagram size", size),
+        NetworkError::InvalidConfig(field) => write!(f, "UDP: {} is not usable as configured", field),
+        NetworkError::WouldBlock(addr) => write!(f, "UDP: Send queue for {} is full", addr),
+        NetworkError::Shutdown => write!(f, "UDP: Network is shut down"),
+        NetworkError::ProtocolVersionMismatch(addr, version) => write!(f, "UDP: {} is running protocol version {}", addr, version),
+      }
+    }
   }
 
-  pub fn socket_send_err(err: Error) {
-    println!("UDP: Error sending to socket: {}", err)
+  impl Error for NetworkError {}
+
+  pub fn socket_bind_err(err: io::Error) -> NetworkError {
+    NetworkError::SocketBind(err.kind(), err.to_string())
+  }
+
+  pub fn socket_recv_err(err: io::Error) -> NetworkError {
+    NetworkError::SocketRecv(err.kind(), err.to_string())
+  }
+
+  pub fn socket_send_err(addr: SocketAddr, err: io::Error) -> NetworkError {
+    NetworkError::SocketSend(addr, err.kind(), err.to_string())
   }
 
+  #[cfg(test)]
+  mod tests {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use errors::{NetworkError, socket_bind_err, socket_send_err};
+
+    #[test]
+    fn socket_bind_err_keeps_kind() {
+      let err = io::Error::new(io::ErrorKind::AddrInUse, "in use");
+      assert_eq!(socket_bind_err(err), NetworkError::SocketBind(io::ErrorKind::AddrInUse, "in use".to_string()));
+    }
+
+    #[test]
+    fn socket_send_err_displays_addr() {
+      let addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+      let err = io::Error::new(io::ErrorKind::Other, "oops");
+      assert_eq!(format!("{}", socket_send_err(addr, err)), "UDP: Error sending to 127.0.0.1:1000: oops");
+    }
+  }
 }
@@ -0,0 +1,211 @@
+pub use self::fec::{
+  FecEncoder,
+  FecDecoder,
+};
+
+mod fec {
+  use std::collections::VecDeque;
+  use std::net::SocketAddr;
+  use std::cmp;
+  use byteorder::{ByteOrder, BigEndian};
+  use packet_types::{
+    SequencedAckedPacket,
+    PacketKind
+  };
+  use constants::{
+    FEC_HEADER_LEN,
+    MAX_FEC_GROUP_SIZE,
+    FEC_HISTORY,
+  };
+
+  // XORs each group of sequenced packets sent to a peer into one Parity packet.
+  // The parity names its packets by the group's first seq # and a mask of offsets from it,
+  // so packets left out of the group (too big to protect) do not break it up.
+  #[derive(Clone, Debug)]
+  pub struct FecEncoder {
+    first_seq: Option<u16>,
+    mask: u16,
+    count: usize,
+    ack_num: u16,
+    ack_field: u32,
+    len: u16,
+    bytes: Vec<u8>
+  }
+
+  impl FecEncoder {
+    pub fn new() -> FecEncoder {
+      FecEncoder { first_seq: None, mask: 0, count: 0, ack_num: 0, ack_field: 0, len: 0, bytes: Vec::new() }
+    }
+
+    // Returns the parity payloads of any groups the packet completes
+    pub fn add(&mut self, packet: &SequencedAckedPacket, group_size: usize) -> Vec<Vec<u8>> {
+      let group_size = cmp::min(group_size, MAX_FEC_GROUP_SIZE);
+      let mut parities = Vec::new();
+      // A packet too far past the start of the group to fit in its mask closes the group early
+      if self.first_seq.map(|first_seq| packet.seq_num.wrapping_sub(first_seq) as usize >= MAX_FEC_GROUP_SIZE).unwrap_or(false) {
+        parities.extend(self.finish().into_iter());
+      }
+
+      let first_seq = *self.first_seq.get_or_insert(packet.seq_num);
+      self.mask |= 1 << packet.seq_num.wrapping_sub(first_seq);
+      self.count += 1;
+      self.ack_num ^= packet.ack_num;
+      self.ack_field ^= packet.ack_field;
+      self.len ^= packet.bytes.len() as u16;
+      if self.bytes.len() < packet.bytes.len() {
+        self.bytes.resize(packet.bytes.len(), 0);
+      }
+      for (parity_byte, byte) in self.bytes.iter_mut().zip(packet.bytes.iter()) {
+        *parity_byte ^= *byte;
+      }
+
+      if self.count >= group_size {
+        parities.extend(self.finish().into_iter());
+      }
+      parities
+    }
+
+    fn finish(&mut self) -> Option<Vec<u8>> {
+      let finished = self.first_seq.map(|first_seq| {
+        let mut header = vec![0; FEC_HEADER_LEN];
+        header[0] = PacketKind::Parity.to_byte();
+        BigEndian::write_u16(&mut header[1..3], first_seq);
+        BigEndian::write_u16(&mut header[3..5], self.mask);
+        BigEndian::write_u16(&mut header[5..7], self.ack_num);
+        BigEndian::write_u32(&mut header[7..11], self.ack_field);
+        BigEndian::write_u16(&mut header[11..13], self.len);
+        header.into_iter().chain(self.bytes.iter().cloned()).collect()
+      });
+      *self = FecEncoder::new();
+      finished
+    }
+  }
+
+  // The last FEC_HISTORY data packets from a peer, to rebuild a missing one from a parity
+  #[derive(Clone, Debug)]
+  pub struct FecDecoder {
+    received: VecDeque<SequencedAckedPacket>
+  }
+
+  impl FecDecoder {
+    pub fn new() -> FecDecoder {
+      FecDecoder { received: VecDeque::new() }
+    }
+
+    pub fn record(&mut self, packet: &SequencedAckedPacket) {
+      if self.received.len() >= FEC_HISTORY {
+        self.received.pop_front();
+      }
+      self.received.push_back(packet.clone());
+    }
+
+    // Rebuilds the parity's group member that never arrived, if exactly one is missing
+    pub fn recover(&mut self, addr: SocketAddr, parity: &[u8]) -> Option<SequencedAckedPacket> {
+      if parity.len() < FEC_HEADER_LEN {
+        return None;
+      }
+      let first_seq = BigEndian::read_u16(&parity[1..3]);
+      let mask = BigEndian::read_u16(&parity[3..5]);
+      let mut ack_num = BigEndian::read_u16(&parity[5..7]);
+      let mut ack_field = BigEndian::read_u32(&parity[7..11]);
+      let mut len = BigEndian::read_u16(&parity[11..13]);
+      let mut bytes = parity[FEC_HEADER_LEN..].to_vec();
+
+      let (present, missing): (Vec<u16>, Vec<u16>) = (0..MAX_FEC_GROUP_SIZE as u16)
+        .filter(|offset| mask & (1 << offset) != 0)
+        .map(|offset| first_seq.wrapping_add(offset))
+        .partition(|&seq_num| self.received.iter().any(|packet| packet.seq_num == seq_num));
+      if missing.len() != 1 {
+        return None;
+      }
+
+      for packet in self.received.iter().filter(|packet| present.contains(&packet.seq_num)) {
+        if packet.bytes.len() > bytes.len() {
+          return None;
+        }
+        ack_num ^= packet.ack_num;
+        ack_field ^= packet.ack_field;
+        len ^= packet.bytes.len() as u16;
+        for (byte, packet_byte) in bytes.iter_mut().zip(packet.bytes.iter()) {
+          *byte ^= *packet_byte;
+        }
+      }
+      if len as usize > bytes.len() {
+        return None;
+      }
+      bytes.truncate(len as usize);
+
+      let recovered = SequencedAckedPacket { addr, seq_num: missing[0], ack_num, ack_field, bytes };
+      self.record(&recovered);
+      Some(recovered)
+    }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use packet_types::{SequencedAckedPacket, PacketKind};
+    use constants::MAX_FEC_GROUP_SIZE;
+    use super::{FecEncoder, FecDecoder};
+
+    fn packet(seq_num: u16, bytes: Vec<u8>) -> SequencedAckedPacket {
+      let addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+      SequencedAckedPacket { addr, seq_num, ack_num: seq_num.wrapping_mul(2), ack_field: seq_num as u32 * 3, bytes }
+    }
+
+    #[test]
+    fn parity_goes_out_once_the_group_fills() {
+      let mut encoder = FecEncoder::new();
+
+      assert_eq!(encoder.add(&packet(7, vec![4, 1]), 3), Vec::<Vec<u8>>::new());
+      assert_eq!(encoder.add(&packet(8, vec![4, 2, 2]), 3), Vec::<Vec<u8>>::new());
+      let parities = encoder.add(&packet(10, vec![4]), 3);
+      assert_eq!(parities.len(), 1);
+      // 7, 8 and 10 are in the group, 9 was left out
+      assert_eq!(parities[0][0..5].to_vec(), vec![PacketKind::Parity.to_byte(), 0, 7, 0, 0b1011]);
+      assert_eq!(parities[0][13..].to_vec(), vec![4 ^ 4 ^ 4, 1 ^ 2, 2]);
+    }
+
+    #[test]
+    fn far_ahead_packets_close_the_group_early() {
+      let mut encoder = FecEncoder::new();
+
+      assert_eq!(encoder.add(&packet(1, vec![1]), 4).len(), 0);
+      let parities = encoder.add(&packet(1 + MAX_FEC_GROUP_SIZE as u16, vec![2]), 4);
+      assert_eq!(parities.len(), 1);
+      assert_eq!(parities[0][1..5].to_vec(), vec![0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn one_missing_packet_is_rebuilt() {
+      let addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+      let mut encoder = FecEncoder::new();
+      let mut decoder = FecDecoder::new();
+      let packets = vec![packet(1, vec![4, 1, 1]), packet(2, vec![4, 2]), packet(3, vec![4, 3, 3, 3])];
+      let parity = packets.iter().flat_map(|packet| encoder.add(packet, 3).into_iter()).next().unwrap();
+
+      decoder.record(&packets[0]);
+      // Two missing is more than one parity can fix
+      assert_eq!(decoder.recover(addr, &parity), None);
+
+      decoder.record(&packets[2]);
+      assert_eq!(decoder.recover(addr, &parity), Some(packets[1].clone()));
+      // Nothing left to rebuild
+      assert_eq!(decoder.recover(addr, &parity), None);
+    }
+
+    #[test]
+    fn longest_packet_is_rebuilt_at_full_length() {
+      let addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+      let mut encoder = FecEncoder::new();
+      let mut decoder = FecDecoder::new();
+      let packets = vec![packet(65535, vec![4, 9, 9, 9, 9]), packet(0, vec![4])];
+      let parity = packets.iter().flat_map(|packet| encoder.add(packet, 2).into_iter()).next().unwrap();
+
+      decoder.record(&packets[1]);
+      assert_eq!(decoder.recover(addr, &parity), Some(packets[0].clone()));
+      assert_eq!(decoder.recover(addr, &parity[0..12]), None);
+    }
+  }
+}
@@ -0,0 +1,304 @@
+pub use self::fragment::{
+  FragmentBuffer,
+  split_packet,
+  max_packet_size,
+};
+
+mod fragment {
+  use std::collections::HashMap;
+  use std::net::SocketAddr;
+  use time::{Duration, SteadyTime};
+  use packet_types::{
+    SequencedAckedPacket,
+    PacketKind
+  };
+  use errors::NetworkError;
+  use constants::{
+    MARKER_LEN,
+    HEADER_LEN,
+    FRAGMENT_HEADER_LEN,
+    MAX_FRAGMENTS,
+    FRAGMENT_TIMEOUT,
+    MAX_FRAGMENT_GROUPS_PER_PEER,
+    MAX_FRAGMENT_BYTES,
+  };
+
+  // Splits a packet too big for one datagram into Fragment packets that all share its
+  // sequence and ack headers, so they are acked (and resent) as a whole.
+  // Callers keep packets within max_packet_size so the count fits in a byte.
+  pub fn split_packet(packet: SequencedAckedPacket, max_datagram_size: usize) -> Vec<SequencedAckedPacket> {
+    if MARKER_LEN + HEADER_LEN + packet.bytes.len() <= max_datagram_size {
+      return vec![packet];
+    }
+
+    let fragment_size = fragment_size(max_datagram_size);
+    let count = (packet.bytes.len() + fragment_size - 1) / fragment_size;
+    packet.bytes.chunks(fragment_size)
+      .enumerate()
+      .map(|(index, chunk)| SequencedAckedPacket {
+        addr: packet.addr,
+        seq_num: packet.seq_num,
+        ack_num: packet.ack_num,
+        ack_field: packet.ack_field,
+        bytes: vec![PacketKind::Fragment.to_byte(), index as u8, count as u8].into_iter()
+          .chain(chunk.iter().cloned())
+          .collect()
+      })
+      .collect()
+  }
+
+  // Payload bytes per fragment, after the marker, sequence headers and fragment header
+  fn fragment_size(max_datagram_size: usize) -> usize {
+    max_datagram_size - MARKER_LEN - HEADER_LEN - FRAGMENT_HEADER_LEN
+  }
+
+  // The biggest packet that can be sent in MAX_FRAGMENTS datagrams
+  pub fn max_packet_size(max_datagram_size: usize) -> usize {
+    fragment_size(max_datagram_size) * MAX_FRAGMENTS
+  }
+
+  #[derive(Debug)]
+  struct FragmentGroup {
+    started: SteadyTime,
+    chunks: Vec<Option<Vec<u8>>>,
+    remaining: usize,
+    // Bytes of the chunks that have arrived
+    bytes: usize
+  }
+
+  // Incomplete fragment groups, keyed by sender and shared sequence number
+  #[derive(Debug)]
+  pub struct FragmentBuffer {
+    groups: HashMap<(SocketAddr, u16), FragmentGroup>,
+    bytes: usize
+  }
+
+  impl FragmentBuffer {
+    pub fn new() -> FragmentBuffer {
+      FragmentBuffer { groups: HashMap::new(), bytes: 0 }
+    }
+
+    // Returns the reassembled packet once the last of its fragments arrives.
+    // Groups that stall for FRAGMENT_TIMEOUT are thrown away, and so are the oldest groups
+    // once a sender has MAX_FRAGMENT_GROUPS_PER_PEER pending or all of them hold MAX_FRAGMENT_BYTES.
+    pub fn insert(&mut self, now: SteadyTime, fragment: SequencedAckedPacket) -> Result<Option<SequencedAckedPacket>, NetworkError> {
+      let timeout = Duration::milliseconds(FRAGMENT_TIMEOUT);
+      self.groups.retain(|_, group| now - group.started <= timeout);
+      self.bytes = self.groups.values().map(|group| group.bytes).sum();
+
+      let (index, count) = match fragment_header(&fragment) {
+        Some(header) => header,
+        None => return Err(NetworkError::MalformedPacket(fragment.addr))
+      };
+      let chunk: Vec<u8> = fragment.bytes.iter().skip(FRAGMENT_HEADER_LEN).cloned().collect();
+
+      let key = (fragment.addr, fragment.seq_num);
+      if !self.groups.contains_key(&key) {
+        while self.groups.keys().filter(|&&(addr, _)| addr == fragment.addr).count() >= MAX_FRAGMENT_GROUPS_PER_PEER {
+          self.evict_oldest(|&(addr, _)| addr == fragment.addr);
+        }
+      }
+
+      let is_complete = {
+        let group = self.groups.entry(key).or_insert_with(|| FragmentGroup {
+          started: now,
+          chunks: vec![None; count],
+          remaining: count,
+          bytes: 0
+        });
+        if group.chunks.len() != count {
+          return Err(NetworkError::MalformedPacket(fragment.addr));
+        }
+        if group.chunks[index].is_none() {
+          group.bytes += chunk.len();
+          self.bytes += chunk.len();
+          group.chunks[index] = Some(chunk);
+          group.remaining -= 1;
+        }
+        group.remaining == 0
+      };
+
+      if !is_complete {
+        // The group being filled is never the one thrown away, so one packet bigger than the limit still gets through
+        while self.bytes > MAX_FRAGMENT_BYTES && self.evict_oldest(|other| *other != key) {}
+        return Ok(None);
+      }
+
+      let group = self.groups.remove(&key);
+      self.bytes -= group.as_ref().map(|group| group.bytes).unwrap_or(0);
+      Ok(group.map(|group| SequencedAckedPacket {
+        addr: fragment.addr,
+        seq_num: fragment.seq_num,
+        ack_num: fragment.ack_num,
+        ack_field: fragment.ack_field,
+        bytes: group.chunks.into_iter().flat_map(|chunk| chunk.unwrap_or(Vec::new()).into_iter()).collect()
+      }))
+    }
+  }
+
+  impl FragmentBuffer {
+    // Throws away the oldest group the filter picks, returning whether there was one
+    fn evict_oldest<F>(&mut self, filter: F) -> bool
+      where F: Fn(&(SocketAddr, u16)) -> bool {
+      let oldest =
+        self.groups.iter()
+          .filter(|&(key, _)| filter(key))
+          .min_by_key(|&(_, group)| group.started)
+          .map(|(key, _)| *key);
+      match oldest.and_then(|key| self.groups.remove(&key)) {
+        Some(group) => {
+          self.bytes -= group.bytes;
+          true
+        },
+        None => false
+      }
+    }
+  }
+
+  // Index and count, if the fragment header is present and consistent
+  fn fragment_header(fragment: &SequencedAckedPacket) -> Option<(usize, usize)> {
+    if fragment.bytes.len() < FRAGMENT_HEADER_LEN {
+      return None;
+    }
+    let index = fragment.bytes[1] as usize;
+    let count = fragment.bytes[2] as usize;
+    if index < count { Some((index, count)) } else { None }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use time::{Duration, SteadyTime};
+    use packet_types::{SequencedAckedPacket, PacketKind};
+    use errors::NetworkError;
+    use constants::{MAX_DATAGRAM_SIZE, UDP_DATAGRAM_LIMIT, FRAGMENT_TIMEOUT, MAX_FRAGMENT_GROUPS_PER_PEER, MAX_FRAGMENT_BYTES};
+    use super::{FragmentBuffer, split_packet, fragment_size, max_packet_size};
+
+    // Marker, sequence headers and fragment header take 17 bytes
+    const FRAGMENT_SIZE: usize = MAX_DATAGRAM_SIZE - 17;
+
+    fn split(packet: SequencedAckedPacket) -> Vec<SequencedAckedPacket> {
+      split_packet(packet, MAX_DATAGRAM_SIZE)
+    }
+
+    fn packet(seq_num: u16, len: usize) -> SequencedAckedPacket {
+      SequencedAckedPacket {
+        addr: SocketAddr::from_str("127.0.0.1:1000").unwrap(),
+        seq_num,
+        ack_num: 2,
+        ack_field: 3,
+        bytes: (0..len).map(|byte| byte as u8).collect()
+      }
+    }
+
+    #[test]
+    fn split_packet_leaves_small_packets_alone() {
+      assert_eq!(split(packet(1, 100)), vec![packet(1, 100)]);
+      assert_eq!(split_packet(packet(1, 1000), 1100), vec![packet(1, 1000)]);
+    }
+
+    #[test]
+    fn sizes_follow_max_datagram_size() {
+      assert_eq!(fragment_size(MAX_DATAGRAM_SIZE), FRAGMENT_SIZE);
+      assert_eq!(fragment_size(1200), 1183);
+      assert_eq!(max_packet_size(1200), 1183 * 255);
+    }
+
+    #[test]
+    fn split_packet_numbers_fragments() {
+      let fragments = split(packet(1, FRAGMENT_SIZE * 2 + 10));
+      assert_eq!(fragments.len(), 3);
+      assert_eq!(fragments.iter().all(|fragment| fragment.seq_num == 1 && fragment.ack_field == 3), true);
+      assert_eq!(fragments.iter().map(|fragment| fragment.kind()).collect::<Vec<_>>(), vec![Some(PacketKind::Fragment); 3]);
+      assert_eq!(fragments.iter().map(|fragment| (fragment.bytes[1], fragment.bytes[2])).collect::<Vec<_>>(), vec![(0, 3), (1, 3), (2, 3)]);
+      assert_eq!(fragments[2].bytes.len(), 3 + 10);
+    }
+
+    #[test]
+    fn reassembles_out_of_order_fragments() {
+      let now = SteadyTime::now();
+      let mut buffer = FragmentBuffer::new();
+      let mut fragments = split(packet(1, FRAGMENT_SIZE * 2 + 10));
+      fragments.reverse();
+
+      assert_eq!(buffer.insert(now, fragments[0].clone()), Ok(None));
+      assert_eq!(buffer.insert(now, fragments[1].clone()), Ok(None));
+      // Duplicates do not count twice
+      assert_eq!(buffer.insert(now, fragments[1].clone()), Ok(None));
+      assert_eq!(buffer.insert(now, fragments[2].clone()), Ok(Some(packet(1, FRAGMENT_SIZE * 2 + 10))));
+    }
+
+    #[test]
+    fn stalled_groups_expire() {
+      let now = SteadyTime::now();
+      let mut buffer = FragmentBuffer::new();
+      let fragments = split(packet(1, FRAGMENT_SIZE + 10));
+
+      assert_eq!(buffer.insert(now, fragments[0].clone()), Ok(None));
+      let later = now + Duration::milliseconds(FRAGMENT_TIMEOUT + 1);
+      assert_eq!(buffer.insert(later, fragments[1].clone()), Ok(None));
+      assert_eq!(buffer.insert(later, fragments[0].clone()), Ok(Some(packet(1, FRAGMENT_SIZE + 10))));
+    }
+
+    #[test]
+    fn each_peer_only_holds_its_newest_groups() {
+      let now = SteadyTime::now();
+      let mut buffer = FragmentBuffer::new();
+      let other_packet = SequencedAckedPacket { addr: SocketAddr::from_str("127.0.0.1:1001").unwrap(), .. packet(0, FRAGMENT_SIZE + 10) };
+      let other_peer = split(other_packet.clone());
+      assert_eq!(buffer.insert(now, other_peer[0].clone()), Ok(None));
+      for seq_num in 0..MAX_FRAGMENT_GROUPS_PER_PEER + 1 {
+        let started = now + Duration::milliseconds(seq_num as i64);
+        assert_eq!(buffer.insert(started, split(packet(seq_num as u16, FRAGMENT_SIZE + 10))[0].clone()), Ok(None));
+      }
+
+      // The newest group still completes, but the oldest made way for it
+      let later = now + Duration::milliseconds(MAX_FRAGMENT_GROUPS_PER_PEER as i64);
+      let newest = MAX_FRAGMENT_GROUPS_PER_PEER as u16;
+      assert_eq!(buffer.insert(later, split(packet(newest, FRAGMENT_SIZE + 10))[1].clone()), Ok(Some(packet(newest, FRAGMENT_SIZE + 10))));
+      assert_eq!(buffer.insert(later, split(packet(0, FRAGMENT_SIZE + 10))[1].clone()), Ok(None));
+      // Other peers keep theirs
+      assert_eq!(buffer.insert(later, other_peer[1].clone()), Ok(Some(other_packet)));
+    }
+
+    #[test]
+    fn held_bytes_are_limited() {
+      let now = SteadyTime::now();
+      let mut buffer = FragmentBuffer::new();
+      let fragment_size = fragment_size(UDP_DATAGRAM_LIMIT);
+      let groups = MAX_FRAGMENT_BYTES / fragment_size + 1;
+      let first_fragments = |seq_num: usize| {
+        let mut fragments = split_packet(packet(seq_num as u16, fragment_size * 2), UDP_DATAGRAM_LIMIT);
+        fragments.iter_mut().for_each(|fragment| fragment.addr = SocketAddr::from_str(&format!("127.0.0.1:{}", 2000 + seq_num)).unwrap());
+        fragments
+      };
+      for seq_num in 0..groups {
+        let _ = buffer.insert(now + Duration::milliseconds(seq_num as i64), first_fragments(seq_num)[0].clone());
+      }
+      assert_eq!(buffer.bytes <= MAX_FRAGMENT_BYTES, true);
+
+      // The oldest group was thrown away to make room, and the newest is still there
+      let later = now + Duration::milliseconds(groups as i64);
+      assert_eq!(buffer.insert(later, first_fragments(0)[1].clone()), Ok(None));
+      assert_eq!(buffer.insert(later, first_fragments(groups - 1)[1].clone()).unwrap().is_some(), true);
+    }
+
+    #[test]
+    fn rejects_malformed_fragments() {
+      let now = SteadyTime::now();
+      let mut buffer = FragmentBuffer::new();
+      let addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+      let mut bad_index = packet(1, 10);
+      bad_index.bytes = vec![PacketKind::Fragment.to_byte(), 2, 2, 0];
+      assert_eq!(buffer.insert(now, bad_index), Err(NetworkError::MalformedPacket(addr)));
+
+      let mut first = packet(1, 10);
+      first.bytes = vec![PacketKind::Fragment.to_byte(), 0, 2, 0];
+      assert_eq!(buffer.insert(now, first), Ok(None));
+      let mut count_changed = packet(1, 10);
+      count_changed.bytes = vec![PacketKind::Fragment.to_byte(), 1, 3, 0];
+      assert_eq!(buffer.insert(now, count_changed), Err(NetworkError::MalformedPacket(addr)));
+    }
+  }
+}
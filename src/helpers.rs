@@ -1,5 +1,6 @@
 pub use self::helpers::{
-  try_recv_all
+  try_recv_all,
+  is_newer_seq,
 };
 mod helpers {
   use std::iter::{repeat};
@@ -12,10 +13,15 @@ mod helpers {
       .collect()
   }
 
+  // Wraparound aware "a comes after b" for u16 sequence numbers
+  pub fn is_newer_seq(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+  }
+
   #[cfg(test)]
   mod tests {
     use std::sync::mpsc::channel;
-    use helpers::try_recv_all;
+    use helpers::{try_recv_all, is_newer_seq};
 
     #[test]
     fn try_recv_all_with_none() {
@@ -43,6 +49,15 @@ mod helpers {
       let result = try_recv_all(&rx);
       assert_eq!(result, vec![1, 2, 3, 4]);
     }
+
+    #[test]
+    fn is_newer_seq_normal_and_wraparound() {
+      assert_eq!(is_newer_seq(5, 4), true);
+      assert_eq!(is_newer_seq(4, 5), false);
+      assert_eq!(is_newer_seq(5, 5), false);
+      assert_eq!(is_newer_seq(2, 65534), true);
+      assert_eq!(is_newer_seq(65534, 2), false);
+    }
   }
 }
 
@@ -0,0 +1,194 @@
+pub use self::input::{
+  InputStream,
+};
+
+mod input {
+  use std::collections::VecDeque;
+  use byteorder::{ByteOrder, BigEndian};
+  use packet_types::{
+    Packet,
+    DeliveryPacket
+  };
+  use helpers::is_newer_seq;
+  use constants::INPUT_SENT_HISTORY;
+
+  // Both directions of one channel's input stream. Every input packet carries all of the inputs
+  // not yet known to be acked (up to the redundancy), oldest first, starting at its order number.
+  #[derive(Debug)]
+  pub struct InputStream {
+    next_num: u16,
+    pending: VecDeque<(u16, Vec<u8>)>,
+    // Whether any pending input has yet to be sent even once
+    has_new: bool,
+    // Seq #s of the packets that carried inputs, with the newest input each one carried
+    sent: VecDeque<(u16, u16)>,
+    newest_received: Option<u16>
+  }
+
+  impl InputStream {
+    pub fn new() -> InputStream {
+      InputStream { next_num: 0, pending: VecDeque::new(), has_new: false, sent: VecDeque::new(), newest_received: None }
+    }
+
+    pub fn push(&mut self, bytes: Vec<u8>) {
+      self.pending.push_back((self.next_num, bytes));
+      self.next_num = self.next_num.wrapping_add(1);
+      self.has_new = true;
+    }
+
+    pub fn has_new(&self) -> bool {
+      self.has_new
+    }
+
+    // The first input number and length prefixed inputs for the next input packet, dropping
+    // any inputs older than the last redundancy of them, or too old to fit in max_len, for good.
+    // The newest input is always sent.
+    pub fn payload(&mut self, redundancy: usize, max_len: usize) -> Option<(u16, Vec<u8>)> {
+      let mut len = self.pending.iter().map(|&(_, ref input)| 2 + input.len()).sum::<usize>();
+      while self.pending.len() > 1 && (self.pending.len() > redundancy || len > max_len) {
+        if let Some((_, input)) = self.pending.pop_front() {
+          len -= 2 + input.len();
+        }
+      }
+      self.pending.front().map(|&(first_num, _)| first_num).map(|first_num| {
+        let bytes = self.pending.iter()
+          .flat_map(|&(_, ref input)| {
+            let mut len = vec![0; 2];
+            BigEndian::write_u16(&mut len, input.len() as u16);
+            len.into_iter().chain(input.iter().cloned())
+          })
+          .collect();
+        (first_num, bytes)
+      })
+    }
+
+    pub fn record_sent(&mut self, seq_num: u16) {
+      let newest = self.next_num.wrapping_sub(1);
+      // Ack fields only reach 32 packets back, so older packets will never be acked
+      if self.sent.len() >= INPUT_SENT_HISTORY {
+        self.sent.pop_front();
+      }
+      self.sent.push_back((seq_num, newest));
+      self.has_new = false;
+    }
+
+    // Drops every input carried by one of the acked packets, and everything before it
+    pub fn acked(&mut self, seq_nums: &[u16]) {
+      let acked_newest = self.sent.iter()
+        .filter(|&&(seq_num, _)| seq_nums.contains(&seq_num))
+        .map(|&(_, newest)| newest)
+        .fold(None, |acked: Option<u16>, newest| match acked {
+          Some(acked) if !is_newer_seq(newest, acked) => Some(acked),
+          _ => Some(newest)
+        });
+      if let Some(acked_newest) = acked_newest {
+        self.pending.retain(|&(num, _)| is_newer_seq(num, acked_newest));
+        self.sent.retain(|&(_, newest)| is_newer_seq(newest, acked_newest));
+      }
+    }
+
+    // Hands over each input newer than any already delivered, in order, once
+    pub fn receive(&mut self, packet: DeliveryPacket) -> Vec<Packet> {
+      let mut delivered = Vec::new();
+      let mut num = packet.order_num;
+      let mut rest = &packet.bytes[..];
+      while rest.len() >= 2 {
+        let len = BigEndian::read_u16(&rest[0..2]) as usize;
+        if rest.len() < 2 + len {
+          break;
+        }
+        let is_new = self.newest_received.map(|newest| is_newer_seq(num, newest)).unwrap_or(true);
+        if is_new {
+          self.newest_received = Some(num);
+          delivered.push(Packet { addr: packet.addr, bytes: rest[2..2 + len].to_vec() });
+        }
+        rest = &rest[2 + len..];
+        num = num.wrapping_add(1);
+      }
+      delivered
+    }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use packet_types::{Packet, DeliveryMode, DeliveryPacket};
+    use super::InputStream;
+
+    fn input_packet(order_num: u16, bytes: Vec<u8>) -> DeliveryPacket {
+      DeliveryPacket {
+        addr: SocketAddr::from_str("127.0.0.1:1000").unwrap(),
+        delivery: DeliveryMode::Input,
+        channel: 0,
+        order_num,
+        bytes
+      }
+    }
+
+    #[test]
+    fn unacked_inputs_ride_every_packet() {
+      let mut inputs = InputStream::new();
+      assert_eq!(inputs.payload(4, 100), None);
+
+      inputs.push(vec![1]);
+      inputs.push(vec![2, 2]);
+      assert_eq!(inputs.has_new(), true);
+      assert_eq!(inputs.payload(4, 100), Some((0, vec![0, 1, 1, 0, 2, 2, 2])));
+      inputs.record_sent(10);
+      assert_eq!(inputs.has_new(), false);
+
+      inputs.push(vec![3]);
+      assert_eq!(inputs.payload(4, 100), Some((0, vec![0, 1, 1, 0, 2, 2, 2, 0, 1, 3])));
+      inputs.record_sent(11);
+
+      // Acking packet 10 covers inputs 0 and 1
+      inputs.acked(&[10]);
+      assert_eq!(inputs.payload(4, 100), Some((2, vec![0, 1, 3])));
+      inputs.acked(&[11, 10]);
+      assert_eq!(inputs.payload(4, 100), None);
+    }
+
+    #[test]
+    fn only_the_last_redundancy_inputs_are_sent() {
+      let mut inputs = InputStream::new();
+      for byte in 0..5 {
+        inputs.push(vec![byte]);
+      }
+
+      assert_eq!(inputs.payload(2, 100), Some((3, vec![0, 1, 3, 0, 1, 4])));
+    }
+
+    #[test]
+    fn only_inputs_that_fit_are_sent() {
+      let mut inputs = InputStream::new();
+      for byte in 0..4 {
+        inputs.push(vec![byte; 100]);
+      }
+
+      // Each input takes 102 bytes with its length prefix
+      let (first_num, bytes) = inputs.payload(8, 250).unwrap();
+      assert_eq!((first_num, bytes.len()), (2, 204));
+      // Inputs dropped to fit are gone for good
+      assert_eq!(inputs.payload(8, 1000).unwrap().1.len(), 204);
+
+      // The newest input goes out even when it is too big on its own
+      assert_eq!(inputs.payload(8, 50), Some((3, vec![0, 100].into_iter().chain(vec![3; 100].into_iter()).collect())));
+    }
+
+    #[test]
+    fn each_input_is_delivered_once_in_order() {
+      let addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+      let mut inputs = InputStream::new();
+      let input = |byte| Packet { addr, bytes: vec![byte] };
+
+      assert_eq!(inputs.receive(input_packet(0, vec![0, 1, 7, 0, 1, 8])), vec![input(7), input(8)]);
+      // Input 1 was already delivered
+      assert_eq!(inputs.receive(input_packet(1, vec![0, 1, 8, 0, 1, 9, 0, 1, 10])), vec![input(9), input(10)]);
+      // Late packets deliver nothing
+      assert_eq!(inputs.receive(input_packet(0, vec![0, 1, 7, 0, 1, 8])), vec![]);
+      // A truncated input ends the packet
+      assert_eq!(inputs.receive(input_packet(4, vec![0, 1, 11, 0, 5, 1])), vec![input(11)]);
+    }
+  }
+}
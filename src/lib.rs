@@ -5,15 +5,37 @@ extern crate itertools;
 
 pub mod types;
 pub mod packet_types;
+pub mod config;
+pub mod errors;
 mod constants;
 mod helpers;
-mod errors;
 mod ack;
+mod connection;
+mod ordering;
+mod rtt;
+mod congestion;
+mod backpressure;
+mod stats;
+mod mtu;
+mod fec;
+mod input;
+mod fragment;
+mod blob;
 mod actors;
 
 use std::net::{SocketAddr, UdpSocket};
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::channel;
 
-use errors::socket_bind_err;
+use errors::{NetworkError, socket_bind_err};
+use constants::{MIN_DATAGRAM_SIZE, UDP_DATAGRAM_LIMIT};
+use config::{NetworkConfig, OverflowPolicy};
+use backpressure::QueueSlots;
+use stats::StatsBoard;
+use mtu::DatagramSizes;
+use fragment::max_packet_size;
 use types::{
   IOHandles,
   Network,
@@ -21,28 +43,104 @@ use types::{
 
 use actors::{NetSender, NetReceiver, Director};
 
-pub fn start_network(addr: SocketAddr) -> Network {
+pub fn start_network(addr: SocketAddr) -> Result<Network, NetworkError> {
+  start_network_with_config(addr, NetworkConfig::default())
+}
 
-  let send_socket =
-    UdpSocket::bind(addr)
-      .map_err(socket_bind_err)
-      .unwrap();
+pub fn start_network_with_config(addr: SocketAddr, config: NetworkConfig) -> Result<Network, NetworkError> {
+  if config.max_datagram_size < MIN_DATAGRAM_SIZE || config.max_datagram_size > UDP_DATAGRAM_LIMIT {
+    return Err(NetworkError::InvalidDatagramSize(config.max_datagram_size));
+  }
+  if config.max_receive_size() > UDP_DATAGRAM_LIMIT {
+    return Err(NetworkError::InvalidDatagramSize(config.max_probe_size));
+  }
+  // Blocked senders would wait forever for a slot, and reliable messages would never be sent
+  if config.max_queued_per_peer == 0 && config.overflow_policy == OverflowPolicy::Block {
+    return Err(NetworkError::InvalidConfig("max_queued_per_peer"));
+  }
+  if config.max_in_flight_per_peer == 0 {
+    return Err(NetworkError::InvalidConfig("max_in_flight_per_peer"));
+  }
 
-  let recv_socket = send_socket.try_clone().unwrap();
+  let send_socket = UdpSocket::bind(addr).map_err(socket_bind_err)?;
+  let recv_socket = send_socket.try_clone().map_err(socket_bind_err)?;
 
-  let net_sender = NetSender::new(send_socket);
-  let net_receiver = NetReceiver::new(recv_socket);
-  let director = Director::new(net_receiver.socket_recv_rx, net_sender.socket_send_tx);
+  let (event_tx, event_rx) = channel();
+  let datagram_sizes = DatagramSizes::new();
+  let net_sender = NetSender::new(send_socket, config.marker(), config.max_datagram_size, datagram_sizes.clone(), event_tx.clone());
+  let running = Arc::new(AtomicBool::new(true));
+  let net_receiver = NetReceiver::new(recv_socket, config.marker(), config.max_receive_size(), datagram_sizes.clone(), running.clone(), event_tx.clone());
+  let queue_slots = QueueSlots::new();
+  let max_queued_per_peer = config.max_queued_per_peer;
+  let overflow_policy = config.overflow_policy;
+  let max_packet_size = max_packet_size(config.max_datagram_size);
+  let stats = StatsBoard::new();
+  let director = Director::new(net_receiver.socket_recv_rx, net_sender.socket_send_tx, event_tx, queue_slots.clone(), stats.clone(), datagram_sizes, config);
 
   let io_handles = IOHandles {
     send_handle: net_sender.thread_handle,
     recv_handle: net_receiver.thread_handle,
-    direct_handle: director.thread_handle
+    direct_handle: director.thread_handle,
+    running: running
   };
 
-  Network {
+  Ok(Network {
     send_channel: director.api_in_tx,
     recv_channel: director.api_out_rx,
-    thread_handles: io_handles
+    command_channel: director.command_tx,
+    event_channel: event_rx,
+    thread_handles: Some(io_handles),
+    next_message_id: Cell::new(0),
+    next_blob_id: Cell::new(0),
+    max_queued_per_peer: max_queued_per_peer,
+    overflow_policy: overflow_policy,
+    max_packet_size: max_packet_size,
+    queue_slots: queue_slots,
+    stats: stats
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashSet;
+  use std::net::SocketAddr;
+  use std::str::FromStr;
+  use std::time::Duration;
+  use packet_types::{Packet, DeliveryMode};
+  use types::NetworkEvent;
+  use super::start_network;
+
+  #[test]
+  fn localhost_round_trip_test() {
+    let addr_a = SocketAddr::from_str("127.0.0.1:54751").unwrap();
+    let addr_b = SocketAddr::from_str("127.0.0.1:54752").unwrap();
+    let timeout = Duration::from_secs(5);
+    let mut network_a = start_network(addr_a).unwrap();
+    let mut network_b = start_network(addr_b).unwrap();
+
+    network_a.connect(addr_b);
+    assert_eq!(network_a.event_channel.recv_timeout(timeout), Ok(NetworkEvent::Connected(addr_b)));
+    assert_eq!(network_b.event_channel.recv_timeout(timeout), Ok(NetworkEvent::Connected(addr_a)));
+
+    // The last one takes several datagrams
+    let payloads = vec![vec![1], vec![2, 2], vec![3; 100], vec![4; 5000]];
+    let mut ids = payloads.iter()
+      .map(|bytes| network_a.send(Packet { addr: addr_b, bytes: bytes.clone() }, DeliveryMode::ReliableOrdered).unwrap())
+      .collect::<HashSet<_>>();
+
+    payloads.iter().for_each(|bytes| {
+      let packet = network_b.recv_channel.recv_timeout(timeout).unwrap();
+      assert_eq!(packet.addr, addr_a);
+      assert_eq!(&packet.bytes, bytes);
+    });
+    while !ids.is_empty() {
+      if let NetworkEvent::Acked(id) = network_a.event_channel.recv_timeout(timeout).unwrap() {
+        assert_eq!(ids.remove(&id), true);
+      }
+    }
+
+    network_a.shutdown();
+    network_b.shutdown();
+    assert_eq!(start_network(addr_a).is_ok(), true);
   }
 }
@@ -0,0 +1,220 @@
+pub use self::mtu::{
+  MtuProbe,
+  DatagramSizes,
+  probe_payload,
+  probe_ack_payload,
+  read_probe_ack,
+};
+
+mod mtu {
+  use std::collections::HashMap;
+  use std::net::SocketAddr;
+  use std::sync::{Arc, Mutex};
+  use std::cmp;
+  use time::{Duration, SteadyTime};
+  use byteorder::{ByteOrder, BigEndian};
+  use config::NetworkConfig;
+  use packet_types::PacketKind;
+  use constants::{
+    MARKER_LEN,
+    HEADER_LEN,
+    MTU_PROBE_SIZES,
+    MTU_PROBE_ATTEMPTS,
+  };
+
+  // Climbs MTU_PROBE_SIZES (and then max_probe_size) one probe at a time, and stops
+  // at the first size that goes unacked MTU_PROBE_ATTEMPTS times in a row
+  #[derive(Clone, Copy, Debug)]
+  pub struct MtuProbe {
+    // Largest probe the peer acked, 0 until one is
+    confirmed: usize,
+    attempts: u32,
+    last_probe: Option<SteadyTime>,
+    done: bool
+  }
+
+  impl MtuProbe {
+    pub fn new() -> MtuProbe {
+      MtuProbe { confirmed: 0, attempts: 0, last_probe: None, done: false }
+    }
+
+    // Largest datagram the peer may be sent, marker and headers included
+    pub fn datagram_size(&self, config: &NetworkConfig) -> usize {
+      cmp::max(self.confirmed, config.max_datagram_size)
+    }
+
+    // The size being probed, if there is still anything to probe
+    pub fn next_size(&self, config: &NetworkConfig) -> Option<usize> {
+      if !config.mtu_probing || self.done {
+        return None;
+      }
+      let current = self.datagram_size(config);
+      MTU_PROBE_SIZES.iter().cloned()
+        .chain(Some(config.max_probe_size).into_iter())
+        .filter(|&size| size > current && size <= config.max_probe_size)
+        .min()
+    }
+
+    // Returns the size to probe now, if the last probe has had timeout to be acked
+    pub fn next_probe(&mut self, now: SteadyTime, timeout: Duration, config: &NetworkConfig) -> Option<usize> {
+      let size = match self.next_size(config) {
+        Some(size) => size,
+        None => return None
+      };
+      if self.last_probe.map(|last_probe| now - last_probe < timeout).unwrap_or(false) {
+        return None;
+      }
+      if self.attempts >= MTU_PROBE_ATTEMPTS {
+        self.done = true;
+        return None;
+      }
+      self.attempts += 1;
+      self.last_probe = Some(now);
+      Some(size)
+    }
+
+    // Only an ack for the size being probed counts, so late acks for retries are ignored
+    pub fn probe_acked(&mut self, size: usize, config: &NetworkConfig) -> bool {
+      if self.next_size(config) != Some(size) {
+        return false;
+      }
+      self.confirmed = size;
+      self.attempts = 0;
+      self.last_probe = None;
+      true
+    }
+  }
+
+  // Each connected peer's datagram size, published by the director for the sender thread to fragment with
+  #[derive(Clone, Debug)]
+  pub struct DatagramSizes {
+    sizes: Arc<Mutex<HashMap<SocketAddr, usize>>>
+  }
+
+  impl DatagramSizes {
+    pub fn new() -> DatagramSizes {
+      DatagramSizes { sizes: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn publish(&self, sizes: HashMap<SocketAddr, usize>) {
+      *self.sizes.lock().unwrap() = sizes;
+    }
+
+    pub fn get(&self, addr: SocketAddr) -> Option<usize> {
+      self.sizes.lock().unwrap().get(&addr).cloned()
+    }
+  }
+
+  // Padded so the whole datagram, marker and headers included, is size bytes
+  pub fn probe_payload(size: usize) -> Vec<u8> {
+    let mut bytes = vec![0; size - MARKER_LEN - HEADER_LEN];
+    bytes[0] = PacketKind::Probe.to_byte();
+    bytes
+  }
+
+  pub fn probe_ack_payload(size: usize) -> Vec<u8> {
+    let mut bytes = vec![PacketKind::ProbeAck.to_byte(), 0, 0];
+    BigEndian::write_u16(&mut bytes[1..3], size as u16);
+    bytes
+  }
+
+  pub fn read_probe_ack(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < 3 {
+      return None;
+    }
+    Some(BigEndian::read_u16(&bytes[1..3]) as usize)
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use time::{Duration, SteadyTime};
+    use config::NetworkConfig;
+    use packet_types::PacketKind;
+    use constants::{MARKER_LEN, HEADER_LEN, MTU_PROBE_ATTEMPTS};
+    use super::{MtuProbe, DatagramSizes, probe_payload, probe_ack_payload, read_probe_ack};
+
+    fn probing_config() -> NetworkConfig {
+      let mut config = NetworkConfig::default();
+      config.mtu_probing = true;
+      config
+    }
+
+    #[test]
+    fn probing_is_off_by_default() {
+      let config = NetworkConfig::default();
+      let mut probe = MtuProbe::new();
+
+      assert_eq!(probe.next_probe(SteadyTime::now(), Duration::milliseconds(100), &config), None);
+      assert_eq!(probe.datagram_size(&config), config.max_datagram_size);
+    }
+
+    #[test]
+    fn probes_climb_until_one_goes_unacked() {
+      let now = SteadyTime::now();
+      let timeout = Duration::milliseconds(100);
+      let config = probing_config();
+      let mut probe = MtuProbe::new();
+
+      assert_eq!(probe.next_probe(now, timeout, &config), Some(512));
+      assert_eq!(probe.next_probe(now, timeout, &config), None);
+      assert_eq!(probe.probe_acked(1024, &config), false);
+      assert_eq!(probe.probe_acked(512, &config), true);
+      assert_eq!(probe.datagram_size(&config), 512);
+
+      // Acked straight away, so the next size goes out without waiting
+      assert_eq!(probe.next_probe(now, timeout, &config), Some(1024));
+      let tries = (1..MTU_PROBE_ATTEMPTS)
+        .map(|attempt| probe.next_probe(now + timeout * attempt as i32, timeout, &config))
+        .collect::<Vec<Option<usize>>>();
+      assert_eq!(tries, vec![Some(1024); (MTU_PROBE_ATTEMPTS - 1) as usize]);
+      assert_eq!(probe.next_probe(now + timeout * MTU_PROBE_ATTEMPTS as i32, timeout, &config), None);
+      assert_eq!(probe.next_size(&config), None);
+      assert_eq!(probe.datagram_size(&config), 512);
+    }
+
+    #[test]
+    fn probes_stop_at_max_probe_size() {
+      let now = SteadyTime::now();
+      let mut config = probing_config();
+      config.max_probe_size = 1100;
+      let mut probe = MtuProbe::new();
+
+      assert_eq!(probe.probe_acked(512, &config), true);
+      assert_eq!(probe.probe_acked(1024, &config), true);
+      assert_eq!(probe.next_probe(now, Duration::milliseconds(100), &config), Some(1100));
+      assert_eq!(probe.probe_acked(1100, &config), true);
+      assert_eq!(probe.next_size(&config), None);
+      assert_eq!(probe.datagram_size(&config), 1100);
+    }
+
+    #[test]
+    fn probe_payloads_round_trip() {
+      let probe = probe_payload(512);
+      assert_eq!(MARKER_LEN + HEADER_LEN + probe.len(), 512);
+      assert_eq!(probe[0], PacketKind::Probe.to_byte());
+
+      let ack = probe_ack_payload(1400);
+      assert_eq!(ack[0], PacketKind::ProbeAck.to_byte());
+      assert_eq!(read_probe_ack(&ack), Some(1400));
+      assert_eq!(read_probe_ack(&ack[0..2]), None);
+    }
+
+    #[test]
+    fn datagram_sizes_are_replaced_on_publish() {
+      let addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+      let sizes = DatagramSizes::new();
+      let reader = sizes.clone();
+
+      let mut published = HashMap::new();
+      published.insert(addr, 1024);
+      sizes.publish(published);
+      assert_eq!(reader.get(addr), Some(1024));
+
+      sizes.publish(HashMap::new());
+      assert_eq!(reader.get(addr), None);
+    }
+  }
+}
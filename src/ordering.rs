@@ -0,0 +1,239 @@
+pub use self::ordering::{
+  DeliveryChannel,
+};
+
+mod ordering {
+  use std::collections::{HashMap, HashSet};
+  use packet_types::{
+    Packet,
+    DeliveryMode,
+    DeliveryPacket
+  };
+  use helpers::is_newer_seq;
+  use constants::ORDERED_WINDOW;
+  use input::InputStream;
+
+  // Ordering state for both directions of one channel's sequenced, unordered, ordered and input streams
+  #[derive(Debug)]
+  pub struct DeliveryChannel {
+    sequenced_send: u16,
+    sequenced_newest: Option<u16>,
+    unordered_send: u16,
+    // Lowest unordered number not yet delivered, and the ones past it that have been
+    unordered_base: u16,
+    unordered_received: HashSet<u16>,
+    ordered_send: u16,
+    ordered_expected: u16,
+    ordered_buffer: HashMap<u16, Packet>,
+    pub inputs: InputStream
+  }
+
+  impl DeliveryChannel {
+    pub fn new() -> DeliveryChannel {
+      DeliveryChannel {
+        sequenced_send: 0,
+        sequenced_newest: None,
+        unordered_send: 0,
+        unordered_base: 0,
+        unordered_received: HashSet::new(),
+        ordered_send: 0,
+        ordered_expected: 0,
+        ordered_buffer: HashMap::new(),
+        inputs: InputStream::new()
+      }
+    }
+
+    pub fn next_order_num(&mut self, delivery: DeliveryMode) -> u16 {
+      match delivery {
+        DeliveryMode::Sequenced => {
+          let order_num = self.sequenced_send;
+          self.sequenced_send = order_num.wrapping_add(1);
+          order_num
+        },
+        // Reliable messages keep this number across resends, so the receiver can spot duplicates
+        DeliveryMode::ReliableUnordered => {
+          let order_num = self.unordered_send;
+          self.unordered_send = order_num.wrapping_add(1);
+          order_num
+        },
+        DeliveryMode::ReliableOrdered => {
+          let order_num = self.ordered_send;
+          self.ordered_send = order_num.wrapping_add(1);
+          order_num
+        },
+        // Inputs are numbered by their stream, when their packet is built
+        DeliveryMode::Unreliable | DeliveryMode::Input => 0
+      }
+    }
+
+    // Whether a reliable message is within ORDERED_WINDOW of the oldest one the channel is still waiting on.
+    // Messages past that cannot be held, so their packets are left unacked for the peer to resend later.
+    pub fn fits_window(&self, delivery: DeliveryMode, order_num: u16) -> bool {
+      let base = match delivery {
+        DeliveryMode::ReliableUnordered => self.unordered_base,
+        DeliveryMode::ReliableOrdered => self.ordered_expected,
+        DeliveryMode::Unreliable | DeliveryMode::Sequenced | DeliveryMode::Input => return true
+      };
+      !is_newer_seq(order_num, base) || order_num.wrapping_sub(base) < ORDERED_WINDOW
+    }
+
+    // Returns the packets that are ready to be handed to the application, in delivery order
+    pub fn receive(&mut self, packet: DeliveryPacket) -> Vec<Packet> {
+      match packet.delivery {
+        DeliveryMode::Unreliable => vec![packet.into_packet()],
+        DeliveryMode::Sequenced => self.receive_sequenced(packet),
+        DeliveryMode::ReliableUnordered => self.receive_unordered(packet),
+        DeliveryMode::ReliableOrdered => self.receive_ordered(packet),
+        DeliveryMode::Input => self.inputs.receive(packet)
+      }
+    }
+
+    fn receive_sequenced(&mut self, packet: DeliveryPacket) -> Vec<Packet> {
+      let is_fresh =
+        self.sequenced_newest
+          .map(|newest| is_newer_seq(packet.order_num, newest))
+          .unwrap_or(true);
+
+      if is_fresh {
+        self.sequenced_newest = Some(packet.order_num);
+        vec![packet.into_packet()]
+      } else {
+        Vec::new()
+      }
+    }
+
+    // Delivers each message number once. Everything behind the base has been delivered, so only
+    // the numbers past it need remembering
+    fn receive_unordered(&mut self, packet: DeliveryPacket) -> Vec<Packet> {
+      let order_num = packet.order_num;
+      let is_behind = order_num != self.unordered_base && !is_newer_seq(order_num, self.unordered_base);
+      if is_behind || !self.fits_window(DeliveryMode::ReliableUnordered, order_num) || self.unordered_received.contains(&order_num) {
+        return Vec::new();
+      }
+
+      self.unordered_received.insert(order_num);
+      while self.unordered_received.remove(&self.unordered_base) {
+        self.unordered_base = self.unordered_base.wrapping_add(1);
+      }
+      vec![packet.into_packet()]
+    }
+
+    fn receive_ordered(&mut self, packet: DeliveryPacket) -> Vec<Packet> {
+      if packet.order_num == self.ordered_expected {
+        let mut ready = vec![packet.into_packet()];
+        self.ordered_expected = self.ordered_expected.wrapping_add(1);
+        while let Some(buffered) = self.ordered_buffer.remove(&self.ordered_expected) {
+          ready.push(buffered);
+          self.ordered_expected = self.ordered_expected.wrapping_add(1);
+        }
+        ready
+      } else {
+        let is_ahead = is_newer_seq(packet.order_num, self.ordered_expected);
+        // Anything behind the expected number was already delivered
+        if is_ahead && self.fits_window(DeliveryMode::ReliableOrdered, packet.order_num) {
+          self.ordered_buffer.insert(packet.order_num, packet.into_packet());
+        }
+        Vec::new()
+      }
+    }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use packet_types::{DeliveryMode, DeliveryPacket};
+    use ordering::DeliveryChannel;
+    use constants::ORDERED_WINDOW;
+
+    fn delivery_packet(delivery: DeliveryMode, order_num: u16) -> DeliveryPacket {
+      DeliveryPacket {
+        addr: SocketAddr::from_str("127.0.0.1:1000").unwrap(),
+        delivery,
+        channel: 0,
+        order_num,
+        bytes: vec![order_num as u8]
+      }
+    }
+
+    fn payloads(channel: &mut DeliveryChannel, packet: DeliveryPacket) -> Vec<u8> {
+      channel.receive(packet).into_iter().map(|packet| packet.bytes[0]).collect()
+    }
+
+    #[test]
+    fn next_order_num_counts_each_stream() {
+      let mut channel = DeliveryChannel::new();
+      assert_eq!(channel.next_order_num(DeliveryMode::Sequenced), 0);
+      assert_eq!(channel.next_order_num(DeliveryMode::Sequenced), 1);
+      assert_eq!(channel.next_order_num(DeliveryMode::ReliableOrdered), 0);
+      assert_eq!(channel.next_order_num(DeliveryMode::Unreliable), 0);
+      assert_eq!(channel.next_order_num(DeliveryMode::ReliableOrdered), 1);
+      assert_eq!(channel.next_order_num(DeliveryMode::ReliableUnordered), 0);
+      assert_eq!(channel.next_order_num(DeliveryMode::ReliableUnordered), 1);
+    }
+
+    #[test]
+    fn unordered_modes_deliver_immediately() {
+      let mut channel = DeliveryChannel::new();
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::Unreliable, 0)), vec![0]);
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::Unreliable, 0)), vec![0]);
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::ReliableUnordered, 1)), vec![1]);
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::ReliableUnordered, 0)), vec![0]);
+    }
+
+    #[test]
+    fn reliable_unordered_drops_resends() {
+      let mut channel = DeliveryChannel::new();
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::ReliableUnordered, 3)), vec![3]);
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::ReliableUnordered, 3)), vec![]);
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::ReliableUnordered, 2)), vec![2]);
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::ReliableUnordered, 2)), vec![]);
+
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::ReliableUnordered, 4)), vec![4]);
+    }
+
+    #[test]
+    fn reliable_streams_only_take_what_fits_the_window() {
+      let mut channel = DeliveryChannel::new();
+      // Nothing has arrived yet, so the window starts at 0 for both streams
+      for &delivery in [DeliveryMode::ReliableUnordered, DeliveryMode::ReliableOrdered].iter() {
+        assert_eq!(channel.fits_window(delivery, ORDERED_WINDOW - 1), true);
+        assert_eq!(channel.fits_window(delivery, ORDERED_WINDOW), false);
+        assert_eq!(channel.receive(delivery_packet(delivery, ORDERED_WINDOW)).len(), 0);
+      }
+      assert_eq!(channel.fits_window(DeliveryMode::Sequenced, ORDERED_WINDOW), true);
+
+      // A gap holds the window back however far later numbers get, so missing messages are never skipped
+      for order_num in 1..ORDERED_WINDOW {
+        channel.receive(delivery_packet(DeliveryMode::ReliableUnordered, order_num));
+      }
+      assert_eq!(channel.fits_window(DeliveryMode::ReliableUnordered, ORDERED_WINDOW), false);
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::ReliableUnordered, 0)), vec![0]);
+      assert_eq!(channel.fits_window(DeliveryMode::ReliableUnordered, 2 * ORDERED_WINDOW - 1), true);
+      assert_eq!(channel.receive(delivery_packet(DeliveryMode::ReliableUnordered, ORDERED_WINDOW)).len(), 1);
+      // Numbers behind the window were all delivered already
+      assert_eq!(channel.fits_window(DeliveryMode::ReliableUnordered, 5), true);
+      assert_eq!(channel.receive(delivery_packet(DeliveryMode::ReliableUnordered, 5)).len(), 0);
+    }
+
+    #[test]
+    fn sequenced_drops_stale_packets() {
+      let mut channel = DeliveryChannel::new();
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::Sequenced, 2)), vec![2]);
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::Sequenced, 1)), vec![]);
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::Sequenced, 2)), vec![]);
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::Sequenced, 5)), vec![5]);
+    }
+
+    #[test]
+    fn ordered_holds_back_until_gap_is_filled() {
+      let mut channel = DeliveryChannel::new();
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::ReliableOrdered, 1)), vec![]);
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::ReliableOrdered, 2)), vec![]);
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::ReliableOrdered, 0)), vec![0, 1, 2]);
+      // Resends of delivered packets are ignored
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::ReliableOrdered, 1)), vec![]);
+      assert_eq!(payloads(&mut channel, delivery_packet(DeliveryMode::ReliableOrdered, 3)), vec![3]);
+    }
+  }
+}
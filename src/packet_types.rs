@@ -3,7 +3,14 @@ pub use self::packet_types::{
   Packet,
   SequencedPacket,
   SequencedAckedPacket,
-  PacketWithTries
+  PacketWithTries,
+  QueuedPacket,
+  PacketKind,
+  DeliveryMode,
+  OutgoingPacket,
+  DeliveryPacket,
+  MessageId,
+  BlobId
 };
 
 mod packet_types {
@@ -33,13 +40,189 @@ mod packet_types {
     }
   }
 
-  #[derive(Clone)]
+  #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+  pub enum PacketKind {
+    Connect,
+    Accept,
+    Disconnect,
+    Heartbeat,
+    Data,
+    // One piece of a packet too big for a single datagram
+    Fragment,
+    // Blob transfers run beside the data stream with their own acks
+    BlobChunk,
+    BlobAck,
+    BlobCancel,
+    // Several length prefixed Data packets for the same peer, sequenced and acked as one
+    Aggregate,
+    // Carries only the ack headers, for peers we owe acks but have nothing else to send
+    Ack,
+    // Padded out to the datagram size being tried, and answered with that size
+    Probe,
+    ProbeAck,
+    // Xor of a group of sequenced packets, to rebuild one that went missing
+    Parity
+  }
+
+  impl PacketKind {
+    pub fn to_byte(self) -> u8 {
+      match self {
+        PacketKind::Connect => 1,
+        PacketKind::Accept => 2,
+        PacketKind::Disconnect => 3,
+        PacketKind::Data => 4,
+        PacketKind::Heartbeat => 5,
+        PacketKind::Fragment => 6,
+        PacketKind::BlobChunk => 7,
+        PacketKind::BlobAck => 8,
+        PacketKind::BlobCancel => 9,
+        PacketKind::Aggregate => 10,
+        PacketKind::Ack => 11,
+        PacketKind::Probe => 12,
+        PacketKind::ProbeAck => 13,
+        PacketKind::Parity => 14,
+      }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<PacketKind> {
+      match byte {
+        1 => Some(PacketKind::Connect),
+        2 => Some(PacketKind::Accept),
+        3 => Some(PacketKind::Disconnect),
+        4 => Some(PacketKind::Data),
+        5 => Some(PacketKind::Heartbeat),
+        6 => Some(PacketKind::Fragment),
+        7 => Some(PacketKind::BlobChunk),
+        8 => Some(PacketKind::BlobAck),
+        9 => Some(PacketKind::BlobCancel),
+        10 => Some(PacketKind::Aggregate),
+        11 => Some(PacketKind::Ack),
+        12 => Some(PacketKind::Probe),
+        13 => Some(PacketKind::ProbeAck),
+        14 => Some(PacketKind::Parity),
+        _ => None
+      }
+    }
+  }
+
+  #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+  pub enum DeliveryMode {
+    // Fire and forget, may arrive out of order or not at all
+    Unreliable,
+    // Never resent, and anything older than the newest delivered packet is dropped
+    Sequenced,
+    // Resent until acked, delivered in whatever order it arrives
+    ReliableUnordered,
+    // Resent until acked, held back until every earlier packet has been delivered
+    ReliableOrdered,
+    // Never resent, but repeated in every input packet on its channel until acked, and
+    // delivered once each, in order. Anything older than the newest delivered input is dropped.
+    Input
+  }
+
+  impl DeliveryMode {
+    pub fn to_byte(self) -> u8 {
+      match self {
+        DeliveryMode::Unreliable => 0,
+        DeliveryMode::Sequenced => 1,
+        DeliveryMode::ReliableUnordered => 2,
+        DeliveryMode::ReliableOrdered => 3,
+        DeliveryMode::Input => 4,
+      }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<DeliveryMode> {
+      match byte {
+        0 => Some(DeliveryMode::Unreliable),
+        1 => Some(DeliveryMode::Sequenced),
+        2 => Some(DeliveryMode::ReliableUnordered),
+        3 => Some(DeliveryMode::ReliableOrdered),
+        4 => Some(DeliveryMode::Input),
+        _ => None
+      }
+    }
+
+    pub fn is_reliable(self) -> bool {
+      self == DeliveryMode::ReliableUnordered || self == DeliveryMode::ReliableOrdered
+    }
+  }
+
+  // Handed out by Network::send so the application can match up Acked events
+  #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+  pub struct MessageId(pub usize);
+
+  // Handed out by Network::send_blob, and used by the receiver to tell a sender's blobs apart
+  #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+  pub struct BlobId(pub u16);
+
+  #[derive(Clone, Debug)]
+  pub struct OutgoingPacket {
+    pub packet: Packet,
+    pub delivery: DeliveryMode,
+    pub channel: u8,
+    pub id: MessageId,
+    // Higher priorities are sent first when a peer's byte budget runs short
+    pub priority: u8
+  }
+
+  #[derive(Clone, Debug, PartialEq, Eq)]
   pub struct Packet {
     pub addr: SocketAddr,
     pub bytes: Vec<u8>
   }
 
   impl Packet {
+    // Packs framed Data packets for one peer into a single Aggregate packet
+    pub fn aggregate(addr: SocketAddr, packets: Vec<Packet>) -> Packet {
+      let mut bytes = vec![PacketKind::Aggregate.to_byte()];
+      for packet in packets.into_iter() {
+        let mut len_bytes = [0; 2];
+        BigEndian::write_u16(&mut len_bytes, packet.bytes.len() as u16);
+        bytes.extend(len_bytes.iter().cloned());
+        bytes.extend(packet.bytes.into_iter());
+      }
+      Packet { addr: addr, bytes: bytes }
+    }
+
+    pub fn add_kind(self, kind: PacketKind) -> Packet {
+      Packet {
+        addr: self.addr,
+        bytes: Some(kind.to_byte()).into_iter().chain(self.bytes.into_iter()).collect()
+      }
+    }
+
+    // The channel shares a byte with the delivery mode, taking the high nibble
+    pub fn add_delivery(self, delivery: DeliveryMode, channel: u8, order_num: u16) -> Packet {
+      let mut order_num_bytes = [0; 2];
+      BigEndian::write_u16(&mut order_num_bytes, order_num);
+      Packet {
+        addr: self.addr,
+        bytes:
+          Some((channel << 4) | delivery.to_byte()).into_iter()
+            .chain(order_num_bytes.iter().cloned())
+            .chain(self.bytes.into_iter())
+            .collect()
+      }
+    }
+
+    pub fn strip_delivery(self) -> Option<DeliveryPacket> {
+      if self.bytes.len() < 3 {
+        None
+      } else {
+        let channel = self.bytes[0] >> 4;
+        let order_num = BigEndian::read_u16(&self.bytes[1..3]);
+        DeliveryMode::from_byte(self.bytes[0] & 0x0F).map(|delivery| {
+          DeliveryPacket {
+            addr: self.addr,
+            delivery: delivery,
+            channel: channel,
+            order_num: order_num,
+            bytes: self.bytes.into_iter().skip(3).collect()
+          }
+        })
+      }
+    }
+
     pub fn add_sequence_number(self, sequence_num: u16) -> SequencedPacket {
       SequencedPacket { addr: self.addr, seq_num: sequence_num, bytes: self.bytes }
     }
@@ -54,6 +237,21 @@ mod packet_types {
     }
   }
 
+  #[derive(Clone, Debug, PartialEq, Eq)]
+  pub struct DeliveryPacket {
+    pub addr: SocketAddr,
+    pub delivery: DeliveryMode,
+    pub channel: u8,
+    pub order_num: u16,
+    pub bytes: Vec<u8>
+  }
+
+  impl DeliveryPacket {
+    pub fn into_packet(self) -> Packet {
+      Packet { addr: self.addr, bytes: self.bytes }
+    }
+  }
+
   #[derive(Clone)]
   pub struct SequencedPacket {
     pub addr: SocketAddr,
@@ -95,6 +293,43 @@ mod packet_types {
   }
 
   impl SequencedAckedPacket {
+    pub fn kind(&self) -> Option<PacketKind> {
+      self.bytes.first().and_then(|byte| PacketKind::from_byte(*byte))
+    }
+
+    pub fn strip_kind(self) -> Packet {
+      Packet { addr: self.addr, bytes: self.bytes.into_iter().skip(1).collect() }
+    }
+
+    // The Data packets an Aggregate carries, each with the aggregate's headers. Anything else is
+    // returned as it is. None if a length runs past the end of the packet.
+    pub fn split_aggregate(self) -> Option<Vec<SequencedAckedPacket>> {
+      if self.kind() != Some(PacketKind::Aggregate) {
+        return Some(vec![self]);
+      }
+
+      let mut packets = Vec::new();
+      let mut pos = 1;
+      while pos < self.bytes.len() {
+        if pos + 2 > self.bytes.len() {
+          return None;
+        }
+        let len = BigEndian::read_u16(&self.bytes[pos..pos + 2]) as usize;
+        if pos + 2 + len > self.bytes.len() {
+          return None;
+        }
+        packets.push(SequencedAckedPacket {
+          addr: self.addr,
+          seq_num: self.seq_num,
+          ack_num: self.ack_num,
+          ack_field: self.ack_field,
+          bytes: self.bytes[pos + 2..pos + 2 + len].to_vec()
+        });
+        pos = pos + 2 + len;
+      }
+      Some(packets)
+    }
+
     pub fn serialize(self, marker: &[u8]) -> RawPacket {
       let mut sequence_num_bytes = [0; 2];
       let mut ack_num_bytes = [0; 2];
@@ -112,10 +347,25 @@ mod packet_types {
     }
   }
 
+  // A framed message waiting in the director for its peer's byte budget and send rate
+  #[derive(Clone, Debug, PartialEq, Eq)]
+  pub struct QueuedPacket {
+    pub packet: Packet,
+    pub is_reliable: bool,
+    pub id: MessageId,
+    pub priority: u8,
+    // Grows by the priority every tick the packet waits, so low priorities get their turn
+    pub accumulated: u32,
+    // Whether the application has been told the packet missed a tick
+    pub deferred: bool
+  }
+
   #[derive(Clone, Debug, PartialEq, Eq)]
   pub struct PacketWithTries {
     pub packet: SequencedAckedPacket,
-    pub tries: i32
+    pub tries: i32,
+    // An aggregate packet carries one id per reliable message, in packing order
+    pub ids: Vec<MessageId>
   }
 
   #[cfg(test)]
@@ -127,6 +377,8 @@ mod packet_types {
       Packet,
       SequencedPacket,
       SequencedAckedPacket,
+      PacketKind,
+      DeliveryMode,
     };
 
     fn dummy_socket_addr() -> SocketAddr {
@@ -171,6 +423,110 @@ mod packet_types {
       assert_eq!(result.bytes, vec![3]);
     }
 
+    #[test]
+    fn packet_add_kind() {
+      let packet = Packet { addr: dummy_socket_addr(), bytes: vec![7, 8] };
+      let result = packet.add_kind(PacketKind::Data);
+      assert_eq!(result.addr, dummy_socket_addr());
+      assert_eq!(result.bytes, vec![PacketKind::Data.to_byte(), 7, 8]);
+    }
+
+    #[test]
+    fn packet_kind_round_trips() {
+      let kinds = vec![PacketKind::Connect, PacketKind::Accept, PacketKind::Disconnect, PacketKind::Heartbeat, PacketKind::Data, PacketKind::Fragment,
+        PacketKind::BlobChunk, PacketKind::BlobAck, PacketKind::BlobCancel, PacketKind::Aggregate, PacketKind::Ack,
+        PacketKind::Probe, PacketKind::ProbeAck, PacketKind::Parity];
+      for kind in kinds.into_iter() {
+        assert_eq!(PacketKind::from_byte(kind.to_byte()), Some(kind));
+      }
+      assert_eq!(PacketKind::from_byte(0), None);
+    }
+
+    #[test]
+    fn delivery_mode_round_trips() {
+      let modes = vec![DeliveryMode::Unreliable, DeliveryMode::Sequenced, DeliveryMode::ReliableUnordered, DeliveryMode::ReliableOrdered, DeliveryMode::Input];
+      for mode in modes.into_iter() {
+        assert_eq!(DeliveryMode::from_byte(mode.to_byte()), Some(mode));
+      }
+      assert_eq!(DeliveryMode::Input.is_reliable(), false);
+      assert_eq!(DeliveryMode::from_byte(5), None);
+    }
+
+    #[test]
+    fn packet_add_delivery() {
+      let packet = Packet { addr: dummy_socket_addr(), bytes: vec![7, 8] };
+      let result = packet.add_delivery(DeliveryMode::ReliableOrdered, 0, 258);
+      assert_eq!(result.addr, dummy_socket_addr());
+      assert_eq!(result.bytes, vec![3, 1, 2, 7, 8]);
+
+      let packet = Packet { addr: dummy_socket_addr(), bytes: vec![7, 8] };
+      let result = packet.add_delivery(DeliveryMode::Sequenced, 5, 258);
+      assert_eq!(result.bytes, vec![0x51, 1, 2, 7, 8]);
+    }
+
+    #[test]
+    fn packet_strips_delivery() {
+      let packet = Packet { addr: dummy_socket_addr(), bytes: vec![1, 1, 2, 7, 8] };
+      let result = packet.strip_delivery().unwrap();
+      assert_eq!(result.addr, dummy_socket_addr());
+      assert_eq!(result.delivery, DeliveryMode::Sequenced);
+      assert_eq!(result.channel, 0);
+      assert_eq!(result.order_num, 258);
+      assert_eq!(result.bytes, vec![7, 8]);
+
+      let packet = Packet { addr: dummy_socket_addr(), bytes: vec![0xF3, 0, 1] };
+      let result = packet.strip_delivery().unwrap();
+      assert_eq!(result.delivery, DeliveryMode::ReliableOrdered);
+      assert_eq!(result.channel, 15);
+
+      // Unknown delivery modes and short headers are rejected
+      let packet = Packet { addr: dummy_socket_addr(), bytes: vec![9, 1, 2, 7, 8] };
+      assert_eq!(packet.strip_delivery().is_none(), true);
+      let packet = Packet { addr: dummy_socket_addr(), bytes: vec![1, 1] };
+      assert_eq!(packet.strip_delivery().is_none(), true);
+    }
+
+    #[test]
+    fn sequenced_acked_packet_strips_kind() {
+      let packet = SequencedAckedPacket {
+        addr: dummy_socket_addr(),
+        seq_num: 1,
+        ack_num: 2,
+        ack_field: 3,
+        bytes: vec![PacketKind::Accept.to_byte(), 9]
+      };
+      assert_eq!(packet.kind(), Some(PacketKind::Accept));
+
+      let result = packet.strip_kind();
+      assert_eq!(result.addr, dummy_socket_addr());
+      assert_eq!(result.bytes, vec![9]);
+    }
+
+    #[test]
+    fn aggregate_round_trips() {
+      let packets = vec![
+        Packet { addr: dummy_socket_addr(), bytes: vec![PacketKind::Data.to_byte(), 1, 2] },
+        Packet { addr: dummy_socket_addr(), bytes: vec![] },
+        Packet { addr: dummy_socket_addr(), bytes: vec![PacketKind::Data.to_byte(), 3] }
+      ];
+      let aggregate = Packet::aggregate(dummy_socket_addr(), packets.clone());
+      assert_eq!(aggregate.bytes, vec![PacketKind::Aggregate.to_byte(), 0, 3, 4, 1, 2, 0, 0, 0, 2, 4, 3]);
+
+      let sequenced = SequencedAckedPacket { addr: dummy_socket_addr(), seq_num: 1, ack_num: 2, ack_field: 3, bytes: aggregate.bytes };
+      let split = sequenced.split_aggregate().unwrap();
+      assert_eq!(split.iter().map(|packet| packet.bytes.clone()).collect::<Vec<Vec<u8>>>(), packets.into_iter().map(|packet| packet.bytes).collect::<Vec<Vec<u8>>>());
+      assert_eq!(split.iter().all(|packet| packet.seq_num == 1 && packet.ack_num == 2 && packet.ack_field == 3), true);
+    }
+
+    #[test]
+    fn split_aggregate_leaves_other_kinds_alone() {
+      let data = SequencedAckedPacket { addr: dummy_socket_addr(), seq_num: 1, ack_num: 2, ack_field: 3, bytes: vec![PacketKind::Data.to_byte(), 1] };
+      assert_eq!(data.clone().split_aggregate(), Some(vec![data]));
+
+      let truncated = SequencedAckedPacket { addr: dummy_socket_addr(), seq_num: 1, ack_num: 2, ack_field: 3, bytes: vec![PacketKind::Aggregate.to_byte(), 0, 5, 4] };
+      assert_eq!(truncated.split_aggregate(), None);
+    }
+
     #[test]
     fn sequenced_packet_add_acks() {
       let packet = SequencedPacket { addr: dummy_socket_addr(), seq_num: 5, bytes: vec![1, 2, 3] };
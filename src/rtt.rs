@@ -0,0 +1,120 @@
+pub use self::rtt::{
+  RttEstimator,
+};
+
+mod rtt {
+  use std::cmp;
+  use time::Duration;
+  use constants::{
+    INITIAL_RTO,
+    MIN_RTO,
+    PACKET_DROP_TIME,
+  };
+
+  // Smoothed round trip time and retransmission timeout, following RFC 6298
+  #[derive(Clone, Copy, Debug)]
+  pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration
+  }
+
+  impl RttEstimator {
+    pub fn new() -> RttEstimator {
+      RttEstimator {
+        srtt: None,
+        rttvar: Duration::zero(),
+        rto: Duration::milliseconds(INITIAL_RTO)
+      }
+    }
+
+    pub fn add_sample(&mut self, sample: Duration) {
+      match self.srtt {
+        None => {
+          self.srtt = Some(sample);
+          self.rttvar = sample / 2;
+        },
+        Some(srtt) => {
+          let delta = srtt - sample;
+          let abs_delta = if delta < Duration::zero() { -delta } else { delta };
+          self.rttvar = (self.rttvar * 3 + abs_delta) / 4;
+          self.srtt = Some((srtt * 7 + sample) / 8);
+        }
+      }
+
+      let rto = self.srtt.unwrap_or(sample) + self.rttvar * 4;
+      self.rto = clamp_rto(rto);
+    }
+
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+      self.srtt
+    }
+
+    // Doubles the timeout for every attempt after the first
+    pub fn resend_timeout(&self, tries: i32) -> Duration {
+      (1..tries).fold(self.rto, |timeout, _| clamp_rto(timeout * 2))
+    }
+  }
+
+  fn clamp_rto(rto: Duration) -> Duration {
+    cmp::min(cmp::max(rto, Duration::milliseconds(MIN_RTO)), Duration::seconds(PACKET_DROP_TIME))
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use time::Duration;
+    use constants::{
+      INITIAL_RTO,
+      MIN_RTO,
+      PACKET_DROP_TIME,
+    };
+    use rtt::RttEstimator;
+
+    #[test]
+    fn starts_at_initial_rto() {
+      let estimator = RttEstimator::new();
+      assert_eq!(estimator.srtt, None);
+      assert_eq!(estimator.rto, Duration::milliseconds(INITIAL_RTO));
+    }
+
+    #[test]
+    fn first_sample_sets_srtt() {
+      let mut estimator = RttEstimator::new();
+      estimator.add_sample(Duration::milliseconds(100));
+      assert_eq!(estimator.srtt, Some(Duration::milliseconds(100)));
+      // srtt + 4 * (sample / 2)
+      assert_eq!(estimator.rto, Duration::milliseconds(300));
+    }
+
+    #[test]
+    fn later_samples_are_smoothed() {
+      let mut estimator = RttEstimator::new();
+      estimator.add_sample(Duration::milliseconds(100));
+      estimator.add_sample(Duration::milliseconds(180));
+      // 7/8 * 100 + 1/8 * 180
+      assert_eq!(estimator.srtt, Some(Duration::milliseconds(110)));
+      // rttvar = 3/4 * 50 + 1/4 * 80 = 57.5
+      assert_eq!(estimator.rto, Duration::microseconds(340000));
+    }
+
+    #[test]
+    fn rto_is_clamped() {
+      let mut estimator = RttEstimator::new();
+      estimator.add_sample(Duration::milliseconds(1));
+      assert_eq!(estimator.rto, Duration::milliseconds(MIN_RTO));
+
+      estimator.add_sample(Duration::seconds(PACKET_DROP_TIME * 10));
+      assert_eq!(estimator.rto, Duration::seconds(PACKET_DROP_TIME));
+    }
+
+    #[test]
+    fn resend_timeout_backs_off() {
+      let mut estimator = RttEstimator::new();
+      estimator.add_sample(Duration::milliseconds(100));
+      assert_eq!(estimator.resend_timeout(1), Duration::milliseconds(300));
+      assert_eq!(estimator.resend_timeout(2), Duration::milliseconds(600));
+      assert_eq!(estimator.resend_timeout(3), Duration::milliseconds(1200));
+      assert_eq!(estimator.resend_timeout(100), Duration::seconds(PACKET_DROP_TIME));
+    }
+  }
+}
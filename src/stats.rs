@@ -0,0 +1,66 @@
+pub use self::stats::{
+  StatsBoard,
+};
+
+mod stats {
+  use std::collections::HashMap;
+  use std::net::SocketAddr;
+  use std::sync::{Arc, Mutex};
+  use types::PeerStats;
+
+  // The director publishes every connected peer's stats here each tick, for Network to read
+  #[derive(Clone, Debug)]
+  pub struct StatsBoard {
+    stats: Arc<Mutex<HashMap<SocketAddr, PeerStats>>>
+  }
+
+  impl StatsBoard {
+    pub fn new() -> StatsBoard {
+      StatsBoard { stats: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn publish(&self, stats: HashMap<SocketAddr, PeerStats>) {
+      *self.stats.lock().unwrap() = stats;
+    }
+
+    pub fn get(&self, addr: SocketAddr) -> Option<PeerStats> {
+      self.stats.lock().unwrap().get(&addr).cloned()
+    }
+
+    pub fn get_all(&self) -> HashMap<SocketAddr, PeerStats> {
+      self.stats.lock().unwrap().clone()
+    }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use types::PeerStats;
+    use super::StatsBoard;
+
+    #[test]
+    fn publish_replaces_every_peer() {
+      let addr = SocketAddr::from_str("127.0.0.1:1000").unwrap();
+      let other_addr = SocketAddr::from_str("127.0.0.1:1001").unwrap();
+      let board = StatsBoard::new();
+      let reader = board.clone();
+      let stats = PeerStats { packets_sent: 3, .. PeerStats::default() };
+
+      let mut published = HashMap::new();
+      published.insert(addr, stats.clone());
+      board.publish(published.clone());
+      assert_eq!(reader.get(addr), Some(stats.clone()));
+      assert_eq!(reader.get(other_addr), None);
+      assert_eq!(reader.get_all(), published);
+
+      // Peers missing from the next tick are gone
+      let mut published = HashMap::new();
+      published.insert(other_addr, stats.clone());
+      board.publish(published);
+      assert_eq!(reader.get(addr), None);
+      assert_eq!(reader.get(other_addr), Some(stats));
+    }
+  }
+}
@@ -1,22 +1,244 @@
 pub use self::types::{
   IOHandles,
   Network,
+  NetworkEvent,
+  Command,
+  PeerStats,
 };
 
 mod types {
+  use std::net::SocketAddr;
+  use std::collections::HashMap;
+  use std::cell::Cell;
   use std::thread::JoinHandle;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicBool, Ordering};
   use std::sync::mpsc::{Receiver, Sender};
-  use packet_types::Packet;
+  use packet_types::{
+    Packet,
+    DeliveryMode,
+    OutgoingPacket,
+    MessageId,
+    BlobId
+  };
+  use errors::NetworkError;
+  use config::OverflowPolicy;
+  use constants::{
+    DEFAULT_PRIORITY,
+    DATA_HEADER_LEN,
+    MAX_CHANNELS,
+    MAX_BLOB_SIZE,
+  };
+  use backpressure::QueueSlots;
+  use stats::StatsBoard;
+  use time::Duration;
+
+  #[derive(Clone, Debug, PartialEq, Eq)]
+  pub enum NetworkEvent {
+    Connected(SocketAddr),
+    Disconnected(SocketAddr),
+    ConnectionFailed(SocketAddr),
+    TimedOut(SocketAddr),
+    // Only reliable messages are tracked, so only they are ever acked
+    Acked(MessageId),
+    // A message that could not go out the tick it was sent, because of its peer's byte budget or send rate.
+    // Only reported once per message.
+    Deferred(MessageId),
+    // An unreliable message dropped to make room under OverflowPolicy::DropOldestUnreliable
+    Dropped(MessageId),
+    // A ReliableUnordered message that ran out of resend attempts, with its original payload.
+    // ReliableOrdered messages are resent until acked or their peer times out, since later ones wait on them.
+    Lost { id: MessageId, packet: Packet, attempts: i32 },
+    // Emitted at most once per director tick for each blob that made progress
+    BlobSendProgress { addr: SocketAddr, id: BlobId, acked: u32, total: u32 },
+    BlobRecvProgress { addr: SocketAddr, id: BlobId, received: u32, total: u32 },
+    BlobSent { addr: SocketAddr, id: BlobId },
+    BlobReceived { addr: SocketAddr, id: BlobId, bytes: Vec<u8> },
+    // Also emitted for a partly received blob that went BLOB_IDLE_TIMEOUT without a chunk.
+    // Blobs in flight when a peer disconnects or times out are dropped without one of these
+    BlobCancelled { addr: SocketAddr, id: BlobId },
+    // A peer's link looked congested or recovered, with the sequenced packets per second it is now sent
+    SendRateChanged { addr: SocketAddr, packets_per_second: u32 },
+    Error(NetworkError)
+  }
+
+  #[derive(Clone, Debug, PartialEq, Eq)]
+  pub enum Command {
+    Connect(SocketAddr),
+    Disconnect(SocketAddr),
+    SendBlob(SocketAddr, BlobId, Vec<u8>),
+    CancelBlob(SocketAddr, BlobId),
+    Shutdown
+  }
+
+  // How a connection is doing, as of the director's last tick. Packets and bytes are counted as the
+  // director hands them to the socket, so a fragmented packet counts once and headers are included.
+  #[derive(Clone, Debug, Default, PartialEq)]
+  pub struct PeerStats {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    // Reliable packets sent again after going unacked
+    pub resends: u64,
+    // Reliable messages given up on after MAX_RESEND_ATTEMPTS
+    pub lost: u64,
+    // Packets rebuilt from parity packets instead of waiting on a resend
+    pub recovered: u64,
+    // Parity packets sent, and their bytes, which are also counted in packets_sent and bytes_sent
+    pub parities_sent: u64,
+    pub parity_bytes_sent: u64,
+    pub smoothed_rtt: Option<Duration>,
+    // Of recent reliable packets
+    pub loss_percent: f64,
+    // Reliable packets awaiting acks
+    pub packets_in_flight: usize,
+    // Sequenced packets per second the peer is currently allowed
+    pub send_rate: u32,
+    // Largest datagram the peer is sent, raised by MTU probing
+    pub datagram_size: usize
+  }
 
   pub struct IOHandles {
     pub send_handle: JoinHandle<()>,
     pub recv_handle: JoinHandle<()>,
-    pub direct_handle: JoinHandle<()>
+    pub direct_handle: JoinHandle<Vec<Packet>>,
+    pub running: Arc<AtomicBool>
+  }
+
+  impl IOHandles {
+    // Waits for the director to wind down before stopping the receiver, returning unacked packets
+    pub fn join(self) -> Vec<Packet> {
+      let unacked_packets = self.direct_handle.join().unwrap_or(Vec::new());
+      self.running.store(false, Ordering::SeqCst);
+      let _ = self.send_handle.join();
+      let _ = self.recv_handle.join();
+      unacked_packets
+    }
   }
 
   pub struct Network {
-    pub send_channel: Sender<Packet>,
+    pub(crate) send_channel: Sender<OutgoingPacket>,
     pub recv_channel: Receiver<Packet>,
-    pub thread_handles: IOHandles
+    pub command_channel: Sender<Command>,
+    pub event_channel: Receiver<NetworkEvent>,
+    pub(crate) thread_handles: Option<IOHandles>,
+    pub(crate) next_message_id: Cell<usize>,
+    pub(crate) next_blob_id: Cell<u16>,
+    pub(crate) max_queued_per_peer: usize,
+    pub(crate) overflow_policy: OverflowPolicy,
+    // The biggest message, delivery header included, that fits in MAX_FRAGMENTS datagrams
+    pub(crate) max_packet_size: usize,
+    pub(crate) queue_slots: QueueSlots,
+    pub(crate) stats: StatsBoard
+  }
+
+  impl Network {
+    // Fails with WouldBlock when the peer's send queue is full, depending on the overflow policy,
+    // with Shutdown once the network has stopped, and with PacketTooLarge or InvalidChannel right away
+    pub fn send(&self, packet: Packet, delivery: DeliveryMode) -> Result<MessageId, NetworkError> {
+      self.send_on_channel(packet, delivery, 0)
+    }
+
+    // Ordering and sequencing are only enforced between packets on the same channel
+    pub fn send_on_channel(&self, packet: Packet, delivery: DeliveryMode, channel: u8) -> Result<MessageId, NetworkError> {
+      self.send_with_priority(packet, delivery, channel, DEFAULT_PRIORITY)
+    }
+
+    // Higher priorities go first when more is queued for a peer than its byte budget allows.
+    // Everything else is sent at priority 128.
+    pub fn send_with_priority(&self, packet: Packet, delivery: DeliveryMode, channel: u8, priority: u8) -> Result<MessageId, NetworkError> {
+      let addr = packet.addr;
+      let limit = self.max_queued_per_peer;
+      if self.queue_slots.is_closed() {
+        return Err(NetworkError::Shutdown);
+      }
+      if packet.bytes.len() + DATA_HEADER_LEN > self.max_packet_size {
+        return Err(NetworkError::PacketTooLarge(addr, packet.bytes.len()));
+      }
+      if channel >= MAX_CHANNELS {
+        return Err(NetworkError::InvalidChannel(addr, channel));
+      }
+      let has_slot = match self.overflow_policy {
+        OverflowPolicy::Block => {
+          if !self.queue_slots.take(addr, limit) {
+            return Err(NetworkError::Shutdown);
+          }
+          true
+        },
+        OverflowPolicy::WouldBlock => self.queue_slots.try_take(addr, limit),
+        // The director drops the oldest unreliable message to get back under the limit
+        OverflowPolicy::DropOldestUnreliable => {
+          self.queue_slots.try_take(addr, limit) || (!delivery.is_reliable() && {
+            self.queue_slots.force_take(addr);
+            true
+          })
+        }
+      };
+      if !has_slot {
+        return Err(NetworkError::WouldBlock(addr));
+      }
+
+      let id = MessageId(self.next_message_id.get());
+      self.next_message_id.set(id.0.wrapping_add(1));
+      self.send_channel.send(OutgoingPacket { packet: packet, delivery: delivery, channel: channel, id: id, priority: priority })
+        .map(|_| id)
+        .map_err(|_| NetworkError::Shutdown)
+    }
+
+    // Sends a buffer of up to 64MB in chunks, outside of the normal send path.
+    // Progress and completion are reported through the event channel, as is a peer that is not connected.
+    pub fn send_blob(&self, addr: SocketAddr, bytes: Vec<u8>) -> Result<BlobId, NetworkError> {
+      if self.queue_slots.is_closed() {
+        return Err(NetworkError::Shutdown);
+      }
+      if bytes.len() > MAX_BLOB_SIZE as usize {
+        return Err(NetworkError::PacketTooLarge(addr, bytes.len()));
+      }
+      let id = BlobId(self.next_blob_id.get());
+      self.next_blob_id.set(id.0.wrapping_add(1));
+      self.command_channel.send(Command::SendBlob(addr, id, bytes))
+        .map(|_| id)
+        .map_err(|_| NetworkError::Shutdown)
+    }
+
+    pub fn cancel_blob(&self, addr: SocketAddr, id: BlobId) -> Result<(), NetworkError> {
+      self.command_channel.send(Command::CancelBlob(addr, id))
+        .map_err(|_| NetworkError::Shutdown)
+    }
+
+    // None for peers that are not connected
+    pub fn peer_stats(&self, addr: SocketAddr) -> Option<PeerStats> {
+      self.stats.get(addr)
+    }
+
+    pub fn all_peer_stats(&self) -> HashMap<SocketAddr, PeerStats> {
+      self.stats.get_all()
+    }
+
+    pub fn connect(&self, addr: SocketAddr) {
+      let _ = self.command_channel.send(Command::Connect(addr));
+    }
+
+    pub fn disconnect(&self, addr: SocketAddr) {
+      let _ = self.command_channel.send(Command::Disconnect(addr));
+    }
+
+    // Disconnects every peer, stops all threads and closes the socket.
+    // Returns the reliable packets that were still waiting on an ack.
+    pub fn shutdown(&mut self) -> Vec<Packet> {
+      self.thread_handles.take()
+        .map(|handles| {
+          let _ = self.command_channel.send(Command::Shutdown);
+          handles.join()
+        })
+        .unwrap_or(Vec::new())
+    }
+  }
+
+  impl Drop for Network {
+    fn drop(&mut self) {
+      self.shutdown();
+    }
   }
 }